@@ -0,0 +1,307 @@
+// Static analyses over IR that report on a program without changing it.
+
+use alloc::{vec, vec::Vec};
+
+use crate::{parser::IR, HashMap};
+
+// A loop found by one of the analyses.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LoopInfo {
+    // Indices leading to the loop: its position in the program, then in the bodies of each enclosing loop
+    pub path: Vec<usize>,
+}
+
+// Identifies a loop by its place in a pre-order walk of the program: loops are numbered in program order with a loop
+// coming before the loops in its body. This is the order flatten emits JumpIfZero in, `loops(ir)[id.0]` locates the
+// loop in the IR and, as O0 keeps every loop, `loop_positions(bf)[id.0]` locates it in the source of an O0 program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LoopId(pub usize);
+
+// Lists every loop of the program, indexed by LoopId.
+pub fn loops(ir: &[IR]) -> Vec<LoopInfo> {
+    fn walk(ir: &[IR], path: &mut Vec<usize>, found: &mut Vec<LoopInfo>) {
+        for (index, i) in ir.iter().enumerate() {
+            if let IR::Loop { instructions, .. } = i {
+                path.push(index);
+                found.push(LoopInfo { path: path.clone() });
+                walk(instructions, path, found);
+                path.pop();
+            }
+        }
+    }
+
+    let mut found = vec![];
+    walk(ir, &mut vec![], &mut found);
+    found
+}
+
+// Returns the position of every `[` in the source, indexed by LoopId. Positions count characters like the ones in
+// OptimizerError do. Only meaningful for programs that parse.
+pub fn loop_positions(bf: &str) -> Vec<usize> {
+    bf.chars()
+        .enumerate()
+        .filter(|(_, c)| *c == '[')
+        .map(|(position, _)| position)
+        .collect()
+}
+
+// Returns how many loops each loop is made of, itself included, indexed by LoopId. The loop after LoopId(n) and its
+// body is LoopId(n + sizes[n]).
+pub(crate) fn loop_sizes(ir: &[IR]) -> Vec<usize> {
+    fn walk(ir: &[IR], sizes: &mut Vec<usize>) {
+        for i in ir {
+            if let IR::Loop { instructions, .. } = i {
+                let id = sizes.len();
+                sizes.push(0);
+                walk(instructions, sizes);
+                sizes[id] = sizes.len() - id;
+            }
+        }
+    }
+
+    let mut sizes = vec![];
+    walk(ir, &mut sizes);
+    sizes
+}
+
+// Finds the loops that provably never terminate once execution reaches them. A loop is only reported when its cell
+// is known to be non-zero on entry and its body can't change that: the body returns the pointer to where it started,
+// never writes the loop's cell, and holds no Read, ScanZero or nested Loop. Anything that depends on the input or the
+// state of the tape is assumed to terminate, as is a body holding an Assert, so with wrapping cells there are no
+// false positives, only missed loops.
+//
+// A loop that prints is still reported, even though it does something observable: `+[.]` prints forever but never
+// finishes, which is what a caller bounding a run wants to know.
+//
+// Known values wrap like i32. A cell only counts as non-zero when its low byte is, so the result holds for every
+// cell width, but only under Overflow::Wrap. Cells that saturate or fail on overflow can hold other values: `+--[.]`
+// is reported, but a saturating cell stays at 0 on the second `-` and the loop never runs.
+pub fn find_infinite_loops(ir: &[IR]) -> Vec<LoopInfo> {
+    let mut found = vec![];
+    find_infinite_loops_in(ir, true, &mut vec![], &mut found);
+    found
+}
+
+fn find_infinite_loops_in(
+    ir: &[IR],
+    program_start: bool,
+    path: &mut Vec<usize>,
+    found: &mut Vec<LoopInfo>,
+) {
+    // Cells missing from the map are 0 if all_zero is set and unknown otherwise
+    let mut known: HashMap<i32, Option<i32>> = HashMap::new();
+    let mut all_zero = program_start;
+
+    for (index, i) in ir.iter().enumerate() {
+        let value = |known: &HashMap<i32, Option<i32>>, offset: i32| match known.get(&offset) {
+            Some(value) => *value,
+            None => all_zero.then_some(0),
+        };
+
+        match i {
+            IR::Add { x, offset } => {
+                let new = value(&known, *offset).map(|v| v.wrapping_add(*x));
+                known.insert(*offset, new);
+            }
+            IR::Exact { x, offset } => {
+                known.insert(*offset, Some(*x));
+            }
+            IR::Clear { offset } => {
+                known.insert(*offset, Some(0));
+            }
+            IR::Read { offset } => {
+                known.insert(*offset, None);
+            }
+            IR::Print { .. } | IR::Debug { .. } | IR::Assert { .. } => {}
+            IR::Move { over } => {
+                known = known.into_iter().map(|(k, v)| (k - over, v)).collect();
+            }
+            IR::Mul { x, y, offset } => {
+                let destination = offset + x;
+                let new = match (value(&known, *offset), value(&known, destination)) {
+                    (Some(source), Some(old)) => Some(old.wrapping_add(source.wrapping_mul(*y))),
+                    _ => None,
+                };
+                known.insert(destination, new);
+            }
+            IR::Loop { over, instructions } => {
+                path.push(index);
+
+                let entered = value(&known, *over).is_some_and(|v| v as u8 != 0);
+                if entered && never_exits(instructions) {
+                    found.push(LoopInfo { path: path.clone() });
+                } else {
+                    find_infinite_loops_in(instructions, false, path, found);
+                }
+
+                path.pop();
+
+                // The loop only exits on a zero cell, nothing else about the tape is known
+                known.clear();
+                known.insert(0, Some(0));
+                all_zero = false;
+            }
+            IR::ScanZero { .. } => {
+                known.clear();
+                known.insert(0, Some(0));
+                all_zero = false;
+            }
+        }
+    }
+}
+
+// Whether a loop body leaves the pointer and the loop's cell untouched without reading anything.
+fn never_exits(body: &[IR]) -> bool {
+    let mut pointer = 0;
+
+    for i in body {
+        let written = match i {
+            IR::Move { over } => {
+                pointer += over;
+                continue;
+            }
+            IR::Add { offset, .. } | IR::Exact { offset, .. } | IR::Clear { offset } => *offset,
+            IR::Mul { x, offset, .. } => offset + x,
+            IR::Print { .. } | IR::Debug { .. } => continue,
+            // A failing Assert stops the program
+            IR::Read { .. } | IR::Loop { .. } | IR::ScanZero { .. } | IR::Assert { .. } => {
+                return false
+            }
+        };
+
+        if pointer + written == 0 {
+            return false;
+        }
+    }
+
+    pointer == 0
+}
+
+// Returns the lowest and highest cell a program could touch relative to the starting cell, for picking a tape size.
+// Every loop may run any number of times, so a side is None (unbounded) when a loop or ScanZero can drift the pointer
+// that way without limit. The starting cell is always included.
+pub fn static_offset_bounds(ir: &[IR]) -> (Option<i32>, Option<i32>) {
+    let mut touched = Range::at(0);
+    offset_bounds(ir, Range::at(0), &mut touched);
+    (touched.lo, touched.hi)
+}
+
+// A range of cells with None for an unbounded side.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Range {
+    lo: Option<i32>,
+    hi: Option<i32>,
+}
+
+impl Range {
+    fn at(index: i32) -> Self {
+        Range {
+            lo: Some(index),
+            hi: Some(index),
+        }
+    }
+
+    // Shifts the range, a side that overflows becomes unbounded.
+    fn shift(self, by: i32) -> Self {
+        Range {
+            lo: self.lo.and_then(|lo| lo.checked_add(by)),
+            hi: self.hi.and_then(|hi| hi.checked_add(by)),
+        }
+    }
+
+    fn join(self, other: Self) -> Self {
+        Range {
+            lo: self.lo.zip(other.lo).map(|(a, b)| a.min(b)),
+            hi: self.hi.zip(other.hi).map(|(a, b)| a.max(b)),
+        }
+    }
+}
+
+// Walks `ir` with the pointer anywhere in `pointer`, adding every cell it could touch to `touched`. Returns where the
+// pointer could end up.
+fn offset_bounds(ir: &[IR], mut pointer: Range, touched: &mut Range) -> Range {
+    for i in ir {
+        match i {
+            IR::Add { offset, .. }
+            | IR::Print { offset, .. }
+            | IR::Read { offset }
+            | IR::Debug { offset }
+            | IR::Assert { offset, .. }
+            | IR::Exact { offset, .. }
+            | IR::Clear { offset } => *touched = touched.join(pointer.shift(*offset)),
+            IR::Mul { x, offset, .. } => {
+                *touched = touched
+                    .join(pointer.shift(*offset))
+                    .join(pointer.shift(offset + x));
+            }
+            IR::Move { over } => pointer = pointer.shift(*over),
+            IR::ScanZero { step } => {
+                // The scan checks every cell it passes over
+                match step.signum() {
+                    1 => pointer.hi = None,
+                    -1 => pointer.lo = None,
+                    _ => {}
+                }
+                *touched = touched.join(pointer);
+            }
+            IR::Loop { over, instructions } => {
+                pointer = pointer.shift(*over);
+
+                // Widen the pointer's range until another iteration of the body can't move it any further. Every side
+                // only widens once, so this takes at most three rounds.
+                loop {
+                    *touched = touched.join(pointer);
+                    let exit = offset_bounds(instructions, pointer, touched);
+                    let joined = pointer.join(exit);
+                    let widened = Range {
+                        lo: joined.lo.filter(|_| joined.lo == pointer.lo),
+                        hi: joined.hi.filter(|_| joined.hi == pointer.hi),
+                    };
+
+                    if widened == pointer {
+                        break;
+                    }
+                    pointer = widened;
+                }
+            }
+        }
+    }
+
+    pointer
+}
+
+// Counts the Reads outside of any loop, the input a program consumes on every run that gets that far. Returns None
+// if any loop reads, as the number of reads then depends on how often the loop runs. Comparing the count against
+// the input of a test case catches cases that are sure to run out, before they fail with OutOfInputs.
+pub fn count_min_reads(ir: &[IR]) -> Option<usize> {
+    fn reads(ir: &[IR]) -> bool {
+        ir.iter().any(|i| match i {
+            IR::Read { .. } => true,
+            IR::Loop { instructions, .. } => reads(instructions),
+            _ => false,
+        })
+    }
+
+    let mut count = 0;
+    for i in ir {
+        match i {
+            IR::Read { .. } => count += 1,
+            IR::Loop { instructions, .. } if reads(instructions) => return None,
+            _ => {}
+        }
+    }
+
+    Some(count)
+}
+
+// Whether the program never prints or reads, anywhere in a loop included. A pure program always prints nothing, so
+// only the tape and the pointer it ends with tell runs apart. Debug markers don't count as I/O.
+pub fn is_pure(ir: &[IR]) -> bool {
+    ir.iter().all(|i| match i {
+        IR::Print { .. } | IR::Read { .. } => false,
+        IR::Loop { instructions, .. } => is_pure(instructions),
+        _ => true,
+    })
+}
@@ -0,0 +1,158 @@
+// Comparing a program across the optimization levels: how long each takes, to see whether the time spent optimizing
+// pays off in the run, and how much each shrinks the program.
+
+use alloc::vec::Vec;
+use core::{
+    fmt::{self, Display},
+    time::Duration,
+};
+#[cfg(feature = "std")]
+use std::{num::Wrapping, time::Instant};
+
+#[cfg(feature = "std")]
+use crate::{interpreter::Cell, CellWidth, Config};
+use crate::{
+    interpreter::RunTimeError,
+    parser::{count_instructions, optimize_staged, OptimizerError},
+    OptimizationLevel,
+};
+
+// How long one optimization level took to optimize and to run the program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LevelTiming {
+    pub level: OptimizationLevel,
+    // Parsing and running the passes of the level
+    pub optimize: Duration,
+    // Running the optimized program on the input
+    pub run: Duration,
+    pub iterations: usize,
+    // The error the run stopped with, if any. The timings of a run that ran out of iterations aren't comparable
+    // across levels, as the optimized levels get further in the same number of iterations.
+    pub error: Option<RunTimeError>,
+}
+
+impl LevelTiming {
+    pub fn total(&self) -> Duration {
+        self.optimize + self.run
+    }
+}
+
+// The timings of every optimization level, from O0 up.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct LevelTimings {
+    pub levels: Vec<LevelTiming>,
+}
+
+impl LevelTimings {
+    pub fn get(&self, level: OptimizationLevel) -> Option<&LevelTiming> {
+        self.levels.iter().find(|timing| timing.level == level)
+    }
+
+    // The level that optimized and ran the program the fastest.
+    pub fn fastest(&self) -> Option<&LevelTiming> {
+        self.levels.iter().min_by_key(|timing| timing.total())
+    }
+}
+
+// Optimizes and runs the program once at every level, timing both phases separately. Everything but the
+// optimization level comes from `config`. A single run is noisy for short programs, so compare the sums of several
+// calls rather than one. Needs std for the clock.
+#[cfg(feature = "std")]
+pub fn benchmark(
+    bf: &str,
+    input: &[Wrapping<u8>],
+    config: Config,
+) -> Result<LevelTimings, OptimizerError> {
+    match config.cell_width {
+        CellWidth::U8 => benchmark_cells::<Wrapping<u8>>(bf, input, config),
+        CellWidth::U16 => benchmark_cells::<Wrapping<u16>>(bf, input, config),
+        CellWidth::U32 => benchmark_cells::<Wrapping<u32>>(bf, input, config),
+    }
+}
+
+#[cfg(feature = "std")]
+fn benchmark_cells<C: Cell>(
+    bf: &str,
+    input: &[Wrapping<u8>],
+    config: Config,
+) -> Result<LevelTimings, OptimizerError> {
+    let levels = OptimizationLevel::ALL
+        .into_iter()
+        .map(|level| {
+            let config = Config {
+                optimization_level: level,
+                ..config
+            };
+
+            let start = Instant::now();
+            let instructions = config.optimize(bf)?;
+            let optimize = start.elapsed();
+
+            // Building the interpreter allocates the tape, which isn't part of the run
+            let mut interpreter = config.builder::<C>().build(instructions);
+            let start = Instant::now();
+            let outcome = interpreter.run(input);
+            let run = start.elapsed();
+
+            Ok(LevelTiming {
+                level,
+                optimize,
+                run,
+                iterations: outcome.iterations,
+                error: outcome.error,
+            })
+        })
+        .collect::<Result<_, _>>()?;
+
+    Ok(LevelTimings { levels })
+}
+
+// How many instructions a level leaves in the program, loop bodies included.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LevelSize {
+    pub level: OptimizationLevel,
+    pub nodes: usize,
+}
+
+// The size of the program at every optimization level, from O0 up.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct OptimizationReport {
+    pub levels: Vec<LevelSize>,
+}
+
+impl OptimizationReport {
+    pub fn get(&self, level: OptimizationLevel) -> Option<&LevelSize> {
+        self.levels.iter().find(|size| size.level == level)
+    }
+}
+
+// One level per line, with how much smaller than O0 it is: `O3: 150 nodes (-64%)`.
+impl Display for OptimizationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let o0 = self.get(OptimizationLevel::O0).map_or(0, |size| size.nodes);
+        for size in &self.levels {
+            write!(f, "{:?}: {} nodes", size.level, size.nodes)?;
+            if size.level != OptimizationLevel::O0 && o0 > 0 {
+                let change = (size.nodes as f64 / o0 as f64 - 1.0) * 100.0;
+                write!(f, " ({change:+.0}%)")?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+// Optimizes the program at every level and counts what each leaves of it, a static measure of what the optimizer
+// does. See benchmark for what it does to the run.
+pub fn optimization_report(bf: &str) -> Result<OptimizationReport, OptimizerError> {
+    let stages = optimize_staged(bf)?;
+    let levels = OptimizationLevel::ALL
+        .into_iter()
+        .map(|level| LevelSize {
+            level,
+            nodes: count_instructions(stages.level(level), |_| true),
+        })
+        .collect();
+
+    Ok(OptimizationReport { levels })
+}
@@ -0,0 +1,190 @@
+// Runs a brainfuck program from disk, writing whatever it prints to stdout.
+
+use std::{
+    io::{self, Read, Write},
+    num::Wrapping,
+    path::Path,
+    process::ExitCode,
+};
+
+use bf_instrumentor::{
+    format_ir, optimization_report, optimize_bytes, Interpreter, OptimizationLevel,
+};
+
+const USAGE: &str = "usage: bf <file> [-O0|-O1|-O2|-O3|-O4] [--max-iterations <n>] [--input <file or text>]
+          [--emit ir] [--profile]
+
+Without --input the program's input is read from stdin as the program asks for it. An --input that names a file is
+read from that file, anything else is used as the input text itself. --emit ir prints the optimized IR instead of
+running the program. --profile writes how many instructions every optimization level leaves to stderr first.";
+
+// Exit codes, 0 is a successful run
+const USAGE_ERROR: u8 = 1;
+const OPTIMIZER_ERROR: u8 = 2;
+const RUN_TIME_ERROR: u8 = 3;
+
+struct Args {
+    file: String,
+    optimization_level: OptimizationLevel,
+    max_iterations: usize,
+    input: Option<String>,
+    // Print the optimized IR rather than running the program
+    emit_ir: bool,
+    // Report the size of the program at every level on stderr
+    profile: bool,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut file = None;
+    let mut optimization_level = OptimizationLevel::O3;
+    let mut max_iterations = usize::MAX;
+    let mut input = None;
+    let mut emit_ir = false;
+    let mut profile = false;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-O0" => optimization_level = OptimizationLevel::O0,
+            "-O1" => optimization_level = OptimizationLevel::O1,
+            "-O2" => optimization_level = OptimizationLevel::O2,
+            "-O3" => optimization_level = OptimizationLevel::O3,
+            "-O4" => optimization_level = OptimizationLevel::O4,
+            "--max-iterations" => {
+                let n = args.next().ok_or("--max-iterations needs a value")?;
+                max_iterations = n
+                    .parse()
+                    .map_err(|_| format!("invalid --max-iterations: {n}"))?;
+            }
+            "--input" => input = Some(args.next().ok_or("--input needs a value")?),
+            "--emit" => match args.next().as_deref() {
+                Some("ir") => emit_ir = true,
+                Some(emit) => return Err(format!("unknown --emit: {emit}")),
+                None => return Err("--emit needs a value".to_string()),
+            },
+            "--profile" => profile = true,
+            _ if arg.starts_with('-') => return Err(format!("unknown flag: {arg}")),
+            _ if file.is_none() => file = Some(arg),
+            _ => return Err(format!("unexpected argument: {arg}")),
+        }
+    }
+
+    Ok(Args {
+        file: file.ok_or("no program file given")?,
+        optimization_level,
+        max_iterations,
+        input,
+        emit_ir,
+        profile,
+    })
+}
+
+fn read_input(input: &str) -> io::Result<Vec<Wrapping<u8>>> {
+    let bytes = match input {
+        path if Path::new(path).is_file() => std::fs::read(path)?,
+        text => text.as_bytes().to_vec(),
+    };
+
+    Ok(bytes.into_iter().map(Wrapping).collect())
+}
+
+// Reads stdin a byte at a time as the program asks for it, so an interactive program gets each line as it's typed.
+// Stdout is flushed first so a prompt without a newline shows up before the read blocks. A failed read ends the
+// input and is kept in `error`.
+fn stdin_bytes(error: &mut Option<io::Error>) -> impl Iterator<Item = Wrapping<u8>> + '_ {
+    let mut stdin = io::stdin();
+    std::iter::from_fn(move || {
+        let _ = io::stdout().flush();
+        let mut byte = [0];
+        loop {
+            match stdin.read(&mut byte) {
+                Ok(0) => return None,
+                Ok(_) => return Some(Wrapping(byte[0])),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => {
+                    *error = Some(err);
+                    return None;
+                }
+            }
+        }
+    })
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("bf: {err}\n\n{USAGE}");
+            return ExitCode::from(USAGE_ERROR);
+        }
+    };
+
+    // Anything that isn't a command is a comment, so the source doesn't have to be UTF-8
+    let bf = match std::fs::read(&args.file) {
+        Ok(bf) => bf,
+        Err(err) => {
+            eprintln!("bf: can't read {}: {err}", args.file);
+            return ExitCode::from(USAGE_ERROR);
+        }
+    };
+
+    let program = match optimize_bytes(bf.iter().copied(), args.optimization_level) {
+        Ok(program) => program,
+        Err(err) => {
+            eprintln!("bf: {}: {}", args.file, err);
+            return ExitCode::from(OPTIMIZER_ERROR);
+        }
+    };
+
+    // Optimizing already succeeded at one level, so it does at every other level too. Invalid UTF-8 only turns into
+    // U+FFFD, which is as much a comment as the bytes it replaces.
+    if args.profile {
+        if let Ok(report) = optimization_report(&String::from_utf8_lossy(&bf)) {
+            eprint!("{report}");
+        }
+    }
+
+    if args.emit_ir {
+        // The header names the level so dumps of different levels can be diffed
+        print!(
+            "; {} at {:?}\n{}",
+            args.file,
+            args.optimization_level,
+            format_ir(&program)
+        );
+        return ExitCode::SUCCESS;
+    }
+
+    // An --input is read up front, only stdin is read while the program runs
+    let input = match args.input.as_deref().map(read_input).transpose() {
+        Ok(input) => input,
+        Err(err) => {
+            eprintln!("bf: can't read the input: {err}");
+            return ExitCode::from(USAGE_ERROR);
+        }
+    };
+
+    // Output is streamed, so everything printed before an error still reaches stdout
+    let mut interpreter: Interpreter = Interpreter::builder()
+        .max_iterations(args.max_iterations)
+        .build(program);
+    let mut stdout = io::stdout().lock();
+    let mut read_error = None;
+    let err = match input {
+        Some(input) => interpreter.run_with_writer(&input, &mut stdout),
+        None => interpreter.run_iter_with_writer(stdin_bytes(&mut read_error), &mut stdout),
+    };
+    let _ = stdout.flush();
+
+    if let Some(err) = read_error {
+        eprintln!("bf: can't read the input: {err}");
+        return ExitCode::from(USAGE_ERROR);
+    }
+
+    match err {
+        None => ExitCode::SUCCESS,
+        Some(err) => {
+            eprintln!("bf: {}: {}", args.file, err);
+            ExitCode::from(RUN_TIME_ERROR)
+        }
+    }
+}
@@ -0,0 +1,125 @@
+// Transpiles optimized IR into a standalone C program, or into a readable listing.
+
+use alloc::{
+    format,
+    string::{String, ToString},
+};
+
+use crate::parser::IR;
+
+// Number of cells in the generated program's tape. Matches the interpreter's default fixed tape.
+const TAPE_SIZE: usize = 65536;
+
+// Emits a C program equivalent to the IR. `cell_type` is the C type of a single cell, for example `unsigned char`
+// or `uint16_t`; it should be unsigned so that cells wrap. The pointer starts at cell 0, like the interpreter.
+// A Read at the end of input stores EOF (-1) into the cell. A failing Assert exits with status 1.
+pub fn to_c(ir: &[IR], cell_type: &str) -> String {
+    let mut c = String::new();
+
+    c += "#include <stdint.h>\n";
+    c += "#include <stdio.h>\n\n";
+    c += &format!("static {cell_type} mem[{TAPE_SIZE}];\n\n");
+    c += "int main(void) {\n";
+    c += "    long p = 0;\n";
+    emit(ir, 1, &mut c);
+    c += "    return 0;\n";
+    c += "}\n";
+
+    c
+}
+
+// Formats `mem[p+offset]`.
+fn cell(offset: i32) -> String {
+    match offset {
+        0 => "mem[p]".to_string(),
+        offset if offset < 0 => format!("mem[p-{}]", offset.unsigned_abs()),
+        offset => format!("mem[p+{offset}]"),
+    }
+}
+
+fn emit(ir: &[IR], depth: usize, c: &mut String) {
+    let indent = "    ".repeat(depth);
+
+    for instruction in ir {
+        match instruction {
+            IR::Add { x, offset } => *c += &format!("{indent}{} += {x};\n", cell(*offset)),
+            IR::Move { over } => *c += &format!("{indent}p += {over};\n"),
+            IR::Print { times: 1, offset } => {
+                *c += &format!("{indent}putchar({});\n", cell(*offset));
+            }
+            IR::Print { times, offset } => {
+                *c += &format!(
+                    "{indent}for (int i = 0; i < {times}; i++) putchar({});\n",
+                    cell(*offset)
+                );
+            }
+            IR::Read { offset } => *c += &format!("{indent}{} = getchar();\n", cell(*offset)),
+            IR::Exact { x, offset } => *c += &format!("{indent}{} = {x};\n", cell(*offset)),
+            IR::Clear { offset } => *c += &format!("{indent}{} = 0;\n", cell(*offset)),
+            IR::Loop { over, instructions } => {
+                if *over != 0 {
+                    *c += &format!("{indent}p += {over};\n");
+                }
+                *c += &format!("{indent}while (mem[p]) {{\n");
+                emit(instructions, depth + 1, c);
+                *c += &format!("{indent}}}\n");
+            }
+            IR::Mul { x, y, offset } => {
+                *c += &format!("{indent}{} += {} * {y};\n", cell(offset + x), cell(*offset));
+            }
+            IR::ScanZero { step } => *c += &format!("{indent}while (mem[p]) p += {step};\n"),
+            // There is no debug hook in C
+            IR::Debug { .. } => {}
+            IR::Assert { offset, expected } => {
+                *c += &format!("{indent}if ({} != {expected}) return 1;\n", cell(*offset));
+            }
+        }
+    }
+}
+
+// Formats IR as a listing with one instruction per line, for reading what the optimizer did. Loop bodies are
+// indented one level further than their loop. Offsets are written relative to the pointer, so `Add x=3 @+2` adds 3
+// two cells to the right and `Mul y=2 dst=+1 @+0` adds twice the current cell to the next one.
+pub fn format_ir(ir: &[IR]) -> String {
+    let mut listing = String::new();
+    list(ir, 0, &mut listing);
+    listing
+}
+
+// Formats an offset with its sign, `+0` for the current cell.
+fn relative(offset: i32) -> String {
+    format!("{offset:+}")
+}
+
+fn list(ir: &[IR], depth: usize, listing: &mut String) {
+    let indent = "    ".repeat(depth);
+
+    for instruction in ir {
+        let line = match instruction {
+            IR::Add { x, offset } => format!("Add x={x} @{}", relative(*offset)),
+            IR::Move { over } => format!("Move over={}", relative(*over)),
+            IR::Print { times, offset } => format!("Print times={times} @{}", relative(*offset)),
+            IR::Read { offset } => format!("Read @{}", relative(*offset)),
+            IR::Exact { x, offset } => format!("Exact x={x} @{}", relative(*offset)),
+            IR::Clear { offset } => format!("Clear @{}", relative(*offset)),
+            IR::Loop { over, .. } => format!("Loop @{}", relative(*over)),
+            IR::Mul { x, y, offset } => {
+                format!(
+                    "Mul y={y} dst={} @{}",
+                    relative(offset + x),
+                    relative(*offset)
+                )
+            }
+            IR::ScanZero { step } => format!("ScanZero step={}", relative(*step)),
+            IR::Debug { offset } => format!("Debug @{}", relative(*offset)),
+            IR::Assert { offset, expected } => {
+                format!("Assert expected={expected} @{}", relative(*offset))
+            }
+        };
+        *listing += &format!("{indent}{line}\n");
+
+        if let IR::Loop { instructions, .. } = instruction {
+            list(instructions, depth + 1, listing);
+        }
+    }
+}
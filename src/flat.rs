@@ -0,0 +1,54 @@
+// Flattens IR into a linear program where loops are replaced by jumps, so it can be executed without recursion.
+
+use alloc::{vec, vec::Vec};
+
+use crate::parser::IR;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum FlatOp {
+    // Any instruction other than a Loop
+    Op(IR),
+    // The start of a loop. Moves the pointer `over` cells, then jumps to `target` (just past the matching
+    // JumpIfNonZero) if the cell is zero.
+    JumpIfZero { over: i32, target: usize },
+    // The end of a loop. Jumps to `target` (just past the matching JumpIfZero) if the cell is non-zero.
+    JumpIfNonZero { target: usize },
+}
+
+// Turns every Loop into a JumpIfZero, its flattened body and a JumpIfNonZero. Works with an explicit stack, so
+// arbitrarily deep nesting is fine.
+pub fn flatten(ir: &[IR]) -> Vec<FlatOp> {
+    let mut ops = Vec::new();
+    // The remaining instructions of every block being flattened, along with the index of the JumpIfZero that
+    // opened it (None for the program itself)
+    let mut stack = vec![(ir.iter(), None)];
+
+    while let Some((instructions, start)) = stack.last_mut() {
+        match instructions.next() {
+            Some(IR::Loop { over, instructions }) => {
+                // The target is patched once the end of the loop is known
+                let start = ops.len();
+                ops.push(FlatOp::JumpIfZero {
+                    over: *over,
+                    target: 0,
+                });
+                stack.push((instructions.iter(), Some(start)));
+            }
+            Some(instruction) => ops.push(FlatOp::Op(instruction.clone())),
+            None => {
+                if let Some(start) = *start {
+                    ops.push(FlatOp::JumpIfNonZero { target: start + 1 });
+
+                    let end = ops.len();
+                    if let FlatOp::JumpIfZero { target, .. } = &mut ops[start] {
+                        *target = end;
+                    }
+                }
+                stack.pop();
+            }
+        }
+    }
+
+    ops
+}
@@ -1,180 +1,1626 @@
-use std::{cmp::Ordering, num::Wrapping};
+use alloc::{boxed::Box, collections::VecDeque, vec, vec::Vec};
+use core::{
+    fmt::{self, Debug, Display},
+    hash::Hash,
+    marker::PhantomData,
+    num::Wrapping,
+    ops::{Add, Mul},
+};
+#[cfg(feature = "std")]
+use std::{
+    hash::{DefaultHasher, Hasher},
+    io::{self, Write},
+    time::{Duration, Instant},
+};
 
-use crate::parser::IR;
+use crate::{
+    analysis::{loop_sizes, LoopId},
+    flat::{flatten, FlatOp},
+    parser::IR,
+    tape::{Tape, TapeMode},
+    HashMap, HashSet,
+};
 
-type Cell = Wrapping<u8>;
+// How many iterations run between checks of the clock when a timeout is set.
+#[cfg(feature = "std")]
+const TIMEOUT_CHECK_INTERVAL: usize = 1024;
+
+// A single memory cell on the tape. Cells are unsigned integers with wrapping arithmetic, the width of the
+// integer decides where the wrapping happens. Input and output are bytes, by default a Read stores a single byte
+// into the cell and a Print emits the lowest byte of the cell, see CellEncoding for the other options.
+pub trait Cell:
+    Copy + Default + Debug + Eq + Hash + Add<Output = Self> + Mul<Output = Self>
+{
+    // Converts an IR constant into a cell, wrapping it modulo the cell width.
+    fn from_i32(x: i32) -> Self;
+    fn from_byte(byte: Wrapping<u8>) -> Self;
+    // Truncates the cell to its lowest byte.
+    fn to_byte(self) -> Wrapping<u8>;
+    fn to_u32(self) -> u32;
+    // The largest value a cell can hold, and a conversion for values up to it.
+    const MAX: u32;
+    fn from_u32(x: u32) -> Self;
+    // The width of the cell in bytes.
+    const BYTES: usize;
+
+    fn is_zero(self) -> bool {
+        self == Self::default()
+    }
+}
+
+macro_rules! impl_cell {
+    ($($t:ty),*) => {
+        $(
+            impl Cell for Wrapping<$t> {
+                fn from_i32(x: i32) -> Self {
+                    Wrapping(x as $t)
+                }
+
+                fn from_byte(byte: Wrapping<u8>) -> Self {
+                    Wrapping(byte.0.into())
+                }
+
+                fn to_byte(self) -> Wrapping<u8> {
+                    Wrapping(self.0 as u8)
+                }
+
+                fn to_u32(self) -> u32 {
+                    self.0.into()
+                }
+
+                const MAX: u32 = <$t>::MAX as u32;
+
+                fn from_u32(x: u32) -> Self {
+                    Wrapping(x as $t)
+                }
+
+                const BYTES: usize = core::mem::size_of::<$t>();
+            }
+        )*
+    };
+}
+
+impl_cell!(u8, u16, u32);
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum RunTimeError {
-    OutOfBounds,
+    // A cell outside of the tape was accessed. `index` is the cell, relative to where the pointer started. A Mul
+    // reports whichever of its source or destination was outside, checking the source first.
+    OutOfBounds {
+        index: i32,
+    },
     OutOfInputs,
     MaxIterationsExceeded,
+    Timeout,
+    // Reading the input failed
+    #[cfg(feature = "std")]
+    ReadFailed(io::ErrorKind),
+    // Writing the output failed
+    #[cfg(feature = "std")]
+    WriteFailed(io::ErrorKind),
+    // An Add, Mul or Exact left the range of the cell while Overflow::Error is used. `value` is the result the
+    // instruction would have stored.
+    IntegerOverflow {
+        cell: i32,
+        value: i64,
+    },
+    // The program printed more than the output limit allows. What it printed up to the limit is still output.
+    OutputLimitExceeded,
+    // An IR::Assert found `actual` instead of `expected` in cell `offset`, relative to where the pointer started
+    AssertionFailed {
+        offset: i32,
+        expected: u32,
+        actual: u32,
+    },
+}
+
+impl Display for RunTimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RunTimeError::OutOfBounds { index } => write!(f, "cell {index} is outside of the tape"),
+            RunTimeError::OutOfInputs => write!(f, "the program read past the end of its input"),
+            RunTimeError::MaxIterationsExceeded => write!(f, "the program ran out of iterations"),
+            RunTimeError::Timeout => write!(f, "the program ran out of time"),
+            #[cfg(feature = "std")]
+            RunTimeError::ReadFailed(kind) => write!(f, "reading the input failed: {kind}"),
+            #[cfg(feature = "std")]
+            RunTimeError::WriteFailed(kind) => write!(f, "writing the output failed: {kind}"),
+            RunTimeError::IntegerOverflow { cell, value } => {
+                write!(f, "cell {cell} overflowed with the value {value}")
+            }
+            RunTimeError::OutputLimitExceeded => write!(f, "the program printed too much output"),
+            RunTimeError::AssertionFailed {
+                offset,
+                expected,
+                actual,
+            } => write!(f, "cell {offset} holds {actual} instead of {expected}"),
+        }
+    }
+}
+
+// Decides what happens when an Add, Mul or Exact produces a value that doesn't fit in a cell. The check applies to
+// the instructions being executed, so optimized IR can overflow where its source doesn't, for example `-+` only
+// overflows before the Adds are merged. Config only optimizes as much as O0 does unless cells wrap, which keeps
+// every level behaving like the source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Overflow {
+    // Wrap around modulo the cell width
+    #[default]
+    Wrap,
+    // Stop the program with RunTimeError::IntegerOverflow
+    Error,
+    // Clamp to 0 or the maximum value of the cell
+    Saturate,
+}
+
+// Writes `bytes` to the output `times` times, in chunks to keep large repeats cheap.
+#[cfg(feature = "std")]
+fn write_repeated(output: &mut dyn Write, bytes: &[u8], times: usize) -> io::Result<()> {
+    // As many repeats as fit in 512 bytes
+    let per_chunk = (512 / bytes.len()).max(1);
+    let chunk = bytes.repeat(per_chunk.min(times));
+    let mut remaining = times;
+    while remaining > 0 {
+        let n = remaining.min(per_chunk);
+        output.write_all(&chunk[..n * bytes.len()])?;
+        remaining -= n;
+    }
+    Ok(())
+}
+
+// Where the bytes of a Print go. Every Print is handed over in one call, however many times it repeats.
+trait Output {
+    // Prints the encoded cell `bytes` `times` times.
+    fn print(&mut self, bytes: &[u8], times: usize) -> Result<(), RunTimeError>;
+}
+
+#[cfg(feature = "std")]
+impl<W: Write> Output for W {
+    fn print(&mut self, bytes: &[u8], times: usize) -> Result<(), RunTimeError> {
+        write_repeated(self, bytes, times).map_err(|err| RunTimeError::WriteFailed(err.kind()))
+    }
+}
+
+// Without std the output can only be collected.
+#[cfg(not(feature = "std"))]
+impl Output for Vec<u8> {
+    fn print(&mut self, bytes: &[u8], times: usize) -> Result<(), RunTimeError> {
+        self.extend(bytes.repeat(times));
+        Ok(())
+    }
+}
+
+// Passes the bytes of every Print to a closure, see Interpreter::run_with_callback.
+struct Callback<F>(F);
+
+impl<F: FnMut(&[Wrapping<u8>])> Output for Callback<F> {
+    fn print(&mut self, bytes: &[u8], times: usize) -> Result<(), RunTimeError> {
+        let bytes: Vec<_> = bytes.repeat(times).into_iter().map(Wrapping).collect();
+        (self.0)(&bytes);
+        Ok(())
+    }
+}
+
+// Applies the overflow behavior to an arithmetic result. `wrapped` is the result with wrapping arithmetic and `exact`
+// the mathematically exact one.
+fn overflowed<C: Cell>(
+    overflow: Overflow,
+    cell: i32,
+    wrapped: C,
+    exact: i128,
+) -> Result<C, RunTimeError> {
+    if overflow == Overflow::Wrap {
+        return Ok(wrapped);
+    }
+
+    match u32::try_from(exact) {
+        Ok(value) if value <= C::MAX => Ok(C::from_u32(value)),
+        _ if overflow == Overflow::Saturate => {
+            Ok(C::from_u32(exact.clamp(0, C::MAX.into()) as u32))
+        }
+        _ => Err(RunTimeError::IntegerOverflow {
+            cell,
+            value: exact.clamp(i64::MIN.into(), i64::MAX.into()) as i64,
+        }),
+    }
+}
+
+// Decides what a Read does once the input has been exhausted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum EofBehavior {
+    // Stop the program with RunTimeError::OutOfInputs
+    #[default]
+    Error,
+    // Leave the cell unchanged and continue
+    Unchanged,
+    // Set the cell to 0 and continue
+    Zero,
+    // Set the cell to -1 (the maximum value of the cell) and continue
+    NegativeOne,
+    // Set the cell to this byte and continue, for programs that read until a sentinel
+    Value(u8),
+}
+
+// Decides how many bytes of input a Read consumes and how many bytes of output a Print produces per cell. Only
+// matters for cells wider than a byte, 8-bit cells always read and print a single byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum CellEncoding {
+    // A Read stores one byte in the cell and a Print writes the lowest byte of the cell
+    #[default]
+    LowByte,
+    // A Read consumes and a Print writes one byte per byte of the cell, least significant byte first. Input that
+    // ends in the middle of a cell is RunTimeError::OutOfInputs whatever the EofBehavior.
+    LittleEndian,
+    // Same as LittleEndian, most significant byte first
+    BigEndian,
+}
+
+impl CellEncoding {
+    // How many bytes a cell takes up in the input and the output.
+    fn width<C: Cell>(self) -> usize {
+        match self {
+            CellEncoding::LowByte => 1,
+            CellEncoding::LittleEndian | CellEncoding::BigEndian => C::BYTES,
+        }
+    }
+
+    // Returns the bytes a Print of `cell` writes in the first `width` bytes of the array.
+    fn encode<C: Cell>(self, cell: C) -> ([u8; 4], usize) {
+        let width = self.width::<C>();
+        let value = cell.to_u32();
+        match self {
+            CellEncoding::LowByte | CellEncoding::LittleEndian => (value.to_le_bytes(), width),
+            CellEncoding::BigEndian => {
+                let mut bytes = [0; 4];
+                bytes[..width].copy_from_slice(&value.to_be_bytes()[4 - width..]);
+                (bytes, width)
+            }
+        }
+    }
+
+    // Reads the next cell from the input. None when the input ended before the cell started.
+    fn decode<C: Cell>(
+        self,
+        inputs: &mut impl Iterator<Item = Wrapping<u8>>,
+    ) -> Result<Option<C>, RunTimeError> {
+        let Some(first) = inputs.next() else {
+            return Ok(None);
+        };
+
+        let mut value = u32::from(first.0);
+        for i in 1..self.width::<C>() {
+            let byte = u32::from(inputs.next().ok_or(RunTimeError::OutOfInputs)?.0);
+            value = match self {
+                CellEncoding::BigEndian => value << 8 | byte,
+                _ => value | byte << (8 * i),
+            };
+        }
+
+        Ok(Some(C::from_u32(value)))
+    }
+}
+
+// Dynamic instruction counts collected while profiling.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Profile {
+    // Same as Interpreter::get_iterations, includes every loop condition check.
+    pub iterations: usize,
+    // How many times each kind of instruction was executed, keyed by IR::name. "Loop" counts loop iterations
+    // (executions of the loop body) rather than how often a loop was reached.
+    pub instructions: HashMap<&'static str, u64>,
+    // How many times the body of each loop ran, in LoopId order. Loops whose body never ran are left out.
+    pub loops: Vec<(LoopId, u64)>,
+}
+
+// How often the instructions a run executed accessed a single cell, see Interpreter::run_instrumented. Every
+// instruction counts once however many times it repeats, so the counts depend on the optimization level.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct CellStats {
+    // Prints, loop condition checks, the cells a ScanZero passes over and the sources of Muls
+    pub reads: u64,
+    // Adds, Exacts, Clears, Reads and the destinations of Muls
+    pub writes: u64,
+}
+
+// Everything a run leaves behind. The output is kept even if the run stopped with an error.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunOutcome {
+    pub output: Vec<Wrapping<u8>>,
+    pub error: Option<RunTimeError>,
+    // Same as Interpreter::get_iterations once the run has finished
+    pub iterations: usize,
+    pub final_pointer: i32,
+    // Same as Interpreter::get_min_pointer and get_max_pointer once the run has finished
+    pub min_pointer: i32,
+    pub max_pointer: i32,
+}
+
+// The outcome of a single Interpreter::step.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StepResult {
+    // The program has finished, further steps do nothing
+    Done,
+    Error(RunTimeError),
+    // The step printed these bytes
+    Output(Vec<Wrapping<u8>>),
+    Continue,
+}
+
+// Conditions that stop Interpreter::run_until_breakpoint.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Breakpoints {
+    // Stop when the pointer moves onto one of these cells
+    pub on_pointer: HashSet<i32>,
+    // Stop right after one of these cells is written with a different value
+    pub on_cell_change: HashSet<i32>,
+    // Stop before the instruction with this flattened index runs, see Interpreter::instruction_index
+    pub on_instruction: HashSet<usize>,
+}
+
+// The breakpoint that stopped Interpreter::run_until_breakpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Breakpoint {
+    Pointer(i32),
+    CellChange(i32),
+    Instruction(usize),
+}
+
+// Which of the instructions a run executes are recorded as TraceEvents, see Interpreter::run_traced.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum TraceMode {
+    #[default]
+    Off,
+    All,
+    // Only the most recent events, so long runs can be traced in bounded memory
+    Last(usize),
+}
+
+// A single instruction a traced run executed. Enough to replay the run forwards or undo it backwards.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TraceEvent<C: Cell = Wrapping<u8>> {
+    // The position of the instruction in flatten(program). Loops show up as the JumpIfZero that enters them and the
+    // JumpIfNonZero that checks their condition again after each run of the body.
+    pub index: usize,
+    pub op: FlatOp,
+    pub pointer_before: i32,
+    pub pointer_after: i32,
+    // The index, old and new value of every cell the instruction changed
+    pub changes: Vec<(i32, C, C)>,
+}
+
+// What has to be known before an op runs to record it: the pointer, and the cell the op may write along with what
+// it held.
+struct TraceStart<C> {
+    pointer: i32,
+    written: Option<(i32, Option<C>)>,
 }
 
 // Implements an interpreter that makes use of the optimizations presented in http://calmerthanyouare.org/2015/01/07/optimizing-brainfuck.html
 // The interpreter is constructed with the BF program it is supposed to execute. Test cases are provided as an iterator of (input: Vec, output: Vec) tuples.
-pub struct Interpreter {
+pub struct Interpreter<C: Cell = Wrapping<u8>> {
     program: Vec<IR>,
-    memory: Vec<Cell>,
+    memory: Tape<C>,
     pointer: i32,
+    // The lowest and highest cell the pointer has reached since the last reset
+    min_pointer: i32,
+    max_pointer: i32,
     iterations: usize,
     max_iterations: usize,
+    // How many bytes were printed since the last reset, and how many may be
+    printed: usize,
+    max_output: Option<usize>,
+    // The wall-clock budget of a run, and when the current run has to be done by
+    #[cfg(feature = "std")]
+    timeout: Option<Duration>,
+    #[cfg(feature = "std")]
+    deadline: Option<Instant>,
+    eof: EofBehavior,
+    encoding: CellEncoding,
+    overflow: Overflow,
+    // Whether a Move that leaves the tape fails right away instead of only when the cell is accessed
+    strict_moves: bool,
+    // Only tracked when profiling is enabled
+    instructions: Option<HashMap<&'static str, u64>>,
+    // Body executions of every loop, indexed by LoopId. Grows as loops are reached.
+    loops: Vec<u64>,
+    // Only tracked when cell stats are enabled, keyed by the index of the cell
+    cell_stats: Option<HashMap<i32, CellStats>>,
+    // State used by step. `stack` holds the index of the next instruction at every loop depth, the outermost
+    // program first. `input` is filled using push_input.
+    stack: Vec<usize>,
+    input: VecDeque<Wrapping<u8>>,
+    // Called on every IR::Debug along with how many cells on each side of the pointer it is shown
+    debug_hook: Option<(usize, DebugHook<C>)>,
+    trace_mode: TraceMode,
+    trace: VecDeque<TraceEvent<C>>,
+}
+
+// Called with the index of the cell an IR::Debug runs on and the memory window around it, see memory_window.
+pub type DebugHook<C> = Box<dyn FnMut(i32, &[(i32, C)])>;
+
+// Configures an Interpreter before it is built. Every option starts out with the same default Interpreter::from
+// uses, except that there is no limit on the number of iterations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct InterpreterBuilder<C: Cell = Wrapping<u8>> {
+    max_iterations: usize,
+    max_output: Option<usize>,
+    #[cfg(feature = "std")]
+    timeout: Option<Duration>,
+    eof: EofBehavior,
+    encoding: CellEncoding,
+    overflow: Overflow,
+    tape: TapeMode,
+    profiling: bool,
+    cell_stats: bool,
+    strict_moves: bool,
+    trace: TraceMode,
+    cells: PhantomData<C>,
+}
+
+impl Default for InterpreterBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InterpreterBuilder {
+    pub fn new() -> Self {
+        Self {
+            max_iterations: usize::MAX,
+            max_output: None,
+            #[cfg(feature = "std")]
+            timeout: None,
+            eof: EofBehavior::default(),
+            encoding: CellEncoding::default(),
+            overflow: Overflow::default(),
+            tape: TapeMode::default(),
+            profiling: false,
+            cell_stats: false,
+            strict_moves: false,
+            trace: TraceMode::Off,
+            cells: PhantomData,
+        }
+    }
+}
+
+impl<C: Cell> InterpreterBuilder<C> {
+    pub fn max_iterations(mut self, max_iterations: usize) -> Self {
+        self.max_iterations = max_iterations;
+        self
+    }
+
+    pub fn max_output(mut self, max_output: usize) -> Self {
+        self.max_output = Some(max_output);
+        self
+    }
+
+    #[cfg(feature = "std")]
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn eof(mut self, eof: EofBehavior) -> Self {
+        self.eof = eof;
+        self
+    }
+
+    pub fn encoding(mut self, encoding: CellEncoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    pub fn overflow(mut self, overflow: Overflow) -> Self {
+        self.overflow = overflow;
+        self
+    }
+
+    pub fn tape(mut self, tape: TapeMode) -> Self {
+        self.tape = tape;
+        self
+    }
+
+    pub fn profiling(mut self, enabled: bool) -> Self {
+        self.profiling = enabled;
+        self
+    }
+
+    pub fn cell_stats(mut self, enabled: bool) -> Self {
+        self.cell_stats = enabled;
+        self
+    }
+
+    pub fn strict_moves(mut self, enabled: bool) -> Self {
+        self.strict_moves = enabled;
+        self
+    }
+
+    pub fn trace(mut self, mode: TraceMode) -> Self {
+        self.trace = mode;
+        self
+    }
+
+    // Changes the type of the cells, for example `.cell_width::<Wrapping<u16>>()`.
+    pub fn cell_width<D: Cell>(self) -> InterpreterBuilder<D> {
+        InterpreterBuilder {
+            max_iterations: self.max_iterations,
+            max_output: self.max_output,
+            #[cfg(feature = "std")]
+            timeout: self.timeout,
+            eof: self.eof,
+            encoding: self.encoding,
+            overflow: self.overflow,
+            tape: self.tape,
+            profiling: self.profiling,
+            cell_stats: self.cell_stats,
+            strict_moves: self.strict_moves,
+            trace: self.trace,
+            cells: PhantomData,
+        }
+    }
+
+    pub fn build(self, program: Vec<IR>) -> Interpreter<C> {
+        let mut interpreter = Interpreter::with_tape(program, self.max_iterations, self.tape);
+        interpreter.set_eof(self.eof);
+        interpreter.set_encoding(self.encoding);
+        interpreter.set_max_output(self.max_output);
+        #[cfg(feature = "std")]
+        interpreter.set_timeout(self.timeout);
+        interpreter.set_overflow(self.overflow);
+        interpreter.set_profiling(self.profiling);
+        interpreter.set_cell_stats(self.cell_stats);
+        interpreter.set_strict_moves(self.strict_moves);
+        interpreter.set_trace_mode(self.trace);
+        interpreter
+    }
 }
 
 impl Interpreter {
+    pub fn builder() -> InterpreterBuilder {
+        InterpreterBuilder::new()
+    }
+
+    // An interpreter that behaves like the reference implementations most programs are written against: 30000
+    // wrapping 8-bit cells, moving off either end of the tape is an error, a Read at the end of input leaves the cell
+    // unchanged and there is no limit on the number of iterations.
+    pub fn classic(program: Vec<IR>) -> Self {
+        Interpreter::builder()
+            .tape(TapeMode::Fixed(30000))
+            .eof(EofBehavior::Unchanged)
+            .build(program)
+    }
+}
+
+impl<C: Cell> Interpreter<C> {
     pub fn from(program: Vec<IR>, max_iterations: usize) -> Self {
+        Self::with_tape(program, max_iterations, TapeMode::default())
+    }
+
+    pub fn with_tape(program: Vec<IR>, max_iterations: usize, tape: TapeMode) -> Self {
         Self {
             program,
-            memory: vec![Wrapping(0); 65536],
+            memory: Tape::new(tape),
             pointer: 0,
+            min_pointer: 0,
+            max_pointer: 0,
             iterations: 0,
             max_iterations,
+            printed: 0,
+            max_output: None,
+            #[cfg(feature = "std")]
+            timeout: None,
+            #[cfg(feature = "std")]
+            deadline: None,
+            eof: EofBehavior::default(),
+            encoding: CellEncoding::default(),
+            overflow: Overflow::default(),
+            strict_moves: false,
+            instructions: None,
+            loops: vec![],
+            cell_stats: None,
+            stack: vec![0],
+            input: VecDeque::new(),
+            debug_hook: None,
+            trace_mode: TraceMode::Off,
+            trace: VecDeque::new(),
         }
     }
 
-    pub fn return_shrinked_memory(&self) -> Vec<Cell> {
-        // find the last non-zero cell
-        let mut last_non_zero_cell = 0;
-        for (i, cell) in self.memory.iter().enumerate() {
-            if *cell != Wrapping(0) {
-                last_non_zero_cell = i;
-            }
-        }
+    // Same as Interpreter::from, but the tape starts out holding `initial` from cell 0 on instead of all zeros. reset
+    // restores this pattern.
+    pub fn with_initial_memory(
+        program: Vec<IR>,
+        max_iterations: usize,
+        initial: &[Wrapping<u8>],
+    ) -> Self {
+        let mut interpreter = Self::from(program, max_iterations);
+        interpreter.set_initial_memory(initial);
+        interpreter
+    }
+
+    // Sets what the tape holds from cell 0 on before the program runs, and resets the tape to it. Cells that don't
+    // fit on a fixed tape are dropped.
+    pub fn set_initial_memory(&mut self, initial: &[Wrapping<u8>]) {
+        self.memory
+            .set_initial(initial.iter().copied().map(C::from_byte).collect());
+    }
+
+    // Whether every cell holds what it held after the last reset.
+    pub fn memory_is_initial(&self) -> bool {
+        self.memory.is_initial()
+    }
+
+    pub fn set_eof(&mut self, eof: EofBehavior) {
+        self.eof = eof;
+    }
+
+    // Limits how many bytes may be printed between resets. The Print that goes over the limit only prints the bytes
+    // up to it, then stops the program with RunTimeError::OutputLimitExceeded.
+    pub fn set_max_output(&mut self, max_output: Option<usize>) {
+        self.max_output = max_output;
+    }
+
+    pub fn set_encoding(&mut self, encoding: CellEncoding) {
+        self.encoding = encoding;
+    }
+
+    pub fn set_overflow(&mut self, overflow: Overflow) {
+        self.overflow = overflow;
+    }
+
+    // Makes a Move that takes the pointer off the tape fail with RunTimeError::OutOfBounds, even if the pointer
+    // comes back before touching a cell. Only the moves that are left in the program are checked: the optimizer
+    // cancels out opposite moves unless it is asked to keep them, see fold_adjacent_strict.
+    pub fn set_strict_moves(&mut self, enabled: bool) {
+        self.strict_moves = enabled;
+    }
+
+    // Limits how long each run may take. The clock is only checked every few iterations, so a run can go slightly
+    // over the budget before stopping with RunTimeError::Timeout. Stepping is never timed out. Needs std for the clock.
+    #[cfg(feature = "std")]
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    pub fn return_shrinked_memory(&self) -> Vec<C> {
+        self.memory.shrinked()
+    }
+
+    // Returns the value of a single cell, None if the index is outside of the tape.
+    pub fn cell(&self, index: i32) -> Option<C> {
+        self.memory.get(index)
+    }
+
+    // Returns the indices and values of the cells in [center - radius, center + radius], leaving out any that are
+    // outside of the tape.
+    pub fn memory_window(&self, center: i32, radius: usize) -> Vec<(i32, C)> {
+        let radius = i32::try_from(radius).unwrap_or(i32::MAX);
+        let (lowest, highest) = self.memory.bounds();
+        (center.saturating_sub(radius).max(lowest)..=center.saturating_add(radius).min(highest))
+            .filter_map(|index| Some((index, self.memory.get(index)?)))
+            .collect()
+    }
+
+    // Returns the index and value of every cell that changed since the last reset, in index order, including cells
+    // left of 0 on a bidirectional tape. A cell that was changed and then set back to what it held is left out.
+    pub fn changed_cells(&self) -> Vec<(i32, C)> {
+        self.memory.changed()
+    }
 
-        self.memory[0..=last_non_zero_cell].to_vec()
+    // Makes every IR::Debug call `hook` with the cell it runs on and the `radius` cells on either side of it. Without a
+    // hook an IR::Debug does nothing.
+    pub fn set_debug_hook(&mut self, radius: usize, hook: impl FnMut(i32, &[(i32, C)]) + 'static) {
+        self.debug_hook = Some((radius, Box::new(hook)));
+    }
+
+    pub fn clear_debug_hook(&mut self) {
+        self.debug_hook = None;
+    }
+
+    // Makes the runs record what each instruction did, see run_traced. Tracing runs the flattened program, which
+    // behaves exactly like the recursive one but is slower, and keeps an event for every instruction. Changing the
+    // mode drops the events recorded so far.
+    pub fn set_trace_mode(&mut self, mode: TraceMode) {
+        self.trace_mode = mode;
+        self.trace.clear();
+    }
+
+    // Returns the events recorded since the last call or reset, oldest first.
+    pub fn take_trace(&mut self) -> Vec<TraceEvent<C>> {
+        core::mem::take(&mut self.trace).into()
     }
 
     pub fn get_pointer(&self) -> i32 {
         self.pointer
     }
 
+    // Hashes the pointer and every non-zero cell, to bucket the states many runs end in without comparing their tapes.
+    // Equal states always hash the same, different states almost never do, so compare return_shrinked_memory to be
+    // sure. Hashes are only comparable between interpreters of the same cell width and the same build of the crate.
+    #[cfg(feature = "std")]
+    pub fn tape_hash(&self) -> u64 {
+        let mut state = DefaultHasher::new();
+        self.pointer.hash(&mut state);
+        self.memory.hash_non_zero(&mut state);
+        state.finish()
+    }
+
+    // The lowest and highest cell the pointer reached since the last reset. A cell accessed through an offset counts
+    // as reached, as the optimizer folds the moves to it into the offset. Moves the optimizer cancels out, like `><`,
+    // only count at O0.
+    pub fn get_min_pointer(&self) -> i32 {
+        self.min_pointer
+    }
+
+    pub fn get_max_pointer(&self) -> i32 {
+        self.max_pointer
+    }
+
+    // The iterations used since the last reset, see IR::cost for what each instruction costs.
+    pub fn get_iterations(&self) -> usize {
+        self.iterations
+    }
+
+    pub fn set_profiling(&mut self, enabled: bool) {
+        self.instructions = enabled.then(HashMap::new);
+        self.loops.clear();
+    }
+
+    // Returns the profile of everything executed since the last reset, if profiling is enabled.
+    pub fn get_profile(&self) -> Option<Profile> {
+        self.instructions.as_ref().map(|instructions| Profile {
+            iterations: self.iterations,
+            instructions: instructions.clone(),
+            loops: self
+                .loops
+                .iter()
+                .enumerate()
+                .filter(|(_, count)| **count > 0)
+                .map(|(id, count)| (LoopId(id), *count))
+                .collect(),
+        })
+    }
+
+    fn count(&mut self, name: &'static str) {
+        if let Some(instructions) = &mut self.instructions {
+            *instructions.entry(name).or_default() += 1;
+        }
+    }
+
+    // Counts how often every cell is read and written from now on, see CellStats. Disabling it drops the counts.
+    pub fn set_cell_stats(&mut self, enabled: bool) {
+        self.cell_stats = enabled.then(HashMap::new);
+    }
+
+    // Returns the accesses to every cell since the last reset that touched it, if cell stats are enabled.
+    pub fn get_cell_stats(&self) -> Option<&HashMap<i32, CellStats>> {
+        self.cell_stats.as_ref()
+    }
+
+    fn note_read(&mut self, index: i32) {
+        if let Some(stats) = &mut self.cell_stats {
+            stats.entry(index).or_default().reads += 1;
+        }
+    }
+
+    fn note_write(&mut self, index: i32) {
+        if let Some(stats) = &mut self.cell_stats {
+            stats.entry(index).or_default().writes += 1;
+        }
+    }
+
     pub fn reset(&mut self) {
-        self.memory = vec![Wrapping(0); 65536];
+        self.memory.reset();
         self.pointer = 0;
+        self.min_pointer = 0;
+        self.max_pointer = 0;
         self.iterations = 0;
+        self.printed = 0;
+        if let Some(instructions) = &mut self.instructions {
+            instructions.clear();
+        }
+        self.loops.clear();
+        if let Some(stats) = &mut self.cell_stats {
+            stats.clear();
+        }
+        self.stack = vec![0];
+        self.input.clear();
+        self.trace.clear();
     }
 
-    pub fn run_vec<I>(
+    pub fn run_vec<I>(&mut self, instructions: &[IR], inputs: &mut I) -> RunOutcome
+    where
+        I: Iterator<Item = Wrapping<u8>>,
+    {
+        let mut output = Vec::new();
+        let err = self.run_vec_output(instructions, inputs, &mut output);
+        self.outcome(err, output)
+    }
+
+    // Same as run_vec, but everything the program prints is written to `output` as it happens instead of being
+    // collected. A failed write stops the program with RunTimeError::WriteFailed.
+    #[cfg(feature = "std")]
+    pub fn run_vec_with_writer<I>(
         &mut self,
-        instructions: Vec<IR>,
+        instructions: &[IR],
         inputs: &mut I,
-    ) -> (Option<RunTimeError>, Vec<Wrapping<u8>>)
+        mut output: &mut dyn Write,
+    ) -> Option<RunTimeError>
     where
         I: Iterator<Item = Wrapping<u8>>,
     {
-        let mut output = Vec::new();
+        self.run_vec_output(instructions, inputs, &mut output)
+    }
+
+    fn run_vec_output<I>(
+        &mut self,
+        instructions: &[IR],
+        inputs: &mut I,
+        output: &mut dyn Output,
+    ) -> Option<RunTimeError>
+    where
+        I: Iterator<Item = Wrapping<u8>>,
+    {
+        self.start_clock();
+        let sizes = loop_sizes(instructions);
+        let err = self.run_block(instructions, 0, &sizes, inputs, output);
+        self.stop_clock();
+        err
+    }
+
+    // `first` is the LoopId of the first loop in `instructions`, `sizes` comes from loop_sizes on the whole program.
+    fn run_block<I>(
+        &mut self,
+        instructions: &[IR],
+        first: usize,
+        sizes: &[usize],
+        inputs: &mut I,
+        output: &mut dyn Output,
+    ) -> Option<RunTimeError>
+    where
+        I: Iterator<Item = Wrapping<u8>>,
+    {
+        let mut next_id = first;
+
         for instruction in instructions {
+            if let Err(err) = self.tick() {
+                return Some(err);
+            }
+
+            if let IR::Loop { over, instructions } = instruction {
+                let id = next_id;
+                next_id += sizes[id];
+
+                // preform a move
+                self.move_pointer(*over);
+
+                // then begin the loop
+                loop {
+                    match self.check_loop(id) {
+                        Ok(true) => {}
+                        Ok(false) => break,
+                        Err(err) => return Some(err),
+                    }
+
+                    let err = self.run_block(instructions, id + 1, sizes, inputs, output);
+                    if err.is_some() {
+                        return err;
+                    }
+                }
+            } else if let Some(err) = self.execute(instruction, inputs, output) {
+                return Some(err);
+            }
+        }
+        None
+    }
+
+    // Sets the deadline of the run that is about to start, if there is a timeout.
+    fn start_clock(&mut self) {
+        #[cfg(feature = "std")]
+        {
+            self.deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        }
+    }
+
+    fn stop_clock(&mut self) {
+        #[cfg(feature = "std")]
+        {
+            self.deadline = None;
+        }
+    }
+
+    // Counts an iteration, failing once the program has used up its iterations or its time budget.
+    fn tick(&mut self) -> Result<(), RunTimeError> {
+        self.tick_by(1)
+    }
+
+    // Counts `n` iterations at once, for instructions that do the work of several.
+    fn tick_by(&mut self, n: usize) -> Result<(), RunTimeError> {
+        #[cfg(feature = "std")]
+        let before = self.iterations;
+        self.iterations = self.iterations.saturating_add(n);
+        if self.iterations > self.max_iterations {
+            return Err(RunTimeError::MaxIterationsExceeded);
+        }
+
+        // Reading the clock is comparatively slow, so it only happens every so often
+        #[cfg(feature = "std")]
+        if let Some(deadline) = self.deadline {
+            let crossed =
+                self.iterations / TIMEOUT_CHECK_INTERVAL != before / TIMEOUT_CHECK_INTERVAL;
+            if crossed && Instant::now() >= deadline {
+                return Err(RunTimeError::Timeout);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Moves the pointer, wrapping it around a circular tape.
+    fn move_pointer(&mut self, over: i32) {
+        self.pointer = self.memory.wrap(self.pointer + over);
+        self.reach(self.pointer);
+    }
+
+    // Returns the index of the cell at `offset` from the pointer.
+    fn index(&mut self, offset: i32) -> i32 {
+        let index = self.memory.wrap(self.pointer + offset);
+        self.reach(index);
+        index
+    }
+
+    fn reach(&mut self, index: i32) {
+        self.min_pointer = self.min_pointer.min(index);
+        self.max_pointer = self.max_pointer.max(index);
+    }
+
+    // Checks the condition of the loop the pointer is on, returning true if its body should run again.
+    // `id` is the loop's LoopId, used for profiling.
+    fn check_loop(&mut self, id: usize) -> Result<bool, RunTimeError> {
+        self.tick()?;
+
+        let cell = self.memory.get(self.pointer);
+        if cell.is_some() {
+            self.note_read(self.pointer);
+        }
+
+        match cell {
+            Some(cell) if cell.is_zero() => Ok(false),
+            Some(_) => {
+                self.count("Loop");
+                if self.instructions.is_some() {
+                    if id >= self.loops.len() {
+                        self.loops.resize(id + 1, 0);
+                    }
+                    self.loops[id] += 1;
+                }
+                Ok(true)
+            }
+            None => Err(RunTimeError::OutOfBounds {
+                index: self.pointer,
+            }),
+        }
+    }
+
+    // Prints `bytes` `times` times, cutting the output off at the output limit. Every repeat costs an iteration like
+    // the `.` it was merged from, so a Print that runs out of iterations stops after the repeats that fit. Nothing
+    // past either limit reaches the output, however often the Print repeats.
+    fn print(
+        &mut self,
+        output: &mut dyn Output,
+        bytes: &[u8],
+        times: usize,
+    ) -> Result<(), RunTimeError> {
+        // The first repeat was counted along with the instruction
+        let remaining = self.max_iterations.saturating_sub(self.iterations);
+        let repeats = times.min(remaining.saturating_add(1));
+        self.tick_by(repeats.saturating_sub(1))?;
+
+        let total = bytes.len().saturating_mul(repeats);
+        let allowed = match self.max_output {
+            Some(max_output) => total.min(max_output.saturating_sub(self.printed)),
+            None => total,
+        };
+
+        output.print(bytes, allowed / bytes.len())?;
+        if allowed < total {
+            // A wide cell can be cut off partway through
+            let partial = allowed % bytes.len();
+            if partial > 0 {
+                output.print(&bytes[..partial], 1)?;
+            }
+            self.printed += allowed;
+            return Err(RunTimeError::OutputLimitExceeded);
+        }
+
+        self.printed += total;
+        if repeats < times {
             self.iterations += 1;
-            if self.iterations > self.max_iterations {
-                return (Some(RunTimeError::MaxIterationsExceeded), output);
+            return Err(RunTimeError::MaxIterationsExceeded);
+        }
+        Ok(())
+    }
+
+    // Executes a single instruction that isn't a Loop, writing anything it prints to output.
+    fn execute<I>(
+        &mut self,
+        instruction: &IR,
+        inputs: &mut I,
+        output: &mut dyn Output,
+    ) -> Option<RunTimeError>
+    where
+        I: Iterator<Item = Wrapping<u8>>,
+    {
+        self.count(instruction.name());
+
+        // The first iteration was counted before the instruction, a Print counts the rest as it goes
+        if !matches!(instruction, IR::Print { .. }) {
+            if let Err(err) = self.tick_by(instruction.cost() - 1) {
+                return Some(err);
             }
+        }
 
-            match instruction {
-                IR::Add { x, offset } => {
-                    let cell = self.memory.get_mut((self.pointer + offset) as usize);
-
-                    if let Some(cell) = cell {
-                        match x.cmp(&0) {
-                            Ordering::Less => *cell -= Wrapping(-x as u8),
-                            Ordering::Equal => {}
-                            Ordering::Greater => *cell += Wrapping(x as u8),
-                        }
-                    } else {
-                        return (Some(RunTimeError::OutOfBounds), output);
+        match *instruction {
+            IR::Add { x, offset } => {
+                let index = self.index(offset);
+                let cell = self.memory.get_mut(index);
+
+                if let Some(cell) = cell {
+                    let exact = i128::from(cell.to_u32()) + i128::from(x);
+                    match overflowed(self.overflow, index, *cell + C::from_i32(x), exact) {
+                        Ok(value) => *cell = value,
+                        Err(err) => return Some(err),
                     }
+                } else {
+                    return Some(RunTimeError::OutOfBounds { index });
                 }
-                IR::Move { over } => {
-                    self.pointer += over;
+                self.note_write(index);
+            }
+            IR::Move { over } => {
+                self.move_pointer(over);
+
+                let (lowest, highest) = self.memory.bounds();
+                if self.strict_moves && !(lowest..=highest).contains(&self.pointer) {
+                    return Some(RunTimeError::OutOfBounds {
+                        index: self.pointer,
+                    });
                 }
-                IR::Print { times, offset } => {
-                    let cell = self.memory.get((self.pointer + offset) as usize);
+            }
+            IR::Print { times, offset } => {
+                let index = self.index(offset);
+                let cell = self.memory.get(index);
 
-                    if let Some(cell) = cell {
-                        output.extend(std::iter::repeat(cell).take(times));
-                    } else {
-                        return (Some(RunTimeError::OutOfBounds), output);
+                if let Some(cell) = cell {
+                    let (bytes, width) = self.encoding.encode(cell);
+                    if let Err(err) = self.print(output, &bytes[..width], times) {
+                        return Some(err);
                     }
+                } else {
+                    return Some(RunTimeError::OutOfBounds { index });
                 }
-                IR::Read { offset } => {
-                    let cell = self.memory.get_mut((self.pointer + offset) as usize);
+                self.note_read(index);
+            }
+            IR::Read { offset } => {
+                let index = self.index(offset);
+                let cell = self.memory.get_mut(index);
 
-                    if let Some(cell) = cell {
-                        if let Some(input) = inputs.next() {
-                            *cell = input;
-                        } else {
-                            return (Some(RunTimeError::OutOfInputs), output);
-                        }
-                    } else {
-                        return (Some(RunTimeError::OutOfBounds), output);
+                if let Some(cell) = cell {
+                    match self.encoding.decode(inputs) {
+                        Ok(Some(value)) => *cell = value,
+                        Ok(None) => match self.eof {
+                            EofBehavior::Error => return Some(RunTimeError::OutOfInputs),
+                            EofBehavior::Unchanged => {}
+                            EofBehavior::Zero => *cell = C::default(),
+                            EofBehavior::NegativeOne => *cell = C::from_i32(-1),
+                            EofBehavior::Value(byte) => *cell = C::from_byte(Wrapping(byte)),
+                        },
+                        Err(err) => return Some(err),
                     }
+                } else {
+                    return Some(RunTimeError::OutOfBounds { index });
                 }
-                IR::Exact { x, offset } => {
-                    let cell = self.memory.get_mut((self.pointer + offset) as usize);
+                self.note_write(index);
+            }
+            IR::Exact { x, offset } => {
+                let index = self.index(offset);
+                let cell = self.memory.get_mut(index);
 
-                    if let Some(cell) = cell {
-                        *cell = Wrapping(x as u8)
-                    } else {
-                        return (Some(RunTimeError::OutOfBounds), output);
+                if let Some(cell) = cell {
+                    match overflowed(self.overflow, index, C::from_i32(x), x.into()) {
+                        Ok(value) => *cell = value,
+                        Err(err) => return Some(err),
                     }
+                } else {
+                    return Some(RunTimeError::OutOfBounds { index });
+                }
+                self.note_write(index);
+            }
+            IR::Clear { offset } => {
+                let index = self.index(offset);
+                match self.memory.get_mut(index) {
+                    Some(cell) => *cell = C::default(),
+                    None => return Some(RunTimeError::OutOfBounds { index }),
                 }
-                IR::Loop { over, instructions } => {
-                    // preform a move
-                    self.pointer += over;
+                self.note_write(index);
+            }
+            IR::Debug { offset } => {
+                let index = self.index(offset);
+                if let Some((radius, _)) = self.debug_hook {
+                    let window = self.memory_window(index, radius);
+                    if let Some((_, hook)) = &mut self.debug_hook {
+                        hook(index, &window);
+                    }
+                }
+            }
+            IR::Assert { offset, expected } => {
+                let index = self.index(offset);
+                let actual = match self.memory.get(index) {
+                    Some(cell) => cell.to_u32(),
+                    None => return Some(RunTimeError::OutOfBounds { index }),
+                };
+                self.note_read(index);
 
-                    // then begin the loop
-                    loop {
-                        self.iterations += 1;
-                        if self.iterations > self.max_iterations {
-                            return (Some(RunTimeError::MaxIterationsExceeded), output);
-                        }
+                if actual != expected {
+                    return Some(RunTimeError::AssertionFailed {
+                        offset: index,
+                        expected,
+                        actual,
+                    });
+                }
+            }
+            IR::Loop { .. } => unreachable!("loops are driven by the caller"),
+            IR::ScanZero { step } => loop {
+                if let Err(err) = self.tick() {
+                    return Some(err);
+                }
 
-                        let cell = self.memory.get(self.pointer as usize);
-                        if let Some(cell) = cell {
-                            if *cell == Wrapping(0) {
-                                break;
-                            }
-                        } else {
-                            return (Some(RunTimeError::OutOfBounds), output);
-                        }
+                let cell = self.memory.get(self.pointer);
+                if cell.is_some() {
+                    self.note_read(self.pointer);
+                }
 
-                        let (err, outputs) = self.run_vec(instructions.clone(), inputs);
-                        output.extend(outputs);
+                match cell {
+                    Some(cell) if cell.is_zero() => break,
+                    Some(_) => self.move_pointer(step),
+                    None => {
+                        return Some(RunTimeError::OutOfBounds {
+                            index: self.pointer,
+                        })
+                    }
+                }
+            },
+            IR::Mul { x, y, offset } => {
+                let index = self.index(offset);
+                let source = match self.memory.get(index) {
+                    Some(cell) => cell,
+                    None => return Some(RunTimeError::OutOfBounds { index }),
+                };
+                self.note_read(index);
 
-                        if err.is_some() {
-                            return (err, output);
-                        }
+                let index = self.index(offset + x);
+                let cell = self.memory.get_mut(index);
+                if let Some(cell) = cell {
+                    let wrapped = *cell + source * C::from_i32(y);
+                    let exact =
+                        i128::from(cell.to_u32()) + i128::from(source.to_u32()) * i128::from(y);
+                    match overflowed(self.overflow, index, wrapped, exact) {
+                        Ok(value) => *cell = value,
+                        Err(err) => return Some(err),
                     }
+                } else {
+                    return Some(RunTimeError::OutOfBounds { index });
                 }
-                IR::Mul { x, y, offset } => {
-                    let add = {
-                        let cell = self.memory.get_mut((self.pointer + offset) as usize);
-                        if let Some(cell) = cell {
-                            cell.0 as i32 * y
-                        } else {
-                            return (Some(RunTimeError::OutOfBounds), output);
-                        }
-                    };
+                self.note_write(index);
+            }
+        };
+
+        None
+    }
+
+    pub fn run(&mut self, inputs: &[Wrapping<u8>]) -> RunOutcome {
+        self.run_iter(inputs.iter().copied())
+    }
+
+    pub fn run_iter(&mut self, mut inputs: impl Iterator<Item = Wrapping<u8>>) -> RunOutcome {
+        let mut output = Vec::new();
+        let err = self.run_program(&mut inputs, &mut output);
+        self.outcome(err, output)
+    }
+
+    fn outcome(&self, error: Option<RunTimeError>, output: Vec<u8>) -> RunOutcome {
+        RunOutcome {
+            output: output.into_iter().map(Wrapping).collect(),
+            error,
+            iterations: self.iterations,
+            final_pointer: self.pointer,
+            min_pointer: self.min_pointer,
+            max_pointer: self.max_pointer,
+        }
+    }
 
-                    let cell = self.memory.get_mut((self.pointer + offset + x) as usize);
-                    if let Some(cell) = cell {
-                        *cell += Wrapping(add as u8);
-                    } else {
-                        return (Some(RunTimeError::OutOfBounds), output);
+    // Runs the program, writing its output straight to `output` rather than holding it in memory.
+    #[cfg(feature = "std")]
+    pub fn run_with_writer(
+        &mut self,
+        inputs: &[Wrapping<u8>],
+        mut output: &mut dyn Write,
+    ) -> Option<RunTimeError> {
+        self.run_program(&mut inputs.iter().copied(), &mut output)
+    }
+
+    // Same as run_with_writer but the input is pulled from `inputs` only when the program reads it, so it can come
+    // from a source that is still being filled, like an interactive stdin.
+    #[cfg(feature = "std")]
+    pub fn run_iter_with_writer(
+        &mut self,
+        mut inputs: impl Iterator<Item = Wrapping<u8>>,
+        mut output: &mut dyn Write,
+    ) -> Option<RunTimeError> {
+        self.run_program(&mut inputs, &mut output)
+    }
+
+    // Runs the program, calling `on_output` with the bytes of each Print as soon as it runs. A Print that repeats
+    // passes all of its bytes in a single call.
+    pub fn run_with_callback(
+        &mut self,
+        inputs: &[Wrapping<u8>],
+        on_output: impl FnMut(&[Wrapping<u8>]),
+    ) -> Option<RunTimeError> {
+        self.run_program(&mut inputs.iter().copied(), &mut Callback(on_output))
+    }
+
+    // Runs the program, returning the cells it changed along with the outcome, see changed_cells.
+    pub fn run_with_trace(&mut self, inputs: &[Wrapping<u8>]) -> (RunOutcome, Vec<(i32, C)>) {
+        let outcome = self.run(inputs);
+        (outcome, self.changed_cells())
+    }
+
+    // Runs the program, returning the events the trace mode recorded along with the outcome. The trace is empty
+    // unless a trace mode was set. An instruction that fails isn't recorded, the outcome holds its error.
+    pub fn run_traced(&mut self, inputs: &[Wrapping<u8>]) -> (RunOutcome, Vec<TraceEvent<C>>) {
+        let outcome = self.run(inputs);
+        (outcome, self.take_trace())
+    }
+
+    // Runs the program with cell stats enabled, returning how often it read and wrote every cell along with the
+    // outcome. The counts include earlier runs since the last reset if cell stats were already enabled.
+    pub fn run_instrumented(
+        &mut self,
+        inputs: &[Wrapping<u8>],
+    ) -> (RunOutcome, HashMap<i32, CellStats>) {
+        if self.cell_stats.is_none() {
+            self.set_cell_stats(true);
+        }
+        let outcome = self.run(inputs);
+        (outcome, self.cell_stats.clone().unwrap_or_default())
+    }
+
+    fn run_program<I>(&mut self, inputs: &mut I, output: &mut dyn Output) -> Option<RunTimeError>
+    where
+        I: Iterator<Item = Wrapping<u8>>,
+    {
+        // The program is moved out for the run so it can be borrowed while the rest of the interpreter changes
+        let program = core::mem::take(&mut self.program);
+        let err = if self.trace_mode == TraceMode::Off {
+            self.run_vec_output(&program, inputs, output)
+        } else {
+            // Trace events point into the flattened program
+            self.start_clock();
+            let err = self.run_flat_ops(&flatten(&program), inputs, output);
+            self.stop_clock();
+            err
+        };
+        self.program = program;
+        err
+    }
+
+    // Runs a flattened program with a single loop over an instruction pointer instead of recursing into loops.
+    // Behaves exactly like run_vec on the IR the program was flattened from, including the iteration count.
+    pub fn run_flat<I>(&mut self, ops: &[FlatOp], inputs: &mut I) -> RunOutcome
+    where
+        I: Iterator<Item = Wrapping<u8>>,
+    {
+        let mut output = Vec::new();
+        self.start_clock();
+        let err = self.run_flat_ops(ops, inputs, &mut output);
+        self.stop_clock();
+        self.outcome(err, output)
+    }
+
+    fn run_flat_ops<I>(
+        &mut self,
+        ops: &[FlatOp],
+        inputs: &mut I,
+        output: &mut dyn Output,
+    ) -> Option<RunTimeError>
+    where
+        I: Iterator<Item = Wrapping<u8>>,
+    {
+        let mut ip = 0;
+        // The LoopId of the loop every JumpIfZero starts, they come in pre-order
+        let mut ids = vec![0; ops.len()];
+        let mut next_id = 0;
+        for (index, op) in ops.iter().enumerate() {
+            if let FlatOp::JumpIfZero { .. } = op {
+                ids[index] = next_id;
+                next_id += 1;
+            }
+        }
+
+        while let Some(op) = ops.get(ip) {
+            let index = ip;
+            let traced = self.trace_start(op);
+
+            // The end of a loop is only a condition check, it isn't counted as an instruction of its own
+            if let FlatOp::JumpIfNonZero { target } = op {
+                match self.check_loop(ids[target - 1]) {
+                    Ok(true) => ip = *target,
+                    Ok(false) => ip += 1,
+                    Err(err) => return Some(err),
+                }
+            } else {
+                if let Err(err) = self.tick() {
+                    return Some(err);
+                }
+
+                match op {
+                    FlatOp::JumpIfZero { over, target } => {
+                        self.move_pointer(*over);
+                        match self.check_loop(ids[ip]) {
+                            Ok(true) => ip += 1,
+                            Ok(false) => ip = *target,
+                            Err(err) => return Some(err),
+                        }
                     }
+                    FlatOp::Op(instruction) => {
+                        if let Some(err) = self.execute(instruction, inputs, output) {
+                            return Some(err);
+                        }
+                        ip += 1;
+                    }
+                    FlatOp::JumpIfNonZero { .. } => unreachable!(),
+                }
+            }
+
+            if let Some(start) = traced {
+                self.trace_finish(index, op, start);
+            }
+        }
+
+        None
+    }
+
+    // None when nothing is traced.
+    fn trace_start(&self, op: &FlatOp) -> Option<TraceStart<C>> {
+        if matches!(self.trace_mode, TraceMode::Off | TraceMode::Last(0)) {
+            return None;
+        }
+
+        let written = match op {
+            FlatOp::Op(
+                IR::Add { offset, .. }
+                | IR::Exact { offset, .. }
+                | IR::Clear { offset }
+                | IR::Read { offset },
+            ) => Some(*offset),
+            FlatOp::Op(IR::Mul { x, offset, .. }) => Some(offset + x),
+            _ => None,
+        };
+        let written = written.map(|offset| {
+            let index = self.memory.wrap(self.pointer + offset);
+            (index, self.memory.get(index))
+        });
+
+        Some(TraceStart {
+            pointer: self.pointer,
+            written,
+        })
+    }
+
+    fn trace_finish(&mut self, index: usize, op: &FlatOp, start: TraceStart<C>) {
+        let changes = start
+            .written
+            .and_then(|(cell, old)| Some((cell, old?, self.memory.get(cell)?)))
+            .filter(|(_, old, new)| old != new)
+            .into_iter()
+            .collect();
+
+        if let TraceMode::Last(limit) = self.trace_mode {
+            if self.trace.len() >= limit {
+                self.trace.pop_front();
+            }
+        }
+        self.trace.push_back(TraceEvent {
+            index,
+            op: op.clone(),
+            pointer_before: start.pointer,
+            pointer_after: self.pointer,
+            changes,
+        });
+    }
+
+    // Queues input for step to read from.
+    pub fn push_input(&mut self, input: &[Wrapping<u8>]) {
+        self.input.extend(input);
+    }
+
+    // Returns the body of the loop the step stack is currently inside of, or the whole program at the top level.
+    fn current_body(&self) -> &[IR] {
+        let mut body = &self.program[..];
+        for &index in &self.stack[..self.stack.len() - 1] {
+            match &body[index] {
+                IR::Loop { instructions, .. } => body = instructions,
+                _ => unreachable!("the step stack only descends into loops"),
+            }
+        }
+        body
+    }
+
+    // Returns the instruction the next call to step will execute, None once the end of a loop body or the
+    // program has been reached.
+    pub fn current_instruction(&self) -> Option<&IR> {
+        self.current_body().get(*self.stack.last().unwrap())
+    }
+
+    // Executes a single instruction. Entering a loop and each check of its condition at the end of the body are
+    // steps of their own, so a loop is flattened into one step per instruction it runs. Counts iterations the same
+    // way run does, so stepping through a program gives the same state as running it.
+    pub fn step(&mut self) -> StepResult {
+        let Some(instruction) = self.current_instruction().cloned() else {
+            if self.stack.len() == 1 {
+                return StepResult::Done;
+            }
+
+            // End of a loop body, check whether to run it again
+            let id = self.loop_id(self.stack.len() - 1);
+            return match self.check_loop(id) {
+                Ok(true) => {
+                    *self.stack.last_mut().unwrap() = 0;
+                    StepResult::Continue
+                }
+                Ok(false) => {
+                    self.stack.pop();
+                    *self.stack.last_mut().unwrap() += 1;
+                    StepResult::Continue
                 }
+                Err(err) => StepResult::Error(err),
             };
+        };
+
+        if let Err(err) = self.tick() {
+            return StepResult::Error(err);
+        }
+
+        if let IR::Loop { over, .. } = instruction {
+            self.move_pointer(over);
+
+            let id = self.loop_id(self.stack.len());
+            return match self.check_loop(id) {
+                Ok(true) => {
+                    self.stack.push(0);
+                    StepResult::Continue
+                }
+                Ok(false) => {
+                    *self.stack.last_mut().unwrap() += 1;
+                    StepResult::Continue
+                }
+                Err(err) => StepResult::Error(err),
+            };
+        }
+
+        let mut input = core::mem::take(&mut self.input);
+        let mut output = Vec::new();
+        let err = self.execute(
+            &instruction,
+            &mut core::iter::from_fn(|| input.pop_front()),
+            &mut output,
+        );
+        self.input = input;
+
+        if let Some(err) = err {
+            return StepResult::Error(err);
+        }
+
+        *self.stack.last_mut().unwrap() += 1;
+        if output.is_empty() {
+            StepResult::Continue
+        } else {
+            StepResult::Output(output.into_iter().map(Wrapping).collect())
         }
-        (None, output)
     }
 
-    pub fn run(&mut self, inputs: &[Wrapping<u8>]) -> (Option<RunTimeError>, Vec<Wrapping<u8>>) {
-        self.run_vec(self.program.clone(), &mut inputs.iter().copied())
+    // Returns the LoopId of the loop at stack[depth - 1], the loop whose body stack[depth] walks through. Only needed
+    // when profiling, so it is 0 otherwise.
+    fn loop_id(&self, depth: usize) -> usize {
+        if self.instructions.is_none() {
+            return 0;
+        }
+
+        let mut body = &self.program[..];
+        let mut id = 0;
+        for &position in &self.stack[..depth] {
+            id += loop_sizes(&body[..position]).len();
+
+            match &body[position] {
+                IR::Loop { instructions, .. } => body = instructions,
+                _ => unreachable!("the step stack only descends into loops"),
+            }
+            id += 1;
+        }
+
+        id - 1
+    }
+
+    // Returns the flattened index of the instruction the next step executes. Instructions are numbered in program
+    // order with a loop coming before the instructions in its body, so the index is the same as the position of the
+    // instruction when the program is written out in full.
+    pub fn instruction_index(&self) -> Option<usize> {
+        fn size(instruction: &IR) -> usize {
+            match instruction {
+                IR::Loop { instructions, .. } => 1 + instructions.iter().map(size).sum::<usize>(),
+                _ => 1,
+            }
+        }
+
+        let mut body = &self.program[..];
+        let mut index = 0;
+        for (depth, &position) in self.stack.iter().enumerate() {
+            index += body[..position].iter().map(size).sum::<usize>();
+
+            if depth == self.stack.len() - 1 {
+                return (position < body.len()).then_some(index);
+            }
+
+            match &body[position] {
+                IR::Loop { instructions, .. } => body = instructions,
+                _ => unreachable!("the step stack only descends into loops"),
+            }
+            index += 1;
+        }
+
+        unreachable!("the step stack is never empty")
     }
 
-    pub fn run_iter(
+    // Steps until a breakpoint trips, an error occurs or the program finishes, returning the breakpoint (None if the
+    // program finished) along with everything printed on the way. At least one step is always taken, so calling this
+    // again resumes past the breakpoint that tripped.
+    pub fn run_until_breakpoint(
         &mut self,
-        mut inputs: impl Iterator<Item = Wrapping<u8>>,
-    ) -> (Option<RunTimeError>, Vec<Wrapping<u8>>) {
-        self.run_vec(self.program.clone(), &mut inputs)
+        breakpoints: &Breakpoints,
+    ) -> (Result<Option<Breakpoint>, RunTimeError>, Vec<Wrapping<u8>>) {
+        let mut output = Vec::new();
+
+        loop {
+            let pointer = self.pointer;
+            let watched: Vec<(i32, Option<C>)> = breakpoints
+                .on_cell_change
+                .iter()
+                .map(|&cell| (cell, self.memory.get(cell)))
+                .collect();
+
+            match self.step() {
+                StepResult::Done => return (Ok(None), output),
+                StepResult::Error(err) => return (Err(err), output),
+                StepResult::Output(bytes) => output.extend(bytes),
+                StepResult::Continue => {}
+            }
+
+            if let Some(&(cell, _)) = watched
+                .iter()
+                .find(|(cell, value)| self.memory.get(*cell) != *value)
+            {
+                return (Ok(Some(Breakpoint::CellChange(cell))), output);
+            }
+
+            if self.pointer != pointer && breakpoints.on_pointer.contains(&self.pointer) {
+                return (Ok(Some(Breakpoint::Pointer(self.pointer))), output);
+            }
+
+            if let Some(index) = self.instruction_index() {
+                if breakpoints.on_instruction.contains(&index) {
+                    return (Ok(Some(Breakpoint::Instruction(index))), output);
+                }
+            }
+        }
     }
 }
@@ -1,135 +1,685 @@
-use std::num::Wrapping;
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, string::String, vec, vec::Vec};
+use core::{
+    fmt::{self, Display},
+    num::Wrapping,
+};
+#[cfg(feature = "std")]
+use std::{
+    io::{ErrorKind, Read},
+    time::Duration,
+};
 
 use either::Either;
-use interpreter::{Interpreter, RunTimeError};
 
+mod analysis;
+mod bench;
+mod codegen;
+mod flat;
 mod interpreter;
+mod minimize;
 mod parser;
+mod program;
+mod suite;
+mod tape;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+pub use analysis::{
+    count_min_reads, find_infinite_loops, is_pure, loop_positions, loops, static_offset_bounds,
+    LoopId, LoopInfo,
+};
+#[cfg(feature = "std")]
+pub use bench::benchmark;
+pub use bench::{optimization_report, LevelSize, LevelTiming, LevelTimings, OptimizationReport};
+pub use codegen::{format_ir, to_c};
+pub use flat::{flatten, FlatOp};
+pub use interpreter::{
+    Breakpoint, Breakpoints, Cell, CellEncoding, CellStats, DebugHook, EofBehavior, Interpreter,
+    InterpreterBuilder, Overflow, Profile, RunOutcome, RunTimeError, StepResult, TraceEvent,
+    TraceMode,
+};
+pub use minimize::minimize;
+#[cfg(feature = "std")]
+pub use parser::optimize_with_log;
+pub use parser::{
+    fold_adjacent, fold_adjacent_strict, fold_constant_muls, lower_loops, merge_moves_into_offset,
+    merge_updates, optimize_all, optimize_bytes, optimize_o4_with_limit, optimize_staged,
+    optimize_with, parse, parse_bytes, parse_with_assertions, parse_with_debug_markers,
+    parse_with_max_depth, remove_redundant_writes, remove_zero_moves_and_adds, run_passes,
+    to_brainfuck, unroll_loops, unroll_loops_up_to, validate_ir, BracketKind, InvalidIr,
+    OptimizationStages, OptimizerError, OptimizerEvent, OptimizerLog, Pass, IR, MAX_NESTING_DEPTH,
+    UNROLL_LIMIT,
+};
+pub use program::Program;
+pub use suite::{SuiteReport, TestSuite};
+pub use tape::TapeMode;
+
+// The maps and sets used throughout the crate. There is no HashMap without std, the ordered collections of alloc take
+// its place.
+#[cfg(not(feature = "std"))]
+pub(crate) use alloc::collections::{BTreeMap as HashMap, BTreeSet as HashSet};
+#[cfg(feature = "std")]
+pub(crate) use std::collections::{HashMap, HashSet};
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct TestFailure {
     typ: TestFailureType,
+    // Position of the failing case in the inputs and outputs
+    case_index: usize,
     input: Vec<Wrapping<u8>>,
     expected_output: Vec<Wrapping<u8>>,
+    // Iterations the case used before it finished or failed
+    iterations: usize,
+}
+
+impl TestFailure {
+    pub fn typ(&self) -> &TestFailureType {
+        &self.typ
+    }
+
+    pub fn case_index(&self) -> usize {
+        self.case_index
+    }
+
+    // The input of the failing case as plain bytes.
+    pub fn input(&self) -> Vec<u8> {
+        unwrap_bytes(&self.input)
+    }
+
+    // The output the failing case expected, as plain bytes.
+    pub fn expected_output(&self) -> Vec<u8> {
+        unwrap_bytes(&self.expected_output)
+    }
+
+    pub fn iterations(&self) -> usize {
+        self.iterations
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum TestFailureType {
     RunTimeError { err: interpreter::RunTimeError },
     NonZeroPointer { pointer: i32 },
-    NonZeroMemory { memory: Vec<Wrapping<u8>> },
+    // The memory didn't end the way it started: all zeros, or the initial memory given to test_with_initial_memory
+    NonZeroMemory { memory: Vec<u32> },
     IncorrectOutput { output: Vec<Wrapping<u8>> },
     OptimizerError(parser::OptimizerError),
 }
 
+impl Display for TestFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "case {}: ", self.case_index)?;
+        match &self.typ {
+            TestFailureType::IncorrectOutput { output } => write!(
+                f,
+                "incorrect output: expected {:?} got {:?}",
+                self.expected_output(),
+                unwrap_bytes(output)
+            ),
+            typ => write!(f, "{typ}"),
+        }
+    }
+}
+
+impl Display for TestFailureType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TestFailureType::RunTimeError { err } => write!(f, "run time error: {err}"),
+            TestFailureType::NonZeroPointer { pointer } => {
+                write!(f, "the pointer ended at {pointer} instead of 0")
+            }
+            TestFailureType::NonZeroMemory { memory } => {
+                write!(f, "the memory ended as {memory:?} instead of as it started")
+            }
+            TestFailureType::IncorrectOutput { output } => {
+                write!(f, "incorrect output: got {:?}", unwrap_bytes(output))
+            }
+            TestFailureType::OptimizerError(err) => write!(f, "optimizer error: {err}"),
+        }
+    }
+}
+
+// Running a program either fails at run time or while it is being optimized.
+pub type RunResult<T> = Result<T, Either<RunTimeError, parser::OptimizerError>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum OptimizationLevel {
     O0,
     O1,
     O2,
     O3,
+    O4,
+}
+
+impl OptimizationLevel {
+    // Every level, from O0 up.
+    pub const ALL: [OptimizationLevel; 5] = [
+        OptimizationLevel::O0,
+        OptimizationLevel::O1,
+        OptimizationLevel::O2,
+        OptimizationLevel::O3,
+        OptimizationLevel::O4,
+    ];
+
+    // The passes the level runs, in order. Use these as a starting point for a custom pipeline with optimize_with.
+    pub fn passes(self) -> &'static [Pass] {
+        match self {
+            OptimizationLevel::O0 => parser::O0_PASSES,
+            OptimizationLevel::O1 => parser::O1_PASSES,
+            OptimizationLevel::O2 => parser::O2_PASSES,
+            OptimizationLevel::O3 => parser::O3_PASSES,
+            OptimizationLevel::O4 => parser::O4_PASSES,
+        }
+    }
+}
+
+// Parses and optimizes a program into the IR that the interpreter executes.
+pub fn optimize(
+    bf: &str,
+    optimization_level: OptimizationLevel,
+) -> Result<Vec<IR>, parser::OptimizerError> {
+    match optimization_level {
+        OptimizationLevel::O0 => parser::optimize_o0(bf),
+        OptimizationLevel::O1 => parser::optimize_o1(bf),
+        OptimizationLevel::O2 => parser::optimize_o2(bf),
+        OptimizationLevel::O3 => parser::optimize_o3(bf),
+        OptimizationLevel::O4 => parser::optimize_o4(bf),
+    }
+}
+
+// The width of a single memory cell. Input and output stay byte oriented regardless of the width.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CellWidth {
+    U8,
+    U16,
+    U32,
+}
+
+// Settings shared by test and the run functions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Config {
+    pub optimization_level: OptimizationLevel,
+    pub max_iterations: usize,
+    // How many bytes a single run may print, None means there is no limit
+    pub max_output: Option<usize>,
+    // Wall-clock budget for a single run, None means there is no limit
+    #[cfg(feature = "std")]
+    pub timeout: Option<Duration>,
+    pub cell_width: CellWidth,
+    // How wide cells are read from the input and printed, 8-bit cells ignore it
+    pub encoding: CellEncoding,
+    pub eof: EofBehavior,
+    // Anything but Overflow::Wrap limits the optimizer to O0, as merged and folded instructions would skip the
+    // intermediate values that overflow
+    pub overflow: Overflow,
+    // Whether moving the pointer off the tape is an error by itself, rather than only once a cell there is touched.
+    // Opposite moves can't be cancelled out then, so every level above O0 only optimizes as much as O1 does.
+    pub strict_moves: bool,
+    // Whether test resets the interpreter after each case. Without a reset the tape, pointer and iteration count
+    // carry over into the next case, for testing programs that keep state between inputs. Ignored by the run
+    // functions.
+    pub reset_between_cases: bool,
+    // Programs with loops nested deeper than this fail with OptimizerError::NestingTooDeep, see parse_with_max_depth
+    pub max_nesting_depth: usize,
+    // The most instructions O4 unrolls a loop to, UNROLL_LIMIT by default. The other levels ignore it.
+    pub unroll_limit: usize,
+    // The tape every run uses, 65536 cells by default. Use TapeMode::Fixed(30000) for the classic tape size. A
+    // circular tape limits the optimizer to O1, see TapeMode::Circular.
+    pub tape: TapeMode,
 }
 
-pub fn test<I, O>(
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            optimization_level: OptimizationLevel::O3,
+            max_iterations: usize::MAX,
+            max_output: None,
+            #[cfg(feature = "std")]
+            timeout: None,
+            cell_width: CellWidth::U8,
+            encoding: CellEncoding::default(),
+            eof: EofBehavior::default(),
+            overflow: Overflow::default(),
+            strict_moves: false,
+            reset_between_cases: true,
+            max_nesting_depth: parser::MAX_NESTING_DEPTH,
+            unroll_limit: parser::UNROLL_LIMIT,
+            tape: TapeMode::default(),
+        }
+    }
+}
+
+impl Config {
+    fn builder<C: Cell>(&self) -> InterpreterBuilder<C> {
+        let mut builder = Interpreter::builder()
+            .cell_width::<C>()
+            .max_iterations(self.max_iterations)
+            .eof(self.eof)
+            .encoding(self.encoding)
+            .overflow(self.overflow)
+            .tape(self.tape)
+            .strict_moves(self.strict_moves);
+
+        if let Some(max_output) = self.max_output {
+            builder = builder.max_output(max_output);
+        }
+        #[cfg(feature = "std")]
+        if let Some(timeout) = self.timeout {
+            return builder.timeout(timeout);
+        }
+        builder
+    }
+
+    // Optimizes the program at `optimization_level`, keeping the moves apart for strict moves.
+    fn optimize(&self, bf: &str) -> Result<Vec<IR>, parser::OptimizerError> {
+        self.optimize_on(bf, true)
+    }
+
+    // Same as optimize for a program that may start on a tape that isn't all zeros. The levels above O0 rely on the
+    // tape starting out zeroed, for example to drop a leading `[-]`, so such a tape only gets the O0 passes.
+    fn optimize_on(&self, bf: &str, zero_tape: bool) -> Result<Vec<IR>, parser::OptimizerError> {
+        // Strict moves and circular tapes run passes that stop at what O1 produces, and cells that don't wrap have to
+        // see every single Add
+        let (passes, level) = match self.optimization_level {
+            OptimizationLevel::O0 => (parser::O0_PASSES, OptimizationLevel::O0),
+            _ if self.overflow != Overflow::Wrap || !zero_tape => {
+                (parser::O0_PASSES, OptimizationLevel::O0)
+            }
+            _ if self.strict_moves => (parser::STRICT_PASSES, OptimizationLevel::O1),
+            _ if matches!(self.tape, TapeMode::Circular(_)) => {
+                (parser::O1_PASSES, OptimizationLevel::O1)
+            }
+            level => (level.passes(), level),
+        };
+        let instructions = parse_with_max_depth(bf, self.max_nesting_depth)?;
+        let instructions = match level {
+            OptimizationLevel::O4 => parser::run_o4_passes(instructions, self.unroll_limit),
+            _ => parser::run_passes(instructions, passes.iter().copied()),
+        };
+        parser::debug_validate(&instructions, level);
+        Ok(instructions)
+    }
+}
+
+pub fn test<I, O>(bf: &str, inputs: I, outputs: O, config: Config) -> Vec<TestFailure>
+where
+    I: IntoIterator<Item = Vec<Wrapping<u8>>>,
+    O: IntoIterator<Item = Vec<Wrapping<u8>>>,
+{
+    test_with_initial_memory(bf, &[], inputs, outputs, config)
+}
+
+// Same as `test` but every case starts with `initial` on the tape from cell 0 on, see
+// Interpreter::with_initial_memory. A case then has to leave the memory as it found it instead of all zeros, or it
+// fails with NonZeroMemory. The optimizer assumes the tape starts out zeroed, so a program with non-zero initial
+// memory only gets the O0 passes, whatever the optimization level.
+pub fn test_with_initial_memory<I, O>(
     bf: &str,
+    initial: &[Wrapping<u8>],
     inputs: I,
     outputs: O,
-    optimization_level: OptimizationLevel,
-    max_iterations: usize,
+    config: Config,
 ) -> Vec<TestFailure>
 where
     I: IntoIterator<Item = Vec<Wrapping<u8>>>,
     O: IntoIterator<Item = Vec<Wrapping<u8>>>,
 {
-    let optimizer = match optimization_level {
-        OptimizationLevel::O0 => parser::optimize_o0,
-        OptimizationLevel::O1 => parser::optimize_o1,
-        OptimizationLevel::O2 => parser::optimize_o2,
-        OptimizationLevel::O3 => parser::optimize_o3,
-    };
+    match config.cell_width {
+        CellWidth::U8 => test_cells::<Wrapping<u8>, _, _>(bf, initial, inputs, outputs, config),
+        CellWidth::U16 => test_cells::<Wrapping<u16>, _, _>(bf, initial, inputs, outputs, config),
+        CellWidth::U32 => test_cells::<Wrapping<u32>, _, _>(bf, initial, inputs, outputs, config),
+    }
+}
 
-    match optimizer(bf) {
+// Same as `test` but the inputs and outputs are plain bytes.
+pub fn test_bytes<I, O>(bf: &str, inputs: I, outputs: O, config: Config) -> Vec<TestFailure>
+where
+    I: IntoIterator<Item = Vec<u8>>,
+    O: IntoIterator<Item = Vec<u8>>,
+{
+    let wrap = |bytes: Vec<u8>| bytes.into_iter().map(Wrapping).collect();
+    test(
+        bf,
+        inputs.into_iter().map(wrap),
+        outputs.into_iter().map(wrap),
+        config,
+    )
+}
+
+fn test_cells<C, I, O>(
+    bf: &str,
+    initial: &[Wrapping<u8>],
+    inputs: I,
+    outputs: O,
+    config: Config,
+) -> Vec<TestFailure>
+where
+    C: Cell,
+    I: IntoIterator<Item = Vec<Wrapping<u8>>>,
+    O: IntoIterator<Item = Vec<Wrapping<u8>>>,
+{
+    let zero_tape = initial.iter().all(|cell| cell.0 == 0);
+    match config.optimize_on(bf, zero_tape) {
         Ok(instructions) => {
-            let mut interpreter =
-                crate::interpreter::Interpreter::from(instructions, max_iterations);
-
-            let mut errors = Vec::new();
-            let zipped = inputs.into_iter().zip(outputs);
-            for (input, expected_output) in zipped {
-                let (err, actual) = interpreter.run(&input);
-
-                let pointer = interpreter.get_pointer();
-                let memory = interpreter.return_shrinked_memory();
-
-                if let Some(err) = err {
-                    errors.push(TestFailure {
-                        typ: TestFailureType::RunTimeError { err },
-                        input: input.clone(),
-                        expected_output: expected_output.clone(),
-                    })
-                }
+            test_instructions::<C, _, _>(instructions, initial, inputs, outputs, config)
+        }
+        Err(e) => {
+            // The program never runs, so the failure is reported once against the first test case
+            let (input, expected_output) =
+                inputs.into_iter().zip(outputs).next().unwrap_or_default();
 
-                // Note: Each valid error is returned, they are not mutual exclusive.
-                // For example, if the program halts when max_iterations is exceeded we may return MaxIterationsExceeded and NonZeroPointer.
-                if pointer != 0 {
-                    errors.push(TestFailure {
-                        typ: TestFailureType::NonZeroPointer { pointer },
-                        input: input.clone(),
-                        expected_output: expected_output.clone(),
-                    });
-                }
+            vec![TestFailure {
+                typ: TestFailureType::OptimizerError(e),
+                case_index: 0,
+                input,
+                expected_output,
+                iterations: 0,
+            }]
+        }
+    }
+}
 
-                if memory.iter().any(|x| x != &Wrapping(0)) {
-                    errors.push(TestFailure {
-                        typ: TestFailureType::NonZeroMemory { memory },
-                        input: input.clone(),
-                        expected_output: expected_output.clone(),
-                    });
-                }
+// Tests a program that has already been optimized, see test_with_initial_memory.
+fn test_instructions<C, I, O>(
+    instructions: Vec<IR>,
+    initial: &[Wrapping<u8>],
+    inputs: I,
+    outputs: O,
+    config: Config,
+) -> Vec<TestFailure>
+where
+    C: Cell,
+    I: IntoIterator<Item = Vec<Wrapping<u8>>>,
+    O: IntoIterator<Item = Vec<Wrapping<u8>>>,
+{
+    let mut interpreter = config.builder::<C>().build(instructions);
+    interpreter.set_initial_memory(initial);
+    let mut errors = Vec::new();
+    let zipped = inputs.into_iter().zip(outputs);
+    for (case_index, (input, expected_output)) in zipped.enumerate() {
+        let RunOutcome {
+            output: actual,
+            error: err,
+            iterations,
+            final_pointer: pointer,
+            ..
+        } = interpreter.run(&input);
+        let memory = interpreter.return_shrinked_memory();
 
-                if actual != expected_output {
-                    errors.push(TestFailure {
-                        typ: TestFailureType::IncorrectOutput { output: actual },
-                        input,
-                        expected_output,
-                    });
-                }
+        if let Some(err) = err {
+            errors.push(TestFailure {
+                typ: TestFailureType::RunTimeError { err },
+                case_index,
+                input: input.clone(),
+                expected_output: expected_output.clone(),
+                iterations,
+            })
+        }
 
-                interpreter.reset();
-            }
+        // Note: Each valid error is returned, they are not mutual exclusive.
+        // For example, if the program halts when max_iterations is exceeded we may return MaxIterationsExceeded and NonZeroPointer.
+        if pointer != 0 {
+            errors.push(TestFailure {
+                typ: TestFailureType::NonZeroPointer { pointer },
+                case_index,
+                input: input.clone(),
+                expected_output: expected_output.clone(),
+                iterations,
+            });
+        }
+
+        if !interpreter.memory_is_initial() {
+            errors.push(TestFailure {
+                typ: TestFailureType::NonZeroMemory {
+                    memory: memory.into_iter().map(Cell::to_u32).collect(),
+                },
+                case_index,
+                input: input.clone(),
+                expected_output: expected_output.clone(),
+                iterations,
+            });
+        }
+
+        if actual != expected_output {
+            errors.push(TestFailure {
+                typ: TestFailureType::IncorrectOutput { output: actual },
+                case_index,
+                input,
+                expected_output,
+                iterations,
+            });
+        }
 
-            errors
+        if config.reset_between_cases {
+            interpreter.reset();
         }
-        Err(_) => todo!(),
     }
+
+    errors
 }
 
-pub fn run(
+// Runs a program and returns its output. A run time error replaces the output, use run_outcome to also get what the
+// program printed before it failed.
+pub fn run(bf: &str, input: &[Wrapping<u8>], config: Config) -> RunResult<Vec<Wrapping<u8>>> {
+    run_input(bf, input.iter().copied(), config)
+}
+
+// Same as `run` but returns everything the run left behind, including the output printed before a run time error.
+// For example a program that hits max_iterations still reports what it printed on the way.
+pub fn run_outcome(
     bf: &str,
     input: &[Wrapping<u8>],
-    optimization_level: OptimizationLevel,
-    max_iterations: usize,
-) -> Result<Vec<Wrapping<u8>>, Either<RunTimeError, parser::OptimizerError>> {
-    let optimizer = match optimization_level {
-        OptimizationLevel::O0 => parser::optimize_o0,
-        OptimizationLevel::O1 => parser::optimize_o1,
-        OptimizationLevel::O2 => parser::optimize_o2,
-        OptimizationLevel::O3 => parser::optimize_o3,
-    };
+    config: Config,
+) -> Result<RunOutcome, parser::OptimizerError> {
+    outcome_input(bf, input.iter().copied(), config)
+}
+
+// Same as `run` but the input and output are plain bytes.
+pub fn run_bytes(bf: &str, input: impl Into<Vec<u8>>, config: Config) -> RunResult<Vec<u8>> {
+    let input = input.into().into_iter().map(Wrapping);
+    run_input(bf, input, config).map(|output| unwrap_bytes(&output))
+}
+
+// Same as `run` but the input is read from `reader` one byte at a time, only when the program asks for it. This makes
+// it possible to run interactive programs on stdin. If reading fails the input ends there, and the run reports
+// RunTimeError::ReadFailed.
+#[cfg(feature = "std")]
+pub fn run_reader(bf: &str, mut reader: impl Read, config: Config) -> RunResult<Vec<Wrapping<u8>>> {
+    let mut error = None;
+    // Deliberately unbuffered so nothing past what the program asked for is consumed
+    let input = core::iter::from_fn(|| {
+        let mut byte = [0];
+        loop {
+            match reader.read(&mut byte) {
+                Ok(0) => return None,
+                Ok(_) => return Some(Wrapping(byte[0])),
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => {
+                    error = Some(err.kind());
+                    return None;
+                }
+            }
+        }
+    });
+
+    let result = run_input(bf, input, config);
+    match error {
+        Some(kind) => Err(Either::Left(RunTimeError::ReadFailed(kind))),
+        None => result,
+    }
+}
+
+fn run_input(
+    bf: &str,
+    input: impl Iterator<Item = Wrapping<u8>>,
+    config: Config,
+) -> RunResult<Vec<Wrapping<u8>>> {
+    match outcome_input(bf, input, config) {
+        Ok(RunOutcome {
+            error: Some(err), ..
+        }) => Err(Either::Left(err)),
+        Ok(outcome) => Ok(outcome.output),
+        Err(e) => Err(Either::Right(e)),
+    }
+}
+
+fn outcome_input(
+    bf: &str,
+    input: impl Iterator<Item = Wrapping<u8>>,
+    config: Config,
+) -> Result<RunOutcome, parser::OptimizerError> {
+    match config.cell_width {
+        CellWidth::U8 => run_cells::<Wrapping<u8>>(bf, input, config),
+        CellWidth::U16 => run_cells::<Wrapping<u16>>(bf, input, config),
+        CellWidth::U32 => run_cells::<Wrapping<u32>>(bf, input, config),
+    }
+}
+
+fn run_cells<C: Cell>(
+    bf: &str,
+    input: impl Iterator<Item = Wrapping<u8>>,
+    config: Config,
+) -> Result<RunOutcome, parser::OptimizerError> {
+    let instructions = config.optimize(bf)?;
+    Ok(config.builder::<C>().build(instructions).run_iter(input))
+}
 
-    match optimizer(bf) {
+// Same as `run` but also profiles the execution, reporting how many instructions of each kind were executed and how
+// many times the body of each loop ran.
+pub fn run_profiled(
+    bf: &str,
+    input: &[Wrapping<u8>],
+    config: Config,
+) -> RunResult<(Vec<Wrapping<u8>>, Profile)> {
+    match config.cell_width {
+        CellWidth::U8 => run_profiled_cells::<Wrapping<u8>>(bf, input, config),
+        CellWidth::U16 => run_profiled_cells::<Wrapping<u16>>(bf, input, config),
+        CellWidth::U32 => run_profiled_cells::<Wrapping<u32>>(bf, input, config),
+    }
+}
+
+fn run_profiled_cells<C: Cell>(
+    bf: &str,
+    input: &[Wrapping<u8>],
+    config: Config,
+) -> RunResult<(Vec<Wrapping<u8>>, Profile)> {
+    match config.optimize(bf) {
         Ok(instructions) => {
-            let mut interpreter = Interpreter::from(instructions, max_iterations);
-            let (err, output) = interpreter.run(input);
+            let mut interpreter = config.builder::<C>().profiling(true).build(instructions);
+            let outcome = interpreter.run(input);
 
-            if let Some(err) = err {
-                Err(Either::Left(err))
-            } else {
-                Ok(output)
+            match outcome.error {
+                Some(err) => Err(Either::Left(err)),
+                None => Ok((
+                    outcome.output,
+                    interpreter.get_profile().unwrap_or_default(),
+                )),
             }
         }
         Err(e) => Err(Either::Right(e)),
     }
 }
 
-#[cfg(test)]
+// Why run_verified couldn't return the output of a program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyError {
+    // Both levels failed in the same way, or the O0 run ran out of iterations or time and can't serve as a reference
+    Failed(Either<RunTimeError, parser::OptimizerError>),
+    // The optimized program printed something else or stopped with a different error than O0. Boxed to keep the
+    // error small.
+    Mismatch {
+        expected: Box<RunOutcome>,
+        actual: Box<RunOutcome>,
+    },
+}
+
+// Same as `run` but the program is also run at O0 and the two runs must agree on their output and error. This is the
+// optimizer's differential test done at run time, for validating the optimizer on your programs rather than for
+// production use: it is roughly twice as slow as `run`. max_iterations and timeout apply to each run on its own, and
+// O0 needs more iterations than the optimized levels.
+pub fn run_verified(
+    bf: &str,
+    input: &[Wrapping<u8>],
+    config: Config,
+) -> Result<Vec<Wrapping<u8>>, VerifyError> {
+    match config.cell_width {
+        CellWidth::U8 => run_verified_cells::<Wrapping<u8>>(bf, input, config),
+        CellWidth::U16 => run_verified_cells::<Wrapping<u16>>(bf, input, config),
+        CellWidth::U32 => run_verified_cells::<Wrapping<u32>>(bf, input, config),
+    }
+}
+
+fn run_verified_cells<C: Cell>(
+    bf: &str,
+    input: &[Wrapping<u8>],
+    config: Config,
+) -> Result<Vec<Wrapping<u8>>, VerifyError> {
+    let optimizer_error = |e| VerifyError::Failed(Either::Right(e));
+    // Only the level differs, so both sides parse with the same limit on nesting
+    let reference = Config {
+        optimization_level: OptimizationLevel::O0,
+        ..config
+    };
+    let reference = reference.optimize(bf).map_err(optimizer_error)?;
+    let optimized = config.optimize(bf).map_err(optimizer_error)?;
+
+    let expected = config.builder::<C>().build(reference).run(input);
+    let actual = config.builder::<C>().build(optimized).run(input);
+
+    match expected.error {
+        Some(err @ (RunTimeError::MaxIterationsExceeded | RunTimeError::Timeout)) => {
+            Err(VerifyError::Failed(Either::Left(err)))
+        }
+        _ if (expected.error, &expected.output) != (actual.error, &actual.output) => {
+            Err(VerifyError::Mismatch {
+                expected: Box::new(expected),
+                actual: Box::new(actual),
+            })
+        }
+        Some(err) => Err(VerifyError::Failed(Either::Left(err))),
+        None => Ok(actual.output),
+    }
+}
+
+// Same as `run` but the output is decoded as (lossy) UTF-8 text.
+pub fn run_to_string(bf: &str, input: &[Wrapping<u8>], config: Config) -> RunResult<String> {
+    run(bf, input, config).map(|output| bytes_to_string(&output))
+}
+
+// Same as `run` but the input is given as text, which is fed to the program as its UTF-8 bytes.
+pub fn run_str(bf: &str, input: &str, config: Config) -> RunResult<Vec<Wrapping<u8>>> {
+    let input: Vec<Wrapping<u8>> = input.bytes().map(Wrapping).collect();
+    run(bf, &input, config)
+}
+
+// Splits source on the first `!` into the program and the input that follows it, the convention some classic
+// runners use to bundle a program with its data. Source without a `!` is all program and has no input.
+pub fn split_inline_input(bf: &str) -> (&str, &str) {
+    bf.split_once('!').unwrap_or((bf, ""))
+}
+
+// Same as `run_str` but the input is taken from the source itself, see split_inline_input. Everything after the `!`
+// is fed to the program verbatim, including any brainfuck commands or further `!`s.
+pub fn run_with_inline_input(bf: &str, config: Config) -> RunResult<Vec<Wrapping<u8>>> {
+    let (bf, input) = split_inline_input(bf);
+    run_str(bf, input, config)
+}
+
+fn unwrap_bytes(bytes: &[Wrapping<u8>]) -> Vec<u8> {
+    bytes.iter().map(|byte| byte.0).collect()
+}
+
+// Decodes program output as UTF-8, replacing invalid sequences with U+FFFD.
+pub fn bytes_to_string(bytes: &[Wrapping<u8>]) -> String {
+    // Valid output is taken as is, only invalid output is copied to replace what doesn't decode
+    String::from_utf8(unwrap_bytes(bytes))
+        .unwrap_or_else(|err| String::from_utf8_lossy(err.as_bytes()).into_owned())
+}
+
+// The tests use std throughout
+#[cfg(all(test, feature = "std"))]
 mod test;
@@ -0,0 +1,119 @@
+// Shrinking a program that triggers a bug, for example one the differential tests found, into a small reproducer.
+
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+
+// Removes as much of `bf` as it can while `predicate` keeps holding, like a delta debugger: first everything that
+// isn't a command, then ever shorter runs of commands, then the brackets around single loop bodies, until nothing
+// more can go. The predicate is only called on programs with balanced brackets. Returns `bf` unchanged if the
+// predicate doesn't hold for it to begin with.
+//
+// The result is minimal in the sense that removing any one command or pair of brackets from it makes the predicate
+// fail, a shorter reproducer that isn't contained in it may still exist.
+pub fn minimize(bf: &str, predicate: impl Fn(&str) -> bool) -> String {
+    if !predicate(bf) {
+        return bf.to_string();
+    }
+
+    let holds = |program: &[char]| balanced(program) && predicate(&String::from_iter(program));
+
+    let mut program: Vec<char> = bf.chars().collect();
+    let commands: Vec<char> = bf.chars().filter(|c| "+-<>.,[]".contains(*c)).collect();
+    if commands.len() < program.len() && holds(&commands) {
+        program = commands;
+    }
+
+    loop {
+        let before = program.len();
+        remove_chunks(&mut program, &holds);
+        unwrap_loops(&mut program, &holds);
+        if program.len() == before {
+            return program.into_iter().collect();
+        }
+    }
+}
+
+// Tries removing every run of `size` commands for sizes from half the program down to a single command. Runs start
+// at every position rather than only at multiples of the size, so pairs like `><` are found at any offset.
+fn remove_chunks(program: &mut Vec<char>, holds: &impl Fn(&[char]) -> bool) {
+    let mut size = (program.len() / 2).max(1);
+    loop {
+        let mut start = 0;
+        while start < program.len() {
+            let end = (start + size).min(program.len());
+            let candidate: Vec<char> = program[..start]
+                .iter()
+                .chain(&program[end..])
+                .copied()
+                .collect();
+
+            // The next run to try moves into place after a successful removal
+            if holds(&candidate) {
+                *program = candidate;
+            } else {
+                start += 1;
+            }
+        }
+
+        if size == 1 {
+            return;
+        }
+        size /= 2;
+    }
+}
+
+// Tries removing the brackets of each loop while keeping its body, which no removal of a single run can do.
+fn unwrap_loops(program: &mut Vec<char>, holds: &impl Fn(&[char]) -> bool) {
+    let mut start = 0;
+    while start < program.len() {
+        if let Some(end) = matching_bracket(program, start) {
+            let mut candidate = program.clone();
+            candidate.remove(end);
+            candidate.remove(start);
+            if holds(&candidate) {
+                *program = candidate;
+                continue;
+            }
+        }
+        start += 1;
+    }
+}
+
+// The index of the `]` closing the `[` at `open`, None if there is no `[` at `open` or it is never closed.
+fn matching_bracket(program: &[char], open: usize) -> Option<usize> {
+    if program[open] != '[' {
+        return None;
+    }
+
+    let mut depth = 0;
+    for (index, c) in program.iter().enumerate().skip(open) {
+        match c {
+            '[' => depth += 1,
+            ']' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(index);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+fn balanced(program: &[char]) -> bool {
+    let mut depth = 0usize;
+    for c in program {
+        match c {
+            '[' => depth += 1,
+            ']' => match depth.checked_sub(1) {
+                Some(d) => depth = d,
+                None => return false,
+            },
+            _ => {}
+        }
+    }
+    depth == 0
+}
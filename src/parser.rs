@@ -1,16 +1,72 @@
 // Parses brainfuck code into an itermediate representation following optimizations strategies presented in http://calmerthanyouare.org/2015/01/07/optimizing-brainfuck.html
 
-use std::collections::HashMap;
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    format,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use core::{
+    fmt::{self, Display},
+    iter::{self, Peekable},
+};
+#[cfg(feature = "std")]
+use std::cell::RefCell;
 
-#[derive(Debug, Clone, PartialEq)]
+use crate::{HashMap, HashSet, OptimizationLevel};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum IR {
     Add { x: i32, offset: i32 },
     Move { over: i32 },
     Print { times: usize, offset: i32 },
     Read { offset: i32 },
     Exact { x: i32, offset: i32 },
+    Clear { offset: i32 },
     Loop { over: i32, instructions: Vec<IR> },
-    Mul { x: i32, y: i32, offset: i32 }, // m[p+x] = m[p] * y
+    // m[p+offset+x] += m[p+offset] * y. The lowering of a loop never has x == 0, as the source cell is its counter.
+    Mul { x: i32, y: i32, offset: i32 },
+    ScanZero { step: i32 }, // while m[p] != 0 { p += step }
+    // A `#` from parse_with_debug_markers, calls the interpreter's debug hook if it has one
+    Debug { offset: i32 },
+    // A `{=n}` from parse_with_assertions, stops the program with RunTimeError::AssertionFailed unless the cell holds
+    // `expected`
+    Assert { offset: i32, expected: u32 },
+}
+
+impl IR {
+    // The name of the variant, used as a key when profiling.
+    pub fn name(&self) -> &'static str {
+        match self {
+            IR::Add { .. } => "Add",
+            IR::Move { .. } => "Move",
+            IR::Print { .. } => "Print",
+            IR::Read { .. } => "Read",
+            IR::Exact { .. } => "Exact",
+            IR::Clear { .. } => "Clear",
+            IR::Loop { .. } => "Loop",
+            IR::Mul { .. } => "Mul",
+            IR::ScanZero { .. } => "ScanZero",
+            IR::Debug { .. } => "Debug",
+            IR::Assert { .. } => "Assert",
+        }
+    }
+
+    // How many iterations executing the instruction costs, about how many instructions of the O0 program it does the
+    // work of, so max_iterations bounds levels alike. Add and Move cost one per `+`, `-`, `>` or `<` they were merged
+    // from and Print one per repeat. Every other instruction costs one, Mul and Exact included even though they stand
+    // in for whole loops. A ScanZero also costs one for every cell it checks and a Loop one for every check of its
+    // condition.
+    pub fn cost(&self) -> usize {
+        match *self {
+            IR::Add { x, .. } => (x.unsigned_abs() as usize).max(1),
+            IR::Move { over } => (over.unsigned_abs() as usize).max(1),
+            IR::Print { times, .. } => times.max(1),
+            _ => 1,
+        }
+    }
 }
 
 impl From<char> for IR {
@@ -30,13 +86,96 @@ impl From<char> for IR {
     }
 }
 
+// A compact single line, like `add +3 @2` or `loop @0 { add -1 @0; mul dst=+1 y=2 @0 }`. Offsets after `@` are
+// relative to the pointer, and so is the destination of a Mul. format_ir lists a whole program instead.
+impl Display for IR {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // A distance with the direction the pointer goes, `>5` or `<5`
+        let direction = |over: i32| {
+            let arrow = if over < 0 { '<' } else { '>' };
+            format!("{arrow}{}", over.unsigned_abs())
+        };
+
+        match self {
+            IR::Add { x, offset } => write!(f, "add {x:+} @{offset}"),
+            IR::Move { over } => write!(f, "mov {}", direction(*over)),
+            IR::Print { times, offset } => write!(f, "prn x{times} @{offset}"),
+            IR::Read { offset } => write!(f, "rd @{offset}"),
+            IR::Exact { x, offset } => write!(f, "set {x} @{offset}"),
+            IR::Clear { offset } => write!(f, "clr @{offset}"),
+            IR::Mul { x, y, offset } => write!(f, "mul dst={:+} y={y} @{offset}", offset + x),
+            IR::ScanZero { step } => write!(f, "scan {}", direction(*step)),
+            IR::Debug { offset } => write!(f, "dbg @{offset}"),
+            IR::Assert { offset, expected } => write!(f, "chk ={expected} @{offset}"),
+            IR::Loop { over, instructions } => {
+                write!(f, "loop @{over} {{")?;
+                for (index, instruction) in instructions.iter().enumerate() {
+                    let separator = if index == 0 { " " } else { "; " };
+                    write!(f, "{separator}{instruction}")?;
+                }
+                if instructions.is_empty() {
+                    write!(f, "}}")
+                } else {
+                    write!(f, " }}")
+                }
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum OptimizerError {
-    UnbalancedBrackets,
+    // `position` is the char index of the offending bracket in the source
+    UnbalancedBrackets { position: usize, kind: BracketKind },
+    // The `[` at `position` opens a loop nested deeper than `limit` loops
+    NestingTooDeep { position: usize, limit: usize },
+}
+
+impl Display for OptimizerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OptimizerError::UnbalancedBrackets {
+                position,
+                kind: BracketKind::UnexpectedClose,
+            } => write!(f, "unmatched `]` at character {position}"),
+            OptimizerError::UnbalancedBrackets {
+                position,
+                kind: BracketKind::Unclosed,
+            } => write!(f, "unclosed `[` at character {position}"),
+            OptimizerError::NestingTooDeep { position, limit } => {
+                write!(
+                    f,
+                    "loops are nested deeper than {limit} at character {position}"
+                )
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum BracketKind {
+    // A `]` without a matching `[` before it
+    UnexpectedClose,
+    // A `[` that is never closed. If several are left open the outermost one is reported.
+    Unclosed,
 }
 
 // Removes any Add { x: 0, offset: _ } or Move { over: 0 } instructions.
-fn remove_zero_moves_and_adds(v: Vec<IR>) -> Vec<IR> {
+pub fn remove_zero_moves_and_adds(v: Vec<IR>) -> Vec<IR> {
+    let before = count_instructions(&v, |_| true);
+    let result = remove_zero_moves_and_adds_in(v);
+    let removed = before - count_instructions(&result, |_| true);
+    if removed > 0 {
+        note("remove_zero_moves_and_adds", || {
+            format!("removed {removed} zero adds and moves")
+        });
+    }
+    result
+}
+
+fn remove_zero_moves_and_adds_in(v: Vec<IR>) -> Vec<IR> {
     v.into_iter()
         .filter(|x| match x {
             IR::Add { x, offset: _ } => *x != 0,
@@ -47,293 +186,898 @@ fn remove_zero_moves_and_adds(v: Vec<IR>) -> Vec<IR> {
         .map(|x| match x {
             IR::Loop { over, instructions } => IR::Loop {
                 over,
-                instructions: remove_zero_moves_and_adds(instructions),
+                instructions: remove_zero_moves_and_adds_in(instructions),
             },
             _ => x,
         })
         .collect()
 }
 
+// A single optimization pass. Passes can be chained in any order, except where a pass notes what it expects as input.
+pub type Pass = fn(Vec<IR>) -> Vec<IR>;
+
+pub(crate) const O0_PASSES: &[Pass] = &[remove_zero_moves_and_adds];
+pub(crate) const O1_PASSES: &[Pass] = &[
+    remove_zero_moves_and_adds,
+    fold_adjacent,
+    remove_zero_moves_and_adds,
+];
+// With strict moves every level from O1 up runs these instead, O1 cancels out opposite moves and the levels above
+// it fold moves into offsets.
+pub(crate) const STRICT_PASSES: &[Pass] = &[
+    remove_zero_moves_and_adds,
+    fold_adjacent_strict,
+    remove_zero_moves_and_adds,
+];
+pub(crate) const O2_PASSES: &[Pass] = &[
+    remove_zero_moves_and_adds,
+    fold_adjacent,
+    remove_zero_moves_and_adds,
+    merge_updates,
+    remove_zero_moves_and_adds,
+];
+pub(crate) const O3_PASSES: &[Pass] = &[
+    remove_zero_moves_and_adds,
+    fold_adjacent,
+    remove_zero_moves_and_adds,
+    merge_updates,
+    remove_zero_moves_and_adds,
+    lower_loops,
+    merge_moves_into_offset,
+    fold_constant_muls,
+    remove_redundant_writes,
+    // The passes above can merge adds that cancel out
+    remove_zero_moves_and_adds,
+];
+pub(crate) const O4_PASSES: &[Pass] = &[
+    remove_zero_moves_and_adds,
+    fold_adjacent,
+    remove_zero_moves_and_adds,
+    merge_updates,
+    remove_zero_moves_and_adds,
+    unroll_loops,
+    merge_updates,
+    remove_zero_moves_and_adds,
+    lower_loops,
+    merge_moves_into_offset,
+    fold_constant_muls,
+    remove_redundant_writes,
+    // The passes above can merge adds that cancel out
+    remove_zero_moves_and_adds,
+];
+// Where unroll_loops is in O4_PASSES, so O4 can run with a different unroll limit
+const O4_UNROLL: usize = 5;
+
+// Runs the passes of O4 with `unroll_limit` in place of UNROLL_LIMIT.
+pub(crate) fn run_o4_passes(instructions: Vec<IR>, unroll_limit: usize) -> Vec<IR> {
+    let instructions = run_passes(instructions, O4_PASSES[..O4_UNROLL].iter().copied());
+    let instructions = unroll_loops_up_to(instructions, unroll_limit);
+    run_passes(instructions, O4_PASSES[O4_UNROLL + 1..].iter().copied())
+}
+
+// Parses brainfuck code and then runs each of the passes over it in order.
+pub fn optimize_with(
+    bf: &str,
+    passes: impl IntoIterator<Item = Pass>,
+) -> Result<Vec<IR>, OptimizerError> {
+    Ok(run_passes(parse(bf)?, passes))
+}
+
+// Runs each of the passes over already parsed IR in order, for example the IR of parse_with_debug_markers.
+pub fn run_passes(instructions: Vec<IR>, passes: impl IntoIterator<Item = Pass>) -> Vec<IR> {
+    passes
+        .into_iter()
+        .fold(instructions, |instructions, pass| pass(instructions))
+}
+
+// Runs the passes of a level. Debug builds check the result with validate_ir.
+fn optimize_level(bf: &str, level: OptimizationLevel) -> Result<Vec<IR>, OptimizerError> {
+    Ok(optimize_parsed(parse(bf)?, level))
+}
+
+// Optimizes the program at every level, from O0 up, see optimize_staged. A program that doesn't parse fails the same
+// way at every level.
+pub fn optimize_all(bf: &str) -> [Result<Vec<IR>, OptimizerError>; 5] {
+    match optimize_staged(bf) {
+        Ok(OptimizationStages { parsed, passes }) => {
+            passes.map(|mut stages| Ok(stages.pop().unwrap_or_else(|| parsed.clone())))
+        }
+        Err(err) => OptimizationLevel::ALL.map(|_| Err(err)),
+    }
+}
+
+// The IR of a program after every pass of every level, see optimize_staged.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct OptimizationStages {
+    // The IR straight out of parse
+    pub parsed: Vec<IR>,
+    // The IR after each pass of a level, indexed by level and then like the level's passes
+    pub passes: [Vec<Vec<IR>>; 5],
+}
+
+impl OptimizationStages {
+    // The IR optimizing at `level` returns.
+    pub fn level(&self, level: OptimizationLevel) -> &[IR] {
+        self.passes[level as usize].last().unwrap_or(&self.parsed)
+    }
+}
+
+// Parses the program once and runs the passes of every level. The levels share the passes they start with (O1 starts
+// with the passes of O0, O2 with those of O1 and so on), so each level only runs the passes no lower level has run
+// yet. Every level ends up with the same IR as optimizing at that level on its own.
+pub fn optimize_staged(bf: &str) -> Result<OptimizationStages, OptimizerError> {
+    let parsed = parse(bf)?;
+    let mut passes: [Vec<Vec<IR>>; 5] = Default::default();
+
+    for (index, level) in OptimizationLevel::ALL.into_iter().enumerate() {
+        let pipeline = level.passes();
+
+        // Continue from the lower level that ran the most of this level's passes. Comparing the addresses of the
+        // passes can only miss a shared pass, never share a different one, as merged functions behave the same.
+        let shared = |lower: OptimizationLevel| {
+            lower
+                .passes()
+                .iter()
+                .zip(pipeline)
+                .take_while(|(a, b)| core::ptr::fn_addr_eq(**a, **b))
+                .count()
+        };
+        let mut stages = OptimizationLevel::ALL[..index]
+            .iter()
+            .zip(&passes)
+            .map(|(&lower, stages)| &stages[..shared(lower)])
+            .max_by_key(|stages| stages.len())
+            .unwrap_or_default()
+            .to_vec();
+
+        let mut instructions = stages.last().unwrap_or(&parsed).clone();
+        for pass in &pipeline[stages.len()..] {
+            instructions = pass(instructions);
+            stages.push(instructions.clone());
+        }
+
+        debug_validate(&instructions, level);
+        passes[index] = stages;
+    }
+
+    Ok(OptimizationStages { parsed, passes })
+}
+
+fn optimize_parsed(instructions: Vec<IR>, level: OptimizationLevel) -> Vec<IR> {
+    let instructions = run_passes(instructions, level.passes().iter().copied());
+    debug_validate(&instructions, level);
+    instructions
+}
+
+// Something a pass decided while optimizing a program, see optimize_with_log.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct OptimizerEvent {
+    // The name of the pass, as it is exported
+    pub pass: &'static str,
+    pub message: String,
+}
+
+// The decisions made while optimizing a program, in the order the passes made them.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct OptimizerLog {
+    pub events: Vec<OptimizerEvent>,
+}
+
+impl OptimizerLog {
+    // The events of a single pass.
+    pub fn pass<'a>(&'a self, pass: &'a str) -> impl Iterator<Item = &'a OptimizerEvent> + 'a {
+        self.events.iter().filter(move |event| event.pass == pass)
+    }
+}
+
+impl Display for OptimizerLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for event in &self.events {
+            writeln!(f, "{}: {}", event.pass, event.message)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+thread_local! {
+    // The log of the optimize_with_log call running on this thread, None when nothing is logging. Keeping it out of
+    // the passes' arguments leaves the Pass signature alone.
+    static LOG: RefCell<Option<Vec<OptimizerEvent>>> = const { RefCell::new(None) };
+}
+
+// Records a decision of a pass. The message is only built while logging, which needs std.
+fn note(pass: &'static str, message: impl FnOnce() -> String) {
+    #[cfg(feature = "std")]
+    LOG.with(|log| {
+        if let Some(events) = log.borrow_mut().as_mut() {
+            events.push(OptimizerEvent {
+                pass,
+                message: message(),
+            });
+        }
+    });
+    #[cfg(not(feature = "std"))]
+    let _ = (pass, message);
+}
+
+// Counts the instructions matching `counts`, those in loop bodies included.
+pub(crate) fn count_instructions(ir: &[IR], counts: fn(&IR) -> bool) -> usize {
+    ir.iter()
+        .map(|i| match i {
+            IR::Loop { instructions, .. } => {
+                usize::from(counts(i)) + count_instructions(instructions, counts)
+            }
+            _ => usize::from(counts(i)),
+        })
+        .sum()
+}
+
+// Same as optimizing at `level`, but also returns why the passes changed what they did: which loops became Clears,
+// ScanZeros or Muls, which were unrolled or removed, and how many instructions were folded away. Logging costs
+// nothing when the program is optimized any other way.
+#[cfg(feature = "std")]
+pub fn optimize_with_log(
+    bf: &str,
+    level: OptimizationLevel,
+) -> (Result<Vec<IR>, OptimizerError>, OptimizerLog) {
+    let previous = LOG.with(|log| log.replace(Some(vec![])));
+    let result = optimize_level(bf, level);
+    let events = LOG.with(|log| log.replace(previous)).unwrap_or_default();
+    (result, OptimizerLog { events })
+}
+
+// An instruction that breaks an invariant of the optimization level it was produced at, see validate_ir.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidIr {
+    // Indices leading to the instruction, like LoopInfo::path
+    pub path: Vec<usize>,
+    pub instruction: IR,
+    pub reason: &'static str,
+}
+
+impl Display for InvalidIr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?} at {:?}: {}",
+            self.instruction, self.path, self.reason
+        )
+    }
+}
+
+// Checks that a program only holds what the passes of `level` can produce:
+// - O0 holds the single, unfolded commands of the source
+// - O1 only folds them, so every offset is still 0 and there are no Exact, Mul or ScanZero
+// - O2 tracks values but doesn't lower loops, so there are no Mul or ScanZero
+// - From O1 up no Add or Move is zero, and at every level no Print prints zero times
+// - No Mul adds to its own source, which no loop lowers to
+// A pass that breaks these may break the passes after it, as those only expect what the earlier levels produce.
+pub fn validate_ir(ir: &[IR], level: OptimizationLevel) -> Result<(), InvalidIr> {
+    fn check(i: &IR, level: OptimizationLevel) -> Option<&'static str> {
+        use OptimizationLevel::*;
+
+        match (level, i) {
+            (_, IR::Print { times: 0, .. }) => Some("a Print always prints"),
+            (
+                O0,
+                IR::Add {
+                    x: 1 | -1,
+                    offset: 0,
+                }
+                | IR::Move { over: 1 | -1 }
+                | IR::Print {
+                    times: 1,
+                    offset: 0,
+                }
+                | IR::Read { offset: 0 }
+                | IR::Debug { offset: 0 }
+                | IR::Assert { offset: 0, .. }
+                | IR::Loop { over: 0, .. },
+            ) => None,
+            (O0, _) => Some("O0 only holds single commands"),
+            (_, IR::Add { x: 0, .. } | IR::Move { over: 0 }) => {
+                Some("zero adds and moves are removed")
+            }
+            (_, IR::Mul { x: 0, .. }) => Some("a Mul never adds to its source"),
+            (O1, IR::Exact { .. } | IR::Mul { .. } | IR::ScanZero { .. }) => {
+                Some("O1 only folds adjacent commands")
+            }
+            (
+                O1,
+                IR::Add { offset: 0, .. }
+                | IR::Move { .. }
+                | IR::Print { offset: 0, .. }
+                | IR::Read { offset: 0 }
+                | IR::Clear { offset: 0 }
+                | IR::Debug { offset: 0 }
+                | IR::Assert { offset: 0, .. }
+                | IR::Loop { over: 0, .. },
+            ) => None,
+            (O1, _) => Some("O1 leaves every offset at 0"),
+            (O2, IR::Mul { .. } | IR::ScanZero { .. }) => Some("loops are only lowered from O3 up"),
+            _ => None,
+        }
+    }
+
+    fn walk(ir: &[IR], level: OptimizationLevel, path: &mut Vec<usize>) -> Result<(), InvalidIr> {
+        for (index, i) in ir.iter().enumerate() {
+            path.push(index);
+            if let Some(reason) = check(i, level) {
+                return Err(InvalidIr {
+                    path: path.clone(),
+                    instruction: i.clone(),
+                    reason,
+                });
+            }
+            if let IR::Loop { instructions, .. } = i {
+                walk(instructions, level, path)?;
+            }
+            path.pop();
+        }
+        Ok(())
+    }
+
+    walk(ir, level, &mut vec![])
+}
+
+// Panics in debug builds if the program breaks an invariant of the level, so a broken pass is caught by the
+// optimizer instead of by a later pass or run.
+pub(crate) fn debug_validate(ir: &[IR], level: OptimizationLevel) {
+    if cfg!(debug_assertions) {
+        if let Err(err) = validate_ir(ir, level) {
+            panic!("invalid IR after {level:?}: {err}");
+        }
+    }
+}
+
 // Parses brainfuck code into an IR with _no_ optimizations.
 pub(crate) fn optimize_o0(bf: &str) -> Result<Vec<IR>, OptimizerError> {
+    optimize_level(bf, OptimizationLevel::O0)
+}
+
+// The deepest nesting of loops parse accepts. The passes and the interpreter recurse once per loop level, so much
+// deeper programs would overflow the stack instead of failing with an error. The limit leaves room to spare in a
+// debug build on the 2 MiB stack spawned threads get by default.
+pub const MAX_NESTING_DEPTH: usize = 500;
+
+// Parses brainfuck code into an IR, one instruction per command. Loops nested deeper than MAX_NESTING_DEPTH are
+// rejected with OptimizerError::NestingTooDeep. A first line starting with `#!` is skipped as a shebang.
+pub fn parse(bf: &str) -> Result<Vec<IR>, OptimizerError> {
+    parse_with_max_depth(bf, MAX_NESTING_DEPTH)
+}
+
+// Same as parse with a different limit on the nesting of loops. Raising it is only safe with a big enough stack.
+pub fn parse_with_max_depth(bf: &str, max_depth: usize) -> Result<Vec<IR>, OptimizerError> {
+    parse_source(bf, max_depth, false, false)
+}
+
+// Same as parse, but each `#` becomes an IR::Debug instead of a comment. This is the extended brainfuck convention
+// for dumping the tape while debugging a program.
+pub fn parse_with_debug_markers(bf: &str) -> Result<Vec<IR>, OptimizerError> {
+    parse_source(bf, MAX_NESTING_DEPTH, true, false)
+}
+
+// Same as parse, but each `{=n}` becomes an IR::Assert that the current cell holds the decimal number n instead of a
+// comment, for annotating a program with what the tape should hold at that point. Anything else in braces, such as
+// `{= 65}` or `{65}`, stays a comment.
+pub fn parse_with_assertions(bf: &str) -> Result<Vec<IR>, OptimizerError> {
+    parse_source(bf, MAX_NESTING_DEPTH, false, true)
+}
+
+// Same as parse for source that isn't in memory as a str, such as the bytes of a reader
+// (`BufReader::new(file).bytes()`). Every command is ASCII, so the bytes are never decoded and anything that isn't a
+// command, invalid UTF-8 included, is a comment. Positions in errors count bytes rather than characters.
+pub fn parse_bytes(bf: impl IntoIterator<Item = u8>) -> Result<Vec<IR>, OptimizerError> {
+    parse_commands(bf.into_iter(), MAX_NESTING_DEPTH, false, false)
+}
+
+// Same as optimizing at `level`, with the source parsed by parse_bytes.
+pub fn optimize_bytes(
+    bf: impl IntoIterator<Item = u8>,
+    level: OptimizationLevel,
+) -> Result<Vec<IR>, OptimizerError> {
+    Ok(optimize_parsed(parse_bytes(bf)?, level))
+}
+
+fn parse_source(
+    bf: &str,
+    max_depth: usize,
+    debug_markers: bool,
+    assertions: bool,
+) -> Result<Vec<IR>, OptimizerError> {
+    // Other characters become a byte that isn't a command, so positions keep counting characters
+    let commands = bf.chars().map(|c| if c.is_ascii() { c as u8 } else { 0 });
+    parse_commands(commands, max_depth, debug_markers, assertions)
+}
+
+fn parse_commands(
+    commands: impl Iterator<Item = u8>,
+    max_depth: usize,
+    debug_markers: bool,
+    assertions: bool,
+) -> Result<Vec<IR>, OptimizerError> {
+    // A `#!` line at the very start is the shebang of an executable script, which is a comment even where `#` isn't.
+    // Its bytes become 0 rather than being dropped so positions still count them.
+    let mut commands = commands.peekable();
+    let mut shebang = None;
+    let commands = iter::from_fn(move || {
+        let c = commands.next()?;
+        let in_shebang = shebang.get_or_insert(c == b'#' && commands.peek() == Some(&b'!'));
+        if *in_shebang {
+            *in_shebang = c != b'\n';
+            Some(0)
+        } else {
+            Some(c)
+        }
+    });
+
     let mut instructions_stack: Vec<Vec<IR>> = vec![vec![]];
+    // Positions of the currently open brackets, parallel to instructions_stack[1..]
+    let mut open_positions: Vec<usize> = vec![];
+
+    let mut commands = commands.map(char::from).enumerate().peekable();
+    while let Some((position, c)) = commands.next() {
+        let unexpected_close = OptimizerError::UnbalancedBrackets {
+            position,
+            kind: BracketKind::UnexpectedClose,
+        };
 
-    for c in bf.chars() {
         if c == '[' {
+            if open_positions.len() == max_depth {
+                return Err(OptimizerError::NestingTooDeep {
+                    position,
+                    limit: max_depth,
+                });
+            }
             instructions_stack.push(vec![]);
+            open_positions.push(position);
         } else if c == ']' {
-            let loop_instructions = instructions_stack
-                .pop()
-                .ok_or(OptimizerError::UnbalancedBrackets)?;
+            open_positions.pop().ok_or(unexpected_close)?;
+            let loop_instructions = instructions_stack.pop().ok_or(unexpected_close)?;
 
             instructions_stack
                 .last_mut()
-                .ok_or(OptimizerError::UnbalancedBrackets)?
+                .ok_or(unexpected_close)?
                 .push(IR::Loop {
                     over: 0,
                     instructions: loop_instructions,
                 });
-        } else {
+        } else if matches!(c, '+' | '-' | '>' | '<' | '.' | ',') {
             instructions_stack
                 .last_mut()
-                .ok_or(OptimizerError::UnbalancedBrackets)?
+                .ok_or(unexpected_close)?
                 .push(c.into());
+        } else if c == '#' && debug_markers {
+            instructions_stack
+                .last_mut()
+                .ok_or(unexpected_close)?
+                .push(IR::Debug { offset: 0 });
+        } else if c == '{' && assertions {
+            if let Some(expected) = parse_assertion(&mut commands) {
+                instructions_stack
+                    .last_mut()
+                    .ok_or(unexpected_close)?
+                    .push(IR::Assert {
+                        offset: 0,
+                        expected,
+                    });
+            }
         }
+        // Every other character is a comment
+    }
+
+    // Anything left open is a loop that was never closed
+    if let Some(&position) = open_positions.first() {
+        return Err(OptimizerError::UnbalancedBrackets {
+            position,
+            kind: BracketKind::Unclosed,
+        });
+    }
+
+    Ok(instructions_stack.pop().unwrap_or_default())
+}
+
+// Parses the `=n}` of an assertion after its `{`. Only the characters that fit the syntax are consumed, and none of
+// them are commands, so source that turns out not to be an assertion parses as if this was never called.
+fn parse_assertion(commands: &mut Peekable<impl Iterator<Item = (usize, char)>>) -> Option<u32> {
+    commands.next_if(|(_, c)| *c == '=')?;
+
+    let mut expected: Option<u32> = None;
+    while let Some((_, digit)) = commands.next_if(|(_, c)| c.is_ascii_digit()) {
+        let digit = digit.to_digit(10)?;
+        expected = Some(expected.unwrap_or(0).checked_mul(10)?.checked_add(digit)?);
+    }
+
+    commands.next_if(|(_, c)| *c == '}')?;
+    expected
+}
+
+// The O1 pass, see optimize_o1.
+pub fn fold_adjacent(instructions: Vec<IR>) -> Vec<IR> {
+    let result = o1_optimize_vec(&instructions, true);
+    note_folded("fold_adjacent", &instructions, &result);
+    result
+}
+
+// Same as fold_adjacent but moves are only joined when they go the same way, so the pointer still reaches every cell
+// the source moves it to. Used for strict moves, where `<>` must fail at cell 0.
+pub fn fold_adjacent_strict(instructions: Vec<IR>) -> Vec<IR> {
+    let result = o1_fold(&instructions, true, true);
+    note_folded("fold_adjacent_strict", &instructions, &result);
+    result
+}
+
+fn note_folded(pass: &'static str, before: &[IR], after: &[IR]) {
+    let (before, after) = (
+        count_instructions(before, |_| true),
+        count_instructions(after, |_| true),
+    );
+    if before != after {
+        note(pass, || {
+            format!("folded {before} instructions into {after}")
+        });
     }
+}
+
+// Applies the O1 optimizations to a single block of IR. Feeding it IR with offsets is fine, instructions are only
+// joined when they touch the same cell.
+pub(crate) fn o1_optimize_vec(v: &[IR], program_start: bool) -> Vec<IR> {
+    o1_fold(v, program_start, false)
+}
 
-    if let Some(mut last_instructions) = instructions_stack.pop() {
-        last_instructions = remove_zero_moves_and_adds(last_instructions);
-        Ok(last_instructions)
+fn fold_pass(strict_moves: bool) -> &'static str {
+    if strict_moves {
+        "fold_adjacent_strict"
     } else {
-        Err(OptimizerError::UnbalancedBrackets)
+        "fold_adjacent"
     }
 }
 
-// Parses brainfuck code into an IR with some optimizations.
-// ALL OFFSETS ARE STILL 0
-// Optimizations:
-// - Join adjacent Add, Print, and Move instructions into a single instruction.
-//      Note for move: It is reasonable to treat moving off the tape as undefined behavior. Therefor, I am comfortable with allowing this program `<<<>>>>+`
-//      to compile down to `Move { 1 } Add { 1 }`
-// - Join adjacent Print instructions into a single instruction.
-// - Add before a Read destroys the Add
-// - Clear before a Read destroys the Clear
-// - Optimizes [-] and [+] into Clear
-// - Adjacent loops are deleted. `[.-][.]` becomes `[.-]` because the second loop will never be executed.
-pub(crate) fn optimize_o1(bf: &str) -> Result<Vec<IR>, OptimizerError> {
-    // Helper function that takes as input a vec<IR>
-    fn o1_optimize_vec(v: &Vec<IR>, program_start: bool) -> Vec<IR> {
-        let mut result: Vec<IR> = if program_start {
-            // Adds an implicit clear on program start
-            vec![IR::Exact { x: 0, offset: 0 }]
-        } else {
-            vec![]
-        };
+fn o1_fold(v: &[IR], program_start: bool, strict_moves: bool) -> Vec<IR> {
+    let mut result: Vec<IR> = if program_start {
+        // Adds an implicit clear on program start
+        vec![IR::Clear { offset: 0 }]
+    } else {
+        vec![]
+    };
 
-        for i in v {
-            match result.last_mut() {
-                None => {
-                    result.push(i.clone());
+    for i in v {
+        match result.last_mut() {
+            None => {
+                result.push(i.clone());
+            }
+            Some(last) => match (last, i) {
+                // Joins adjacent Add and Move instructions into a single instruction.
+                (IR::Add { x: a, offset: 0 }, IR::Add { x: b, offset: 0 }) => {
+                    *a = a.wrapping_add(*b)
                 }
-                Some(last) => match (last, i) {
-                    // Joins adjacent Add and Move instructions into a single instruction.
-                    (IR::Add { x: a, offset: 0 }, IR::Add { x: b, offset: 0 }) => *a += b,
-                    (IR::Move { over: a }, IR::Move { over: b }) => *a += b,
-                    // Add followed by Read destroys the Add
-                    (IR::Add { x: _, offset: 0 }, IR::Read { offset: 0 }) => {
-                        result.pop();
-                        result.push(i.clone());
-                    }
-                    // Clear followed by Read destroys the Clear
-                    (IR::Exact { x: 0, offset: 0 }, IR::Read { offset: 0 }) => {
-                        result.pop();
-                        result.push(i.clone());
-                    }
-                    (
-                        IR::Print {
-                            times: a,
-                            offset: _,
-                        },
-                        IR::Print {
-                            times: b,
-                            offset: _,
-                        },
-                    ) => {
-                        *a += b;
+                (IR::Move { over: a }, IR::Move { over: b })
+                    if !strict_moves || a.signum() == b.signum() =>
+                {
+                    *a += b
+                }
+                // Prints only join when they print the same cell.
+                (
+                    IR::Print {
+                        times: a,
+                        offset: oa,
+                    },
+                    IR::Print {
+                        times: b,
+                        offset: ob,
+                    },
+                ) if oa == ob => {
+                    *a += b;
+                }
+                // loops immediately following a loop are ignored
+                (
+                    IR::Loop {
+                        over: 0,
+                        instructions: _,
+                    },
+                    IR::Loop {
+                        over: 0,
+                        instructions: _,
+                    },
+                ) => note(fold_pass(strict_moves), || {
+                    "removed a loop right after a loop, which never runs".to_string()
+                }),
+                (
+                    IR::Clear { offset: 0 },
+                    IR::Loop {
+                        over: 0,
+                        instructions: _,
+                    },
+                ) => note(fold_pass(strict_moves), || {
+                    if program_start && result.len() == 1 {
+                        "removed a loop at the program start, which never runs".to_string()
+                    } else {
+                        "removed a loop right after a Clear, which never runs".to_string()
                     }
-                    // loops immediately following a loop are ignored
-                    (
-                        IR::Loop {
-                            over: 0,
-                            instructions: _,
-                        },
-                        IR::Loop {
-                            over: 0,
-                            instructions: _,
-                        },
-                    ) => {}
-                    (
-                        IR::Exact { x: 0, offset: 0 },
-                        IR::Loop {
-                            over: 0,
-                            instructions: _,
-                        },
-                    ) => {}
-                    // optimizes [-] and [+] into Clear or just recursively optimizes the loop
-                    (
-                        _,
-                        IR::Loop {
+                }),
+                // optimizes [-] and [+] into Clear or just recursively optimizes the loop
+                (
+                    _,
+                    IR::Loop {
+                        over: 0,
+                        instructions,
+                    },
+                ) => {
+                    if instructions.len() == 1
+                        && (instructions[0] == IR::Add { x: 1, offset: 0 }
+                            || instructions[0] == IR::Add { x: -1, offset: 0 })
+                    {
+                        note(fold_pass(strict_moves), || {
+                            // A single Add always lowers
+                            let loop_bf = to_brainfuck(instructions).unwrap_or_default();
+                            format!("converted {loop_bf} into a Clear")
+                        });
+                        result.push(IR::Clear { offset: 0 });
+                    } else {
+                        result.push(IR::Loop {
                             over: 0,
-                            instructions,
-                        },
-                    ) => {
-                        if instructions.len() == 1
-                            && (instructions[0] == IR::Add { x: 1, offset: 0 }
-                                || instructions[0] == IR::Add { x: -1, offset: 0 })
-                        {
-                            result.push(IR::Exact { x: 0, offset: 0 });
-                        } else {
-                            result.push(IR::Loop {
-                                over: 0,
-                                instructions: o1_optimize_vec(instructions, false),
-                            });
-                        }
-                    }
-                    (_, i) => {
-                        result.push(i.clone());
+                            instructions: o1_fold(instructions, false, strict_moves),
+                        });
                     }
-                },
-            }
-        }
-
-        // remove the initial Clear instruction
-        if program_start && !result.is_empty() && result[0] == (IR::Exact { x: 0, offset: 0 }) {
-            return result.into_iter().skip(1).collect();
+                }
+                (_, i) => {
+                    result.push(i.clone());
+                }
+            },
         }
-
-        // Fold adjacent instructions into a single instruction.
-        result
     }
 
-    // Start with O0 code
-    let instructions = optimize_o0(bf)?;
+    // remove the initial Clear instruction
+    if program_start && !result.is_empty() && result[0] == (IR::Clear { offset: 0 }) {
+        return result.into_iter().skip(1).collect();
+    }
 
     // Fold adjacent instructions into a single instruction.
-    Ok(remove_zero_moves_and_adds(o1_optimize_vec(
-        &instructions,
-        true,
-    )))
+    result
+}
+
+// Parses brainfuck code into an IR with some optimizations.
+// ALL OFFSETS ARE STILL 0
+// Optimizations:
+// - Join adjacent Add, Print, and Move instructions into a single instruction.
+//      Note for move: It is reasonable to treat moving off the tape as undefined behavior. Therefor, I am comfortable with allowing this program `<<<>>>>+`
+//      to compile down to `Move { 1 } Add { 1 }`. Config::strict_moves keeps the moves apart instead.
+// - Join adjacent Print instructions into a single instruction.
+//      Note for read: An Add or Clear before a Read is _not_ destroyed. Depending on the interpreter's EofBehavior
+//      a Read at the end of input may leave the cell unchanged, so the earlier write can still be observed.
+// - Optimizes [-] and [+] into Clear
+// - Adjacent loops are deleted. `[.-][.]` becomes `[.-]` because the second loop will never be executed.
+pub(crate) fn optimize_o1(bf: &str) -> Result<Vec<IR>, OptimizerError> {
+    optimize_level(bf, OptimizationLevel::O1)
 }
 
 // This type is used to merge nonadjacent Clear and Add instructions that update the same memory cell.
 enum Behavior {
+    // Adds to the memory cell. The sum isn't reduced modulo 256: the cell width is only known when the program runs,
+    // where the interpreter wraps it (or reports the overflow) the same way as the individual adds. Sums past the
+    // range of i32 wrap, which every cell width up to 32 bits agrees with.
     Add(i32),
     // Sets the memory cell to be an exact value.
     Exact(i32),
 }
 
+impl Behavior {
+    // The instruction that applies this behavior to the cell at `offset`. Setting a cell to 0 becomes a Clear.
+    fn to_ir(&self, offset: i32) -> IR {
+        match *self {
+            Behavior::Add(x) => IR::Add { x, offset },
+            Behavior::Exact(0) => IR::Clear { offset },
+            Behavior::Exact(x) => IR::Exact { x, offset },
+        }
+    }
+}
+
 // In addition to the optimizations in O1 this function also optimizes the following:
 // - Adds offset to Add instructions when the offset is known
 //   for example at program start `>++++>+++++[loop]` becomes Add { x: 4, offset: 1 } Add { x: 5, offset: 2 } Move { over: 2} ...
 //   similarily if we are within a loop that only consists of Add and Move instructions and all the Move instructions add to 0
 //   then we can remove the moves by adding offsets to the Add instructions.
 // - Non-adjacent Adds that change the same cell are merged
+// - Loops over a cell that is known to be 0 are removed, at the program start that includes cells whose adds cancel out
 pub(crate) fn optimize_o2(bf: &str) -> Result<Vec<IR>, OptimizerError> {
-    // Helper function that takes as input a vec<IR>
-    fn o2_optimize_vec(v: &Vec<IR>) -> Vec<IR> {
-        let mut result: Vec<IR> = vec![];
-        // Tracks how the behavior of a cell changes over time.
-        let mut behaviors: HashMap<i32, Behavior> = HashMap::new();
-        let mut offset = 0;
-
-        for i in v {
-            match i {
-                IR::Move { over } => {
-                    offset += *over;
+    optimize_level(bf, OptimizationLevel::O2)
+}
+
+// The O2 pass, see optimize_o2. Usually runs on the output of fold_adjacent, where every offset is still 0, but any
+// offsets are fine so it can run again after passes that introduce them. Mul and ScanZero end a run of merged
+// updates like a Loop does.
+pub fn merge_updates(instructions: Vec<IR>) -> Vec<IR> {
+    o2_optimize_vec(&instructions, true)
+}
+
+fn o2_optimize_vec(v: &[IR], program_start: bool) -> Vec<IR> {
+    let mut result: Vec<IR> = vec![];
+    // Tracks how the behavior of a cell changes over time. Ordered so the behaviors are always flushed by offset.
+    let mut behaviors: BTreeMap<i32, Behavior> = BTreeMap::new();
+    let mut offset = 0;
+    // Until the first loop that runs every cell starts at 0, except the cells whose value was read or whose behavior
+    // was flushed since
+    let mut all_zero = program_start;
+    let mut unknown: HashSet<i32> = HashSet::new();
+
+    for i in v {
+        match i {
+            IR::Move { over } => {
+                offset += *over;
+            }
+            IR::Add { x, offset: add } => {
+                let offset = offset + add;
+                let behavior = behaviors.get(&offset);
+                let result = match behavior {
+                    Some(Behavior::Add(y)) => Behavior::Add(y.wrapping_add(*x)),
+                    Some(Behavior::Exact(y)) => Behavior::Exact(y.wrapping_add(*x)),
+                    None => Behavior::Add(*x),
+                };
+                behaviors.insert(offset, result);
+            }
+            IR::Clear { offset: clear } => {
+                behaviors.insert(offset + clear, Behavior::Exact(0));
+            }
+            IR::Exact { x, offset: exact } => {
+                behaviors.insert(offset + exact, Behavior::Exact(*x));
+            }
+            IR::Read { offset: read } => {
+                let offset = offset + read;
+                // A Read at the end of input may leave the cell unchanged, so the behavior is applied
+                // before the history is dropped. Same as Print.
+                if let Some(behavior) = behaviors.remove(&offset) {
+                    result.push(behavior.to_ir(offset));
                 }
-                IR::Add { x, offset: 0 } => {
-                    let behavior = behaviors.get(&offset);
-                    let result = match behavior {
-                        Some(Behavior::Add(y)) => Behavior::Add(*y + *x),
-                        Some(Behavior::Exact(y)) => Behavior::Exact(*y + *x),
-                        None => Behavior::Add(*x),
-                    };
-                    behaviors.insert(offset, result);
+                unknown.insert(offset);
+                result.push(IR::Read { offset });
+            }
+            IR::Print {
+                times,
+                offset: print,
+            } => {
+                let offset = offset + print;
+                // When we see a Print instruction we need to
+                // 1. Apply the behavior
+                // 2. Drop the history
+                // 3. Print
+                if let Some(behavior) = behaviors.remove(&offset) {
+                    result.push(behavior.to_ir(offset));
+                    unknown.insert(offset);
                 }
-                IR::Exact { x: 0, offset: 0 } => {
-                    behaviors.insert(offset, Behavior::Exact(0));
+                result.push(IR::Print {
+                    times: *times,
+                    offset,
+                });
+            }
+            IR::Assert {
+                offset: assert,
+                expected,
+            } => {
+                // Like a Print, the cell has to hold its value by now
+                let offset = offset + assert;
+                if let Some(behavior) = behaviors.remove(&offset) {
+                    result.push(behavior.to_ir(offset));
+                    unknown.insert(offset);
                 }
-                IR::Read { offset: 0 } => {
-                    // Drop the history and return the read instruction.
-                    behaviors.remove(&offset);
-                    result.push(IR::Read { offset });
+                result.push(IR::Assert {
+                    offset,
+                    expected: *expected,
+                });
+            }
+            IR::Debug { offset: debug } => {
+                // The hook may look at any cell, so every behavior is applied first
+                for (o, b) in behaviors.iter() {
+                    result.push(b.to_ir(*o));
+                    unknown.insert(*o);
                 }
-                IR::Print { times, offset: 0 } => {
-                    // When we see a Print instruction we need to
-                    // 1. Apply the behavior
-                    // 2. Drop the history
-                    // 3. Print
-                    let behavior = behaviors.get(&offset);
-                    match behavior {
-                        Some(Behavior::Add(x)) => result.push(IR::Add { x: *x, offset }),
-                        Some(Behavior::Exact(x)) => result.push(IR::Exact { x: *x, offset }),
-                        _ => {}
-                    }
-                    behaviors.remove(&offset);
-                    result.push(IR::Print {
-                        times: *times,
-                        offset,
+                behaviors.clear();
+                result.push(IR::Debug {
+                    offset: offset + debug,
+                });
+            }
+            IR::Loop { over, instructions } => {
+                // When we see a Loop instruction we need to
+                // 0. Move to the loop's cell
+                // 1. Consider if the cell is known to be 0, if so we can remove the loop and consider as normal
+                // 2. Apply all of the behaviors that have been tracked so far
+                // 3. Drop the history
+                // 4. Move { offset }
+                // 5. Recursively optimize the loop
+                offset += over;
+                let zero = match behaviors.get(&offset) {
+                    Some(Behavior::Exact(0)) => true,
+                    // Adds that cancel out leave a cell that started at 0 at 0
+                    Some(Behavior::Add(0)) | None => all_zero && !unknown.contains(&offset),
+                    Some(_) => false,
+                };
+
+                if zero {
+                    note("merge_updates", || {
+                        format!("removed a loop at offset {offset}, its cell is known to be 0")
                     });
+                    // continue as normal
+                    continue;
                 }
-                IR::Loop {
-                    over: 0,
-                    instructions,
-                } => {
-                    // When we see a Loop instruction we need to
-                    // 1. Consider if the behavior at this offset is Exact(0), if so we can remove the loop and consider as normal
-                    // 2. Apply all of the behaviors that have been tracked so far
-                    // 3. Drop the history
-                    // 4. Move { offset }
-                    // 5. Recursively optimize the loop
-                    let behavior = behaviors.get(&offset);
-
-                    if let Some(Behavior::Exact(0)) = behavior {
-                        // continue as normal
-                        continue;
-                    }
 
-                    // apply the behaviors
-                    for (o, b) in behaviors.iter() {
-                        result.push(match b {
-                            Behavior::Add(x) => IR::Add { x: *x, offset: *o },
-                            Behavior::Exact(x) => IR::Exact { x: *x, offset: *o },
-                        });
-                    }
+                // apply the behaviors
+                for (o, b) in behaviors.iter() {
+                    result.push(b.to_ir(*o));
+                }
 
-                    // drop the history
-                    behaviors.clear();
+                // drop the history
+                behaviors.clear();
 
-                    // recursively optimize the loop
-                    result.push(IR::Loop {
-                        over: offset,
-                        instructions: o2_optimize_vec(instructions),
-                    });
+                // recursively optimize the loop
+                result.push(IR::Loop {
+                    over: offset,
+                    instructions: o2_optimize_vec(instructions, false),
+                });
 
-                    // reset the offset counter and continue as normal
-                    offset = 0;
-                }
-                _ => {
-                    panic!("Unexpected instruction in program {i:?}");
+                // reset the offset counter and continue as normal
+                offset = 0;
+                all_zero = false;
+            }
+            IR::Mul { x, y, offset: mul } => {
+                // Behaviors are only merged up to a Mul, it reads one cell and adds to another
+                for (o, b) in behaviors.iter() {
+                    result.push(b.to_ir(*o));
+                    unknown.insert(*o);
                 }
+                behaviors.clear();
+                unknown.insert(offset + mul + x);
+                result.push(IR::Mul {
+                    x: *x,
+                    y: *y,
+                    offset: offset + mul,
+                });
             }
-        }
-
-        // At the end of the list we need to apply the behaviors
-        for (o, b) in behaviors.iter() {
-            result.push(match b {
-                Behavior::Add(x) => IR::Add { x: *x, offset: *o },
-                Behavior::Exact(x) => IR::Exact { x: *x, offset: *o },
-            });
-        }
+            IR::ScanZero { step } => {
+                // Like a Loop, but the pointer has to be where the scan starts as it has no offset of its own
+                for (o, b) in behaviors.iter() {
+                    result.push(b.to_ir(*o));
+                }
+                behaviors.clear();
+                if offset != 0 {
+                    result.push(IR::Move { over: offset });
+                }
+                result.push(IR::ScanZero { step: *step });
 
-        // Technically a "correct" program we only need to run this within a loop.
-        // However, for my use case I don't like side effects and want my program to end at 0.
-        if offset != 0 {
-            result.push(IR::Move { over: offset })
+                offset = 0;
+                all_zero = false;
+            }
         }
+    }
 
-        result
+    // At the end of the list we need to apply the behaviors
+    for (o, b) in behaviors.iter() {
+        result.push(b.to_ir(*o));
     }
 
-    // Start with O1 optimize
-    let instructions = optimize_o1(bf)?;
+    // Technically a "correct" program we only need to run this within a loop.
+    // However, for my use case I don't like side effects and want my program to end at 0.
+    if offset != 0 {
+        result.push(IR::Move { over: offset })
+    }
 
-    // Optimize the program
-    Ok(remove_zero_moves_and_adds(o2_optimize_vec(&instructions)))
+    result
 }
 
 // Merges move instructions into the offsets of future instructions until we hit a loop
-fn merge_moves_into_offset(instructions: Vec<IR>) -> Vec<IR> {
+pub fn merge_moves_into_offset(instructions: Vec<IR>) -> Vec<IR> {
+    let is_move = |i: &IR| matches!(i, IR::Move { .. });
+    let before = count_instructions(&instructions, is_move);
+    let result = merge_moves_into_offset_in(instructions);
+    let merged = before - count_instructions(&result, is_move);
+    if merged > 0 {
+        note("merge_moves_into_offset", || {
+            format!("merged {merged} moves into offsets")
+        });
+    }
+    result
+}
+
+fn merge_moves_into_offset_in(instructions: Vec<IR>) -> Vec<IR> {
     let mut result: Vec<IR> = vec![];
     let mut new_offset = 0;
 
@@ -359,12 +1103,28 @@ fn merge_moves_into_offset(instructions: Vec<IR>) -> Vec<IR> {
                     offset: offset + new_offset,
                 });
             }
+            IR::Debug { offset } => {
+                result.push(IR::Debug {
+                    offset: offset + new_offset,
+                });
+            }
+            IR::Assert { offset, expected } => {
+                result.push(IR::Assert {
+                    offset: offset + new_offset,
+                    expected,
+                });
+            }
             IR::Exact { x, offset } => {
                 result.push(IR::Exact {
                     x,
                     offset: offset + new_offset,
                 });
             }
+            IR::Clear { offset } => {
+                result.push(IR::Clear {
+                    offset: offset + new_offset,
+                });
+            }
             IR::Mul { x, y, offset } => {
                 result.push(IR::Mul {
                     x,
@@ -375,8 +1135,19 @@ fn merge_moves_into_offset(instructions: Vec<IR>) -> Vec<IR> {
             IR::Loop { over, instructions } => {
                 result.push(IR::Loop {
                     over: over + new_offset,
-                    instructions: merge_moves_into_offset(instructions),
+                    instructions: merge_moves_into_offset_in(instructions),
                 });
+
+                // The loop performed the pending move, anything after it is relative to where the loop exits
+                new_offset = 0;
+            }
+            IR::ScanZero { step } => {
+                // Scans move the pointer by an unknown amount so pending moves have to happen first
+                if new_offset != 0 {
+                    result.push(IR::Move { over: new_offset });
+                }
+                result.push(IR::ScanZero { step });
+                new_offset = 0;
             }
         }
     }
@@ -389,70 +1160,551 @@ fn merge_moves_into_offset(instructions: Vec<IR>) -> Vec<IR> {
 }
 
 // O3 optimizations adds:
+// - Loops that only consist of a single Move ([>] and [<]) are replaced with ScanZero { step }.
 // - If a loop has the follow structure:
-//   - Loop only has Add and Exact instructions
-//   - At offset 0 there is an Add { x: -1, offset: 0 } instruction
-//   - TODO: Support Add { x: 1, offset: 0 }
+//   - Loop only has Add instructions
+//   - The only instruction at offset 0 is an Add { x: -1, offset: 0 } or Add { x: 1, offset: 0 } counter
 // Then the loop is removed and each Add { x, offset } instruction is replaced with a Mul { x: offset, y: x, offset: loop_offset } instruction.
-// The Exact instructions are kept as they are.
-// And an Exact { x: 0, offset: 0 } instruction is added at the end.
+// An incrementing counter runs -m[p] times (it wraps around to 0), so for those loops y is negated to -x instead.
+// And a Clear { offset: 0 } instruction is added at the end.
+// - A Mul whose source cell holds a known constant is folded into an Add (or an Exact if the destination is known too).
+// - Clears and Exacts that are overwritten before anything reads them, or that store the value the cell already
+//   holds, are removed.
 pub(crate) fn optimize_o3(bf: &str) -> Result<Vec<IR>, OptimizerError> {
-    fn o3_optimize_vec(instruction: IR) -> Vec<IR> {
-        if let IR::Loop { over, instructions } = instruction {
-            // Verify that the loop is only Add and Exact instructions
-            let only_add_and_exact = instructions.iter().all(|i| {
-                matches!(
-                    i,
-                    IR::Add { x: _, offset: _ } | IR::Exact { x: _, offset: _ }
+    optimize_level(bf, OptimizationLevel::O3)
+}
+
+// The O3 pass that turns scan and multiply loops into ScanZero and Mul instructions, see optimize_o3.
+pub fn lower_loops(instructions: Vec<IR>) -> Vec<IR> {
+    instructions.into_iter().flat_map(o3_optimize_vec).collect()
+}
+
+fn o3_optimize_vec(instruction: IR) -> Vec<IR> {
+    if let IR::Loop { over, instructions } = instruction {
+        // Scan loops move to the loop's cell then step until they find a zero
+        if let [IR::Move { over: step }] = instructions[..] {
+            note("lower_loops", || {
+                format!("lowered a scan loop with step {step} into a ScanZero")
+            });
+            return vec![IR::Move { over }, IR::ScanZero { step }];
+        }
+
+        // Verify that the loop is only Add instructions. An Exact can't be lowered: it must not run when the
+        // counter starts at 0 and it makes the Adds after it to the same cell run only once.
+        let only_add = instructions
+            .iter()
+            .all(|i| matches!(i, IR::Add { x: _, offset: _ }));
+
+        // Verify that the only change to offset 0 is an Add { x: -1 } or Add { x: 1 } counter
+        let mut counters = instructions.iter().filter_map(|i| match i {
+            IR::Add { x, offset: 0 } => Some(*x),
+            _ => None,
+        });
+        let counter = match (counters.next(), counters.next()) {
+            (Some(x @ (-1 | 1)), None) => Some(x),
+            _ => None,
+        };
+
+        if let (true, Some(counter)) = (only_add, counter) {
+            note("lower_loops", || {
+                format!(
+                    "lowered a multiply loop at offset {over} into {} Muls",
+                    instructions.len() - 1
                 )
             });
+            instructions
+                .into_iter()
+                .filter(|i| !matches!(i, IR::Add { x: _, offset: 0 }))
+                .map(|i| match i {
+                    IR::Add { x, offset } => IR::Mul {
+                        x: offset,
+                        y: if counter == 1 { x.wrapping_neg() } else { x },
+                        offset: over,
+                    },
+                    _ => unreachable!(),
+                })
+                .chain(core::iter::once(IR::Clear { offset: over }))
+                .chain(core::iter::once(IR::Move { over }))
+                .collect()
+        } else {
+            let mut result = vec![];
 
-            // Verify that there is the Add { x: -1, offset: 0 } instruction
-            let is_sub_one = instructions
-                .iter()
-                .any(|i| matches!(i, IR::Add { x: -1, offset: 0 }));
-
-            if only_add_and_exact && is_sub_one {
-                instructions
-                    .into_iter()
-                    .filter(|i| !matches!(i, IR::Add { x: -1, offset: 0 }))
-                    .map(|i| match i {
-                        IR::Add { x, offset } => IR::Mul {
-                            x: offset,
-                            y: x,
-                            offset: over,
-                        },
-                        _ => i,
-                    })
-                    .chain(std::iter::once(IR::Exact { x: 0, offset: over }))
-                    .chain(std::iter::once(IR::Move { over }))
-                    .collect()
-            } else {
-                let mut result = vec![];
+            instructions
+                .into_iter()
+                .for_each(|i| result.extend(o3_optimize_vec(i)));
+
+            vec![IR::Loop {
+                over,
+                instructions: result,
+            }]
+        }
+    } else {
+        vec![instruction]
+    }
+}
+
+// Replaces each Mul whose source cell is known at that point with a constant Add (or Exact) of the destination. Values
+// are tracked relative to the pointer until something moves it by an unknown amount. At the program start every cell
+// is known to be 0. Constants wrap like i32, which agrees with every cell width once the cell truncates them.
+pub fn fold_constant_muls(instructions: Vec<IR>) -> Vec<IR> {
+    fold_constant_muls_from(instructions, true)
+}
 
-                instructions
-                    .into_iter()
-                    .for_each(|i| result.extend(o3_optimize_vec(i)));
+fn fold_constant_muls_from(instructions: Vec<IR>, program_start: bool) -> Vec<IR> {
+    // Cells missing from the map are 0 if all_zero is set and unknown otherwise
+    let mut known: HashMap<i32, Option<i32>> = HashMap::new();
+    let mut all_zero = program_start;
+    let mut result = vec![];
 
-                vec![IR::Loop {
+    for i in instructions {
+        let value = |known: &HashMap<i32, Option<i32>>, offset: i32| match known.get(&offset) {
+            Some(value) => *value,
+            None => all_zero.then_some(0),
+        };
+
+        match i {
+            IR::Add { x, offset } => {
+                let new = value(&known, offset).map(|v| v.wrapping_add(x));
+                known.insert(offset, new);
+                result.push(i);
+            }
+            IR::Exact { x, offset } => {
+                known.insert(offset, Some(x));
+                result.push(i);
+            }
+            IR::Clear { offset } => {
+                known.insert(offset, Some(0));
+                result.push(i);
+            }
+            IR::Read { offset } => {
+                known.insert(offset, None);
+                result.push(i);
+            }
+            IR::Print { .. } | IR::Debug { .. } | IR::Assert { .. } => result.push(i),
+            IR::Move { over } => {
+                known = known.into_iter().map(|(k, v)| (k - over, v)).collect();
+                result.push(i);
+            }
+            IR::Mul { x, y, offset } => {
+                let destination = offset + x;
+                match (value(&known, offset), value(&known, destination)) {
+                    (Some(source), Some(old)) => {
+                        let new = old.wrapping_add(source.wrapping_mul(y));
+                        note("fold_constant_muls", || {
+                            format!("folded a Mul into offset {destination} into a constant {new}")
+                        });
+                        known.insert(destination, Some(new));
+                        result.push(Behavior::Exact(new).to_ir(destination));
+                    }
+                    (Some(source), None) => {
+                        note("fold_constant_muls", || {
+                            format!("folded a Mul into offset {destination} into an Add")
+                        });
+                        result.push(IR::Add {
+                            x: source.wrapping_mul(y),
+                            offset: destination,
+                        })
+                    }
+                    (None, _) => {
+                        known.insert(destination, None);
+                        result.push(i);
+                    }
+                }
+            }
+            IR::Loop { over, instructions } => {
+                result.push(IR::Loop {
                     over,
-                    instructions: result,
-                }]
+                    instructions: fold_constant_muls_from(instructions, false),
+                });
+                known.clear();
+                all_zero = false;
+            }
+            IR::ScanZero { .. } => {
+                known.clear();
+                all_zero = false;
+                result.push(i);
+            }
+        }
+    }
+
+    result
+}
+
+// Removes writes that can't be observed: an Add, Clear or Exact that is overwritten by a Clear or Exact before the
+// cell is read, and a Clear or Exact of the constant the cell is already known to hold. Only straight-line code is
+// considered, every Loop and ScanZero forgets what is known, and a Read counts as reading its cell because it may
+// leave it unchanged at the end of input. Accesses to other cells, Prints included, don't matter. The Clear that
+// ends a group of Muls is always kept so the group can still be lowered by to_brainfuck.
+pub fn remove_redundant_writes(instructions: Vec<IR>) -> Vec<IR> {
+    let before = count_instructions(&instructions, |_| true);
+    let result = remove_redundant_writes_in(instructions);
+    let removed = before - count_instructions(&result, |_| true);
+    if removed > 0 {
+        note("remove_redundant_writes", || {
+            format!("removed {removed} redundant writes")
+        });
+    }
+    result
+}
+
+fn remove_redundant_writes_in(instructions: Vec<IR>) -> Vec<IR> {
+    let mut result: Vec<Option<IR>> = vec![];
+    // The writes to each cell since it was last read, as indices into result. An Add reads its cell but only to
+    // update it, so it joins the writes before it and they all die together if the cell is overwritten.
+    let mut unread: HashMap<i32, Vec<usize>> = HashMap::new();
+    // The constant each cell is known to hold
+    let mut known: HashMap<i32, i32> = HashMap::new();
+    // Cells that have been the source of a Mul since they were last written
+    let mut mul_sources: HashSet<i32> = HashSet::new();
+
+    for i in instructions {
+        match i {
+            IR::Exact { offset, .. } | IR::Clear { offset } => {
+                let x = match i {
+                    IR::Exact { x, .. } => x,
+                    _ => 0,
+                };
+
+                if mul_sources.remove(&offset) {
+                    // Ends a group of Muls, which must stay even if the cell was already known
+                    unread.remove(&offset);
+                    known.insert(offset, x);
+                    result.push(Some(i));
+                    continue;
+                }
+
+                if known.get(&offset) == Some(&x) {
+                    continue;
+                }
+
+                for overwritten in unread
+                    .insert(offset, vec![result.len()])
+                    .unwrap_or_default()
+                {
+                    result[overwritten] = None;
+                }
+                known.insert(offset, x);
+                result.push(Some(i));
+            }
+            IR::Add { x, offset } => {
+                unread.entry(offset).or_default().push(result.len());
+                mul_sources.remove(&offset);
+                if let Some(value) = known.get_mut(&offset) {
+                    *value = value.wrapping_add(x);
+                }
+                result.push(Some(i));
+            }
+            IR::Print { offset, .. } | IR::Assert { offset, .. } => {
+                unread.remove(&offset);
+                result.push(Some(i));
+            }
+            IR::Debug { .. } => {
+                // The hook may read any cell
+                unread.clear();
+                result.push(Some(i));
+            }
+            IR::Read { offset } => {
+                unread.remove(&offset);
+                mul_sources.remove(&offset);
+                known.remove(&offset);
+                result.push(Some(i));
+            }
+            IR::Mul { x, offset, .. } => {
+                unread.remove(&offset);
+                unread.remove(&(offset + x));
+                mul_sources.remove(&(offset + x));
+                known.remove(&(offset + x));
+                mul_sources.insert(offset);
+                result.push(Some(i));
+            }
+            IR::Move { over } => {
+                unread = unread.into_iter().map(|(k, v)| (k - over, v)).collect();
+                known = known.into_iter().map(|(k, v)| (k - over, v)).collect();
+                mul_sources = mul_sources.into_iter().map(|k| k - over).collect();
+                result.push(Some(i));
+            }
+            IR::Loop { over, instructions } => {
+                unread.clear();
+                known.clear();
+                mul_sources.clear();
+                result.push(Some(IR::Loop {
+                    over,
+                    instructions: remove_redundant_writes_in(instructions),
+                }));
+            }
+            IR::ScanZero { .. } => {
+                unread.clear();
+                known.clear();
+                mul_sources.clear();
+                result.push(Some(i));
             }
-        } else {
-            vec![instruction]
         }
     }
 
-    // Start with O2 optimize
-    let instructions = optimize_o2(bf)?;
+    result.into_iter().flatten().collect()
+}
+
+// O4 optimizations adds:
+// - Loops that run a known, small number of times are unrolled, see unroll_loops. merge_updates then runs again so
+//   the copies of the body collapse into direct writes before the O3 passes. Loops whose count depends on the input
+//   or on cells that aren't known are left untouched.
+pub(crate) fn optimize_o4(bf: &str) -> Result<Vec<IR>, OptimizerError> {
+    optimize_level(bf, OptimizationLevel::O4)
+}
+
+// Same as optimizing at O4, but loops are only unrolled up to `unroll_limit` instructions instead of UNROLL_LIMIT. A
+// limit of 0 keeps every loop, which leaves what O3 produces.
+pub fn optimize_o4_with_limit(bf: &str, unroll_limit: usize) -> Result<Vec<IR>, OptimizerError> {
+    let instructions = run_o4_passes(parse(bf)?, unroll_limit);
+    debug_validate(&instructions, OptimizationLevel::O4);
+    Ok(instructions)
+}
+
+// The most instructions a loop may grow to when unroll_loops unrolls it.
+pub const UNROLL_LIMIT: usize = 64;
+
+// The O4 pass that unrolls loops with a known iteration count. A loop qualifies when its cell holds a known constant
+// from 1 to 255 on entry (so the count is the same for every cell width), and its body counts that cell down by one
+// with a single Add, returns the pointer to where it started, never otherwise writes the cell and holds no Read,
+// Loop or ScanZero. Loops that would unroll to more than UNROLL_LIMIT instructions are kept.
+pub fn unroll_loops(instructions: Vec<IR>) -> Vec<IR> {
+    unroll_loops_up_to(instructions, UNROLL_LIMIT)
+}
+
+// Same as unroll_loops with a different limit on the size of an unrolled loop.
+pub fn unroll_loops_up_to(instructions: Vec<IR>, limit: usize) -> Vec<IR> {
+    unroll_loops_from(instructions, true, limit)
+}
 
+fn unroll_loops_from(instructions: Vec<IR>, program_start: bool, limit: usize) -> Vec<IR> {
+    // Cells missing from the map are 0 if all_zero is set and unknown otherwise
+    let mut known: HashMap<i32, Option<i32>> = HashMap::new();
+    let mut all_zero = program_start;
     let mut result = vec![];
+    // Unrolled bodies are queued up in front of the remaining instructions, so what they write is tracked too
+    let mut queue: VecDeque<IR> = instructions.into();
 
-    // Optimize the program
-    instructions
-        .into_iter()
-        .for_each(|i| result.extend(o3_optimize_vec(i)));
+    while let Some(i) = queue.pop_front() {
+        let value = |known: &HashMap<i32, Option<i32>>, offset: i32| match known.get(&offset) {
+            Some(value) => *value,
+            None => all_zero.then_some(0),
+        };
+
+        match i {
+            IR::Add { x, offset } => {
+                let new = value(&known, offset).map(|v| v.wrapping_add(x));
+                known.insert(offset, new);
+            }
+            IR::Exact { x, offset } => {
+                known.insert(offset, Some(x));
+            }
+            IR::Clear { offset } => {
+                known.insert(offset, Some(0));
+            }
+            IR::Read { offset } => {
+                known.insert(offset, None);
+            }
+            IR::Print { .. } | IR::Debug { .. } | IR::Assert { .. } => {}
+            IR::Move { over } => {
+                known = known.into_iter().map(|(k, v)| (k - over, v)).collect();
+            }
+            IR::Mul { x, y, offset } => {
+                let destination = offset + x;
+                let new = match (value(&known, offset), value(&known, destination)) {
+                    (Some(source), Some(old)) => Some(old.wrapping_add(source.wrapping_mul(y))),
+                    _ => None,
+                };
+                known.insert(destination, new);
+            }
+            IR::Loop { over, instructions } => {
+                let count = value(&known, over)
+                    .filter(|count| (1..256).contains(count))
+                    .filter(|_| counts_down(&instructions))
+                    .map(|count| count as usize)
+                    .filter(|count| count * instructions.len() <= limit);
+
+                if let Some(count) = count {
+                    note("unroll_loops", || {
+                        format!("unrolled a loop that runs {count} times")
+                    });
+                    for _ in 0..count {
+                        for instruction in instructions.iter().rev() {
+                            queue.push_front(instruction.clone());
+                        }
+                    }
+                    queue.push_front(IR::Move { over });
+                    continue;
+                }
+
+                result.push(IR::Loop {
+                    over,
+                    instructions: unroll_loops_from(instructions, false, limit),
+                });
+
+                // The loop only exits on a zero cell, nothing else about the tape is known
+                known.clear();
+                known.insert(0, Some(0));
+                all_zero = false;
+                continue;
+            }
+            IR::ScanZero { .. } => {
+                known.clear();
+                known.insert(0, Some(0));
+                all_zero = false;
+            }
+        }
+
+        result.push(i);
+    }
+
+    result
+}
+
+// Whether a loop body decrements the loop's cell by exactly one and leaves everything else about the loop alone.
+fn counts_down(body: &[IR]) -> bool {
+    let mut pointer = 0;
+    let mut counter = false;
+
+    for i in body {
+        match i {
+            IR::Move { over } => pointer += over,
+            IR::Add { x: -1, offset } if pointer + offset == 0 && !counter => counter = true,
+            IR::Add { offset, .. } | IR::Exact { offset, .. } | IR::Clear { offset } => {
+                if pointer + offset == 0 {
+                    return false;
+                }
+            }
+            IR::Mul { x, offset, .. } => {
+                if pointer + offset + x == 0 {
+                    return false;
+                }
+            }
+            IR::Print { .. } | IR::Debug { .. } | IR::Assert { .. } => {}
+            IR::Read { .. } | IR::Loop { .. } | IR::ScanZero { .. } => return false,
+        }
+    }
+
+    counter && pointer == 0
+}
+
+// Moves the pointer `over` cells.
+fn shift(over: i32) -> String {
+    let c = if over < 0 { '<' } else { '>' };
+    core::iter::repeat_n(c, over.unsigned_abs() as usize).collect()
+}
+
+// Adds `x` to the current cell.
+fn add(x: i32) -> String {
+    let c = if x < 0 { '-' } else { '+' };
+    core::iter::repeat_n(c, x.unsigned_abs() as usize).collect()
+}
+
+// Lowers IR back into equivalent brainfuck. The result is not the original source, only a program with the same
+// behavior. Each instruction that uses an offset moves to its cell and back again.
+//
+// A Mul can't be lowered on its own without a scratch cell to preserve its source. The optimizer always clears the
+// source cell after the Muls (and Exacts) that were generated from the same loop, so that group is lowered back
+// into a single loop, with the Exacts and Clears of other cells in the group moved in front of it. Fails with
+// InvalidIr on a Mul that isn't followed by a Clear of its source, or on a group where moving a write would change
+// what a Mul adds to. The interpreter runs both, but the optimizer never produces them.
+pub fn to_brainfuck(ir: &[IR]) -> Result<String, InvalidIr> {
+    let mut bf = String::new();
+    lower(ir, &mut vec![], &mut bf)?;
+    Ok(bf)
+}
+
+fn lower(ir: &[IR], path: &mut Vec<usize>, bf: &mut String) -> Result<(), InvalidIr> {
+    let mut i = 0;
+
+    while i < ir.len() {
+        match &ir[i] {
+            IR::Add { x, offset } => {
+                *bf += &format!("{}{}{}", shift(*offset), add(*x), shift(-offset));
+            }
+            IR::Move { over } => *bf += &shift(*over),
+            IR::Print { times, offset } => {
+                *bf += &format!("{}{}{}", shift(*offset), ".".repeat(*times), shift(-offset));
+            }
+            IR::Read { offset } => *bf += &format!("{},{}", shift(*offset), shift(-offset)),
+            IR::Exact { x, offset } => {
+                *bf += &format!("{}[-]{}{}", shift(*offset), add(*x), shift(-offset));
+            }
+            IR::Clear { offset } => *bf += &format!("{}[-]{}", shift(*offset), shift(-offset)),
+            IR::Loop { over, instructions } => {
+                *bf += &format!("{}[", shift(*over));
+                path.push(i);
+                lower(instructions, path, bf)?;
+                path.pop();
+                *bf += "]";
+            }
+            IR::ScanZero { step } => *bf += &format!("[{}]", shift(*step)),
+            IR::Debug { offset } => *bf += &format!("{}#{}", shift(*offset), shift(-offset)),
+            IR::Assert { offset, expected } => {
+                *bf += &format!("{}{{={expected}}}{}", shift(*offset), shift(-offset));
+            }
+            IR::Mul { x: _, y: _, offset } => {
+                let source = *offset;
+
+                // Find the clear of the source that ends this group
+                let end = ir[i..]
+                    .iter()
+                    .position(|instruction| match instruction {
+                        IR::Mul { offset, .. } => *offset != source,
+                        IR::Exact { x: _, offset } | IR::Clear { offset } => *offset == source,
+                        _ => true,
+                    })
+                    .map(|end| i + end)
+                    .filter(|&end| ir[end] == IR::Clear { offset: source })
+                    .ok_or_else(|| {
+                        path.push(i);
+                        InvalidIr {
+                            path: path.clone(),
+                            instruction: ir[i].clone(),
+                            reason: "a Mul is only lowered when a Clear of its source follows it",
+                        }
+                    })?;
+
+                // The Exacts in the group happen whether or not the loop runs, which is only the same if no Mul of the
+                // group adds to their cell
+                let group = &ir[i..end];
+                let destinations: Vec<i32> = group
+                    .iter()
+                    .filter_map(|instruction| match instruction {
+                        IR::Mul { x, offset, .. } => Some(offset + x),
+                        _ => None,
+                    })
+                    .collect();
+                if let Some(write) = group.iter().position(|instruction| match instruction {
+                    IR::Exact { offset, .. } | IR::Clear { offset } => {
+                        destinations.contains(offset)
+                    }
+                    _ => false,
+                }) {
+                    path.push(i + write);
+                    return Err(InvalidIr {
+                        path: path.clone(),
+                        instruction: group[write].clone(),
+                        reason: "a write between a Mul and the Clear of its source can't be moved out of the loop",
+                    });
+                }
+
+                let mut body = String::new();
+                for instruction in &ir[i..end] {
+                    match instruction {
+                        IR::Mul { x, y, offset: _ } => {
+                            body += &format!("{}{}{}", shift(*x), add(*y), shift(-x))
+                        }
+                        exact => lower(core::slice::from_ref(exact), path, bf)?,
+                    }
+                }
+                *bf += &format!("{}[-{}]{}", shift(source), body, shift(-source));
+
+                i = end;
+            }
+        }
+
+        i += 1;
+    }
 
-    Ok(merge_moves_into_offset(result))
+    Ok(())
 }
@@ -0,0 +1,83 @@
+// Optimizing a program once and then running or testing it many times.
+
+use alloc::vec::Vec;
+use core::num::Wrapping;
+
+use crate::{
+    interpreter::{Cell, RunOutcome, RunTimeError},
+    parser::{OptimizerError, IR},
+    test_instructions, CellWidth, Config, TestFailure,
+};
+
+// A program that has been parsed and optimized with a config. Running or testing it only builds a new interpreter
+// from the optimized IR, which makes it cheaper than the functions that take the source when the same program gets
+// many inputs.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Program {
+    instructions: Vec<IR>,
+    config: Config,
+}
+
+impl Program {
+    // Optimizes the program the way the run functions and test would with the same config.
+    pub fn compile(bf: &str, config: Config) -> Result<Self, OptimizerError> {
+        Ok(Self {
+            instructions: config.optimize(bf)?,
+            config,
+        })
+    }
+
+    pub fn instructions(&self) -> &[IR] {
+        &self.instructions
+    }
+
+    pub fn config(&self) -> Config {
+        self.config
+    }
+
+    // Same as the `run` function, every call starts from a fresh interpreter.
+    pub fn run(&self, input: &[Wrapping<u8>]) -> Result<Vec<Wrapping<u8>>, RunTimeError> {
+        let outcome = self.run_outcome(input);
+        match outcome.error {
+            Some(err) => Err(err),
+            None => Ok(outcome.output),
+        }
+    }
+
+    // Same as the `run_outcome` function.
+    pub fn run_outcome(&self, input: &[Wrapping<u8>]) -> RunOutcome {
+        match self.config.cell_width {
+            CellWidth::U8 => self.run_cells::<Wrapping<u8>>(input),
+            CellWidth::U16 => self.run_cells::<Wrapping<u16>>(input),
+            CellWidth::U32 => self.run_cells::<Wrapping<u32>>(input),
+        }
+    }
+
+    fn run_cells<C: Cell>(&self, input: &[Wrapping<u8>]) -> RunOutcome {
+        self.config
+            .builder::<C>()
+            .build(self.instructions.clone())
+            .run(input)
+    }
+
+    // Same as the `test` function.
+    pub fn test<I, O>(&self, inputs: I, outputs: O) -> Vec<TestFailure>
+    where
+        I: IntoIterator<Item = Vec<Wrapping<u8>>>,
+        O: IntoIterator<Item = Vec<Wrapping<u8>>>,
+    {
+        let instructions = self.instructions.clone();
+        let config = self.config;
+        match config.cell_width {
+            CellWidth::U8 => {
+                test_instructions::<Wrapping<u8>, _, _>(instructions, &[], inputs, outputs, config)
+            }
+            CellWidth::U16 => {
+                test_instructions::<Wrapping<u16>, _, _>(instructions, &[], inputs, outputs, config)
+            }
+            CellWidth::U32 => {
+                test_instructions::<Wrapping<u32>, _, _>(instructions, &[], inputs, outputs, config)
+            }
+        }
+    }
+}
@@ -0,0 +1,131 @@
+// Testing many different programs at once, for example every submission for an assignment.
+
+use alloc::{string::String, vec::Vec};
+use core::{
+    fmt::{self, Display},
+    num::Wrapping,
+};
+
+use crate::{test, Config, TestFailure};
+
+// Programs to test, each with its own name and cases. Cases are run in the order they were added.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TestSuite {
+    cases: Vec<SuiteCase>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SuiteCase {
+    name: String,
+    bf: String,
+    inputs: Vec<Vec<Wrapping<u8>>>,
+    outputs: Vec<Vec<Wrapping<u8>>>,
+}
+
+impl TestSuite {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Adds a program along with the inputs and expected outputs to test it with, see `test`.
+    pub fn add_case<I, O>(
+        &mut self,
+        name: impl Into<String>,
+        bf: impl Into<String>,
+        inputs: I,
+        outputs: O,
+    ) -> &mut Self
+    where
+        I: IntoIterator<Item = Vec<Wrapping<u8>>>,
+        O: IntoIterator<Item = Vec<Wrapping<u8>>>,
+    {
+        self.cases.push(SuiteCase {
+            name: name.into(),
+            bf: bf.into(),
+            inputs: inputs.into_iter().collect(),
+            outputs: outputs.into_iter().collect(),
+        });
+        self
+    }
+
+    // Tests every program with the same config.
+    pub fn run(&self, config: Config) -> SuiteReport {
+        SuiteReport {
+            results: self
+                .cases
+                .iter()
+                .map(|case| {
+                    let failures = test(
+                        &case.bf,
+                        case.inputs.iter().cloned(),
+                        case.outputs.iter().cloned(),
+                        config,
+                    );
+                    (case.name.clone(), failures)
+                })
+                .collect(),
+        }
+    }
+}
+
+// The failures of every program in a suite, in the order the programs were added. A program passed if it has no
+// failures.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SuiteReport {
+    results: Vec<(String, Vec<TestFailure>)>,
+}
+
+impl SuiteReport {
+    // The failures of the program with this name, None if the suite has no such program. If several programs share
+    // a name the first one is returned.
+    pub fn failures(&self, name: &str) -> Option<&[TestFailure]> {
+        self.results
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, failures)| &failures[..])
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &[TestFailure])> {
+        self.results
+            .iter()
+            .map(|(name, failures)| (&name[..], &failures[..]))
+    }
+
+    // How many programs passed.
+    pub fn passed(&self) -> usize {
+        self.results
+            .iter()
+            .filter(|(_, failures)| failures.is_empty())
+            .count()
+    }
+
+    // How many programs failed.
+    pub fn failed(&self) -> usize {
+        self.results.len() - self.passed()
+    }
+
+    pub fn all_passed(&self) -> bool {
+        self.failed() == 0
+    }
+}
+
+// A summary line followed by every failure, indented under the name of its program.
+impl Display for SuiteReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} programs passed",
+            self.passed(),
+            self.results.len()
+        )?;
+
+        for (name, failures) in self.iter().filter(|(_, failures)| !failures.is_empty()) {
+            write!(f, "\n{name}:")?;
+            for failure in failures {
+                write!(f, "\n    {failure}")?;
+            }
+        }
+
+        Ok(())
+    }
+}
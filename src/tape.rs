@@ -0,0 +1,236 @@
+use alloc::{vec, vec::Vec};
+#[cfg(feature = "std")]
+use core::hash::{Hash, Hasher};
+
+use crate::interpreter::Cell;
+
+// Decides how much memory the interpreter has available.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TapeMode {
+    // A tape with exactly this many cells, allocated up front.
+    Fixed(usize),
+    // A tape that starts empty and grows on demand, optionally up to `max` cells.
+    Growing { max: Option<usize> },
+    // Like Growing, but the tape also extends to the left of the starting cell so negative indices are
+    // addressable. `max` limits the number of cells on each side of cell 0.
+    Bidirectional { max: Option<usize> },
+    // A tape with exactly this many cells where the ends are joined: moving right off the last cell lands on cell 0
+    // and moving left off cell 0 lands on the last cell, so no access is ever out of bounds. O2 and up assume cells
+    // at different offsets are different cells, which a program that goes around the tape breaks, so programs for a
+    // circular tape should be optimized with O1 at most.
+    Circular(usize),
+}
+
+impl Default for TapeMode {
+    fn default() -> Self {
+        TapeMode::Fixed(65536)
+    }
+}
+
+// The tape is stored as two halves. `cells` holds index 0 and up, `left` holds index -1 and down (so cell -1 is
+// `left[0]`). Only the Bidirectional mode ever uses the left half.
+pub(crate) struct Tape<C: Cell> {
+    mode: TapeMode,
+    cells: Vec<C>,
+    left: Vec<C>,
+    // What cells 0 and up hold before the program runs, and again after every reset
+    initial: Vec<C>,
+    // High-water marks for each half: every cell at or past them is known to be zero
+    cells_touched: usize,
+    left_touched: usize,
+}
+
+impl<C: Cell> Tape<C> {
+    pub(crate) fn new(mode: TapeMode) -> Self {
+        let cells = match mode {
+            TapeMode::Fixed(size) | TapeMode::Circular(size) => vec![C::default(); size],
+            TapeMode::Growing { max: _ } | TapeMode::Bidirectional { max: _ } => vec![],
+        };
+
+        Self {
+            mode,
+            cells,
+            left: vec![],
+            initial: vec![],
+            cells_touched: 0,
+            left_touched: 0,
+        }
+    }
+
+    // Returns the maximum number of cells a half of the tape may hold.
+    fn limit(&self, left: bool) -> Option<usize> {
+        match self.mode {
+            TapeMode::Fixed(_) | TapeMode::Growing { max: _ } | TapeMode::Circular(_) if left => {
+                Some(0)
+            }
+            TapeMode::Fixed(size) | TapeMode::Circular(size) => Some(size),
+            TapeMode::Growing { max } | TapeMode::Bidirectional { max } => max,
+        }
+    }
+
+    // Returns the lowest and highest addressable index.
+    pub(crate) fn bounds(&self) -> (i32, i32) {
+        let clamp = |limit: Option<usize>| {
+            limit.map_or(i32::MAX, |limit| limit.min(i32::MAX as usize) as i32)
+        };
+        (-clamp(self.limit(true)), clamp(self.limit(false)) - 1)
+    }
+
+    // Maps an index to the cell it addresses on a circular tape, every other tape addresses cells by their index.
+    pub(crate) fn wrap(&self, index: i32) -> i32 {
+        match self.mode {
+            TapeMode::Circular(size) if size > 0 => {
+                (i64::from(index).rem_euclid(size as i64)) as i32
+            }
+            _ => index,
+        }
+    }
+
+    // Maps a signed tape index to the half it belongs to and the position within that half.
+    fn locate(index: i32) -> (bool, usize) {
+        if index < 0 {
+            (true, (-(index as i64) - 1) as usize)
+        } else {
+            (false, index as usize)
+        }
+    }
+
+    pub(crate) fn get(&self, index: i32) -> Option<C> {
+        let (left, index) = Self::locate(self.wrap(index));
+        let half = if left { &self.left } else { &self.cells };
+
+        match half.get(index) {
+            Some(cell) => Some(*cell),
+            // Cells a growing tape hasn't reached yet are implicitly zero
+            None if self.limit(left).is_none_or(|limit| index < limit) => Some(C::default()),
+            None => None,
+        }
+    }
+
+    pub(crate) fn get_mut(&mut self, index: i32) -> Option<&mut C> {
+        let (left, index) = Self::locate(self.wrap(index));
+        let limit = self.limit(left);
+        let (half, touched) = if left {
+            (&mut self.left, &mut self.left_touched)
+        } else {
+            (&mut self.cells, &mut self.cells_touched)
+        };
+
+        if index >= half.len() {
+            if limit.is_some_and(|limit| index >= limit) {
+                return None;
+            }
+
+            // Grow to at least double the current length to amortize reallocations
+            let mut len = (half.len() * 2).max(index + 1);
+            if let Some(limit) = limit {
+                len = len.min(limit);
+            }
+            half.resize(len, C::default());
+        }
+
+        // The caller may write anything, so the cell has to be checked from now on
+        *touched = (*touched).max(index + 1);
+        half.get_mut(index)
+    }
+
+    // Zeroes the tape in place, keeping its allocation so repeated runs don't pay for a new one, then writes the
+    // initial pattern back. Only the cells up to the high-water marks can be non-zero.
+    pub(crate) fn reset(&mut self) {
+        self.cells[..self.cells_touched].fill(C::default());
+        self.left[..self.left_touched].fill(C::default());
+        self.left_touched = 0;
+
+        let len = self.initial.len();
+        if self.cells.len() < len {
+            self.cells.resize(len, C::default());
+        }
+        self.cells[..len].copy_from_slice(&self.initial);
+        self.cells_touched = len;
+    }
+
+    // Replaces the initial pattern and resets the tape to it. Cells past the end of the tape are dropped.
+    pub(crate) fn set_initial(&mut self, mut initial: Vec<C>) {
+        if let Some(limit) = self.limit(false) {
+            initial.truncate(limit);
+        }
+        self.initial = initial;
+        self.reset();
+    }
+
+    // Whether the tape holds the initial pattern and nothing else. reset never leaves the cells below the initial
+    // pattern's length past the high-water mark, so only the touched cells need checking.
+    pub(crate) fn is_initial(&self) -> bool {
+        let zero = C::default();
+        self.left[..self.left_touched]
+            .iter()
+            .all(|cell| cell.is_zero())
+            && self.cells[..self.cells_touched]
+                .iter()
+                .enumerate()
+                .all(|(index, cell)| *cell == *self.initial.get(index).unwrap_or(&zero))
+    }
+
+    // Returns the index and value of every cell that doesn't hold its initial value, in index order. Only the cells
+    // below the high-water marks are scanned.
+    pub(crate) fn changed(&self) -> Vec<(i32, C)> {
+        let zero = C::default();
+        let left = self.left[..self.left_touched]
+            .iter()
+            .enumerate()
+            .rev()
+            .filter(|(_, cell)| !cell.is_zero())
+            .map(|(index, cell)| (-(index as i64) - 1, *cell));
+        let right = self.cells[..self.cells_touched]
+            .iter()
+            .enumerate()
+            .filter(|(index, cell)| **cell != *self.initial.get(*index).unwrap_or(&zero))
+            .map(|(index, cell)| (index as i64, *cell));
+
+        left.chain(right)
+            .map(|(index, cell)| (index as i32, cell))
+            .collect()
+    }
+
+    // Feeds the index and value of every non-zero cell to `state`, in index order, so tapes holding the same non-zero
+    // cells hash the same however far each has grown. Only the cells below the high-water marks are scanned.
+    #[cfg(feature = "std")]
+    pub(crate) fn hash_non_zero<H: Hasher>(&self, state: &mut H) {
+        let left = self.left[..self.left_touched]
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(index, cell)| (-(index as i64) - 1, cell));
+        let right = self.cells[..self.cells_touched]
+            .iter()
+            .enumerate()
+            .map(|(index, cell)| (index as i64, cell));
+
+        for (index, cell) in left.chain(right).filter(|(_, cell)| !cell.is_zero()) {
+            index.hash(state);
+            cell.hash(state);
+        }
+    }
+
+    // Returns the cells up to and including the last non-zero cell. A blank tape is reported as a single zero
+    // cell regardless of how far it has grown. If any cell left of 0 is non-zero the result starts at the first
+    // non-zero negative cell instead of at cell 0. Only the cells below the high-water marks are scanned.
+    pub(crate) fn shrinked(&self) -> Vec<C> {
+        let touched = &self.cells[..self.cells_touched];
+        let right = match touched.iter().rposition(|cell| !cell.is_zero()) {
+            Some(last_non_zero_cell) => &self.cells[0..=last_non_zero_cell],
+            None => &[C::default()],
+        };
+
+        let touched = &self.left[..self.left_touched];
+        match touched.iter().rposition(|cell| !cell.is_zero()) {
+            Some(first_non_zero_cell) => self.left[0..=first_non_zero_cell]
+                .iter()
+                .rev()
+                .chain(right)
+                .copied()
+                .collect(),
+            None => right.to_vec(),
+        }
+    }
+}
@@ -1,145 +1,4605 @@
 // A fuzing tests for the optimizer.
 // NOTE: Some programs do no halt so we stop running them
 
-use std::num::Wrapping;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    hash::{DefaultHasher, Hash, Hasher},
+    num::Wrapping,
+    path::Path,
+    time::{Duration, Instant},
+};
 
-use itertools::Itertools;
-use rand::{thread_rng, Rng, SeedableRng};
+use either::Either;
+use proptest::prelude::*;
+use proptest::test_runner::{TestCaseError, TestError, TestRunner};
+use rand::{Rng, SeedableRng};
 use rand_chacha::ChaCha8Rng;
 
 use crate::{
-    interpreter::{Interpreter, RunTimeError},
-    parser::{optimize_o0, optimize_o1, optimize_o2, optimize_o3},
+    benchmark, bytes_to_string,
+    codegen::{format_ir, to_c},
+    count_min_reads, find_infinite_loops,
+    flat::{flatten, FlatOp},
+    fold_adjacent, fold_adjacent_strict, fold_constant_muls,
+    interpreter::{
+        Breakpoint, Breakpoints, Interpreter, InterpreterBuilder, Overflow, RunOutcome,
+        RunTimeError, StepResult, TraceMode,
+    },
+    is_pure, loop_positions, loops, lower_loops, merge_moves_into_offset, merge_updates, minimize,
+    optimization_report, optimize, optimize_all, optimize_bytes, optimize_o4_with_limit,
+    optimize_staged, optimize_with, optimize_with_log, parse, parse_bytes, parse_with_assertions,
+    parse_with_debug_markers, parse_with_max_depth,
+    parser::{
+        o1_optimize_vec, optimize_o0, optimize_o1, optimize_o2, optimize_o3, optimize_o4,
+        to_brainfuck, BracketKind, InvalidIr, OptimizerError, IR,
+    },
+    remove_redundant_writes, remove_zero_moves_and_adds, run, run_bytes, run_passes, run_profiled,
+    run_str, run_to_string, run_verified, run_with_inline_input, split_inline_input,
+    static_offset_bounds, test, test_bytes, test_with_initial_memory, unroll_loops,
+    unroll_loops_up_to, validate_ir, CellEncoding, CellStats, CellWidth, Config, EofBehavior,
+    LoopId, LoopInfo, OptimizationLevel, OptimizerLog, Pass, Program, TapeMode, TestFailure,
+    TestFailureType, TestSuite, VerifyError, MAX_NESTING_DEPTH, UNROLL_LIMIT,
 };
 
-fn random_bf() -> String {
-    let mut rng = rand::thread_rng();
-    let mut bf = String::new();
-    let mut i = 0;
+// Generates balanced programs out of single commands and the loops the optimizer recognizes
+fn bf_program() -> impl Strategy<Value = String> {
+    let leaf = prop_oneof![
+        4 => prop::sample::select(vec!["+", "-", ">", "<", ".", ","]),
+        1 => prop::sample::select(vec!["[-]", "[>]", "[<]", "[->+<]", "[->>++<<]", "[+<-->]", "[->[-]+<]"]),
+    ]
+    .prop_map(String::from);
+
+    leaf.prop_recursive(3, 64, 8, |inner| {
+        prop_oneof![
+            prop::collection::vec(inner.clone(), 0..8).prop_map(|parts| parts.concat()),
+            prop::collection::vec(inner, 0..8).prop_map(|parts| format!("[{}]", parts.concat())),
+        ]
+    })
+}
+
+// Counterexamples found by `many` are saved here and replayed by `corpus`, so every bug found stays tested.
+const CORPUS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/corpus");
+
+#[test]
+fn many() {
+    // Failing cases are shrunk to a minimal program. Set PROPTEST_RNG_SEED to reproduce a run.
+    let config = ProptestConfig {
+        source_file: Some(file!()),
+        ..ProptestConfig::with_cases(512)
+    };
+    let strategy = (bf_program(), prop::collection::vec(any::<u8>(), 0..8));
+
+    let result =
+        TestRunner::new(config).run(&strategy, |(bf, input)| specific(&bf, &bytes(&input)));
+    if let Err(err) = result {
+        if let TestError::Fail(_, (bf, input)) = &err {
+            // Shrinking stops at whatever the strategy can produce, minimize also removes single commands
+            let bf = minimize(bf, |bf| specific(bf, &bytes(input)).is_err());
+            save_counterexample(&bf, input);
+        }
+        panic!("{err}");
+    }
+}
+
+// Writes the program to `<hash>.bf` in the corpus, along with its input in `<hash>.input`.
+fn save_counterexample(bf: &str, input: &[u8]) {
+    let mut hasher = DefaultHasher::new();
+    (bf, input).hash(&mut hasher);
+    let path = Path::new(CORPUS).join(format!("{:016x}", hasher.finish()));
+
+    let saved = fs::create_dir_all(CORPUS)
+        .and_then(|_| fs::write(path.with_extension("bf"), bf))
+        .and_then(|_| fs::write(path.with_extension("input"), input));
+    match saved {
+        Ok(()) => eprintln!("saved the counterexample to {}", path.display()),
+        Err(err) => eprintln!("couldn't save the counterexample: {err}"),
+    }
+}
+
+// Reruns a program saved in the corpus. The input is read from the `.input` file next to it, if there is one.
+fn replay(path: &Path) -> Result<(), TestCaseError> {
+    let bf = fs::read_to_string(path).unwrap();
+    let input = fs::read(path.with_extension("input")).unwrap_or_default();
+    specific(&bf, &bytes(&input))
+}
+
+#[test]
+fn corpus() {
+    let Ok(entries) = fs::read_dir(CORPUS) else {
+        return;
+    };
+
+    for path in entries.map(|entry| entry.unwrap().path()) {
+        if path.extension().is_some_and(|extension| extension == "bf") {
+            if let Err(err) = replay(&path) {
+                panic!("{}: {err}", path.display());
+            }
+        }
+    }
+}
+
+#[test]
+fn one() {
+    let bf = ">++.+[+]+.><[].<";
+    specific(bf, &[]).unwrap();
 
-    while i < rng.gen_range(0..100) {
-        let r = rng.gen_range(0..8);
-        match r {
-            0 => bf.push('+'),
-            1 => bf.push('-'),
-            2 => bf.push('>'),
-            3 => bf.push('<'),
-            4 => bf.push('.'),
-            5 => bf.push(','),
-            6 => bf.push('['),
-            7 => bf.push(']'),
-            _ => panic!("Invalid random number"),
+    // A clear inside of a multiply loop only happens if the loop runs
+    specific(">++<[->[-]+<]>.", &[]).unwrap();
+    specific("+>++<[->[-]+<]>.", &[]).unwrap();
+}
+
+#[test]
+fn minimize_programs() {
+    // A stand-in for an optimizer bug: dropping every Clear the optimizer left
+    let disagrees = |bf: &str| {
+        let Ok(o0) = optimize(bf, OptimizationLevel::O0) else {
+            return false;
         };
-        i += 1;
+        let buggy = optimize(bf, OptimizationLevel::O3)
+            .unwrap()
+            .into_iter()
+            .filter(|i| !matches!(i, IR::Clear { .. }))
+            .collect();
+        let run = |ir| parts(Interpreter::<Wrapping<u8>>::from(ir, 1000).run(&[]));
+        run(o0) != run(buggy)
+    };
+
+    let bf = "print 2 ++>+++[>+<-]<. then clear it >+<[-] and print again .";
+    assert!(disagrees(bf));
+    let minimized = minimize(bf, disagrees);
+    assert_eq!(minimized, "+.[-].");
+
+    // Bodies are kept when only the brackets around them have to go
+    let config = Config {
+        max_iterations: 1000,
+        ..Config::default()
+    };
+    let prints_2 = |bf: &str| run(bf, &[], config) == Ok(bytes(&[2]));
+    assert_eq!(minimize("+[+.[-]]", prints_2), "++.");
+
+    // Nothing is removed if the predicate doesn't hold to begin with, or only holds for unbalanced programs
+    assert_eq!(minimize("+[-]. comment", |_| false), "+[-]. comment");
+    assert_eq!(minimize("+[-", |bf| bf.contains('[')), "+[-");
+}
+
+// Runs the program at every optimization level and checks that they all agree with O0. The tape is unbounded so
+// that only running out of input or iterations can stop a program early.
+fn specific(bf: &str, input: &[Wrapping<u8>]) -> Result<(), TestCaseError> {
+    // Staging the levels parses the program once and shares the passes the levels have in common
+    let stages = match optimize_staged(bf) {
+        Ok(stages) => stages,
+        Err(err) => {
+            // Check that all parses have the same Optimizer error
+            for o in optimize_all(bf) {
+                prop_assert_eq!(o, Err(err));
+            }
+            return Ok(());
+        }
+    };
+    let o0 = stages.level(OptimizationLevel::O0).to_vec();
+    let optimized = [
+        OptimizationLevel::O1,
+        OptimizationLevel::O2,
+        OptimizationLevel::O3,
+        OptimizationLevel::O4,
+    ]
+    .map(|level| stages.level(level).to_vec());
+
+    let max_iterations = 10000;
+    let tape = TapeMode::Bidirectional { max: None };
+    let mut i0: Interpreter = Interpreter::with_tape(o0.clone(), max_iterations, tape);
+    let outcome0 = i0.run(input);
+    let (e0, r0) = (outcome0.error, outcome0.output.clone());
+
+    // The flattened program must behave exactly like the recursive one
+    let mut flat: Interpreter = Interpreter::with_tape(vec![], max_iterations, tape);
+    let flat_result = flat.run_flat(&flatten(&o0), &mut input.iter().copied());
+    prop_assert_eq!(&flat_result, &outcome0);
+
+    // A program with an infinite loop in its outermost block can never finish
+    for o in std::iter::once(&o0).chain(&optimized) {
+        if find_infinite_loops(o).iter().any(|l| l.path.len() == 1) {
+            prop_assert!(e0.is_some(), "{:?}", o);
+        }
+    }
+
+    // The optimized programs take fewer iterations, so they may finish where O0 gave up
+    if e0 == Some(RunTimeError::MaxIterationsExceeded) {
+        return Ok(());
+    }
+
+    for o in optimized {
+        let mut i: Interpreter = Interpreter::with_tape(o.clone(), max_iterations, tape);
+        let outcome = i.run(input);
+
+        let mut flat: Interpreter = Interpreter::with_tape(vec![], max_iterations, tape);
+        let flat_result = flat.run_flat(&flatten(&o), &mut input.iter().copied());
+        prop_assert_eq!(&flat_result, &outcome, "{:?}", o);
+
+        prop_assert_eq!(outcome.error, e0, "{:?}", o);
+        prop_assert_eq!(&outcome.output, &r0, "{:?}", o);
+        if e0.is_none() {
+            prop_assert_eq!(outcome.final_pointer, outcome0.final_pointer, "{:?}", o);
+            prop_assert_eq!(
+                i.return_shrinked_memory(),
+                i0.return_shrinked_memory(),
+                "{:?}",
+                o
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// The error and output of a run, for comparing against expected results.
+fn parts(outcome: RunOutcome) -> (Option<RunTimeError>, Vec<Wrapping<u8>>) {
+    (outcome.error, outcome.output)
+}
+
+fn bytes(v: &[u8]) -> Vec<Wrapping<u8>> {
+    v.iter().copied().map(Wrapping).collect()
+}
+
+// Compares two programs as multisets of instructions, for optimizer output where only the order of some
+// instructions is unspecified. Loops are compared as a whole, including the order of their bodies.
+fn same_instructions(a: &[IR], b: &[IR]) -> bool {
+    let count = |ir: &[IR]| {
+        let mut counts: HashMap<IR, usize> = HashMap::new();
+        for i in ir {
+            *counts.entry(i.clone()).or_default() += 1;
+        }
+        counts
+    };
+
+    count(a) == count(b)
+}
+
+#[test]
+fn cell_widths() {
+    // 256 increments wrap an 8 bit cell back to 0, so only the wider cells enter the loop
+    let bf = format!("{}[[-]+.-]", "+".repeat(256));
+
+    for level in OptimizationLevel::ALL {
+        assert_eq!(
+            run(
+                &bf,
+                &[],
+                Config {
+                    optimization_level: level,
+                    max_iterations: 100000,
+                    ..Default::default()
+                }
+            ),
+            Ok(vec![])
+        );
+    }
+
+    for width in [CellWidth::U16, CellWidth::U32] {
+        assert_eq!(
+            run(
+                &bf,
+                &[],
+                Config {
+                    optimization_level: OptimizationLevel::O3,
+                    max_iterations: 100000,
+                    cell_width: width,
+                    ..Default::default()
+                }
+            ),
+            Ok(bytes(&[1]))
+        );
     }
 
-    // check that the brackets are balanced
-    let mut stack = vec![];
-    for c in bf.chars() {
-        if c == '[' {
-            stack.push(());
-        } else if c == ']' {
-            if let None = stack.pop() {
-                return random_bf();
+    // Print emits the lowest byte of a wide cell
+    let bf = format!("{}.[-]", "+".repeat(321));
+    assert_eq!(
+        run(
+            &bf,
+            &[],
+            Config {
+                optimization_level: OptimizationLevel::O2,
+                max_iterations: 100000,
+                cell_width: CellWidth::U16,
+                ..Default::default()
             }
+        ),
+        Ok(bytes(&[65]))
+    );
+}
+
+#[test]
+fn long_add_chains() {
+    // The optimizer keeps the whole sum of a chain in the Add, each cell width does its own wrapping
+    let bf = format!(">{}<{}>.<.", "+".repeat(300), "-".repeat(700));
+    assert!(optimize_o2(&bf)
+        .unwrap()
+        .contains(&IR::Add { x: 300, offset: 1 }));
+
+    for level in OptimizationLevel::ALL {
+        let config = |cell_width| Config {
+            optimization_level: level,
+            max_iterations: 100000,
+            cell_width,
+            ..Default::default()
+        };
+
+        // 300 wraps to 44 and -700 to 68
+        assert_eq!(
+            run(&bf, &[], config(CellWidth::U8)),
+            Ok(bytes(&[44, 68])),
+            "{level:?}"
+        );
+        assert_eq!(
+            test(&bf, [vec![]], [bytes(&[44, 68])], config(CellWidth::U16))[0].typ,
+            TestFailureType::NonZeroMemory {
+                memory: vec![65536 - 700, 300]
+            },
+            "{level:?}"
+        );
+    }
+}
+
+#[test]
+fn large_mul_constants() {
+    // A loop adding 1000 per iteration lowers to a Mul with y = 1000, 3 * 1000 wraps to 184
+    let bf = format!("+++[->{}<]>.", "+".repeat(1000));
+    assert!(optimize_o3(&bf)
+        .unwrap()
+        .iter()
+        .all(|i| !matches!(i, IR::Loop { .. })));
+    for o in optimize_all(&bf) {
+        let mut i: Interpreter = Interpreter::from(o.unwrap(), 100000);
+        assert_eq!(parts(i.run(&[])), (None, bytes(&[184])));
+    }
+
+    // Constants at the ends of i32 multiply in the cell's width without overflowing
+    for y in [i32::MAX, i32::MIN, -1] {
+        let ir = vec![
+            IR::Add { x: 255, offset: 0 },
+            IR::Mul { x: 1, y, offset: 0 },
+        ];
+        let expected = (255 * i64::from(y)).rem_euclid(1 << 32);
+
+        let mut i: Interpreter = Interpreter::from(ir.clone(), 1000);
+        assert_eq!(i.run(&[]).error, None);
+        assert_eq!(i.cell(1), Some(Wrapping(expected as u8)), "{y}");
+
+        let mut i: Interpreter<Wrapping<u32>> = Interpreter::from(ir, 1000);
+        assert_eq!(i.run(&[]).error, None);
+        assert_eq!(i.cell(1), Some(Wrapping(expected as u32)), "{y}");
+    }
+
+    // Merging adds whose sum leaves the range of i32 wraps, like the cells do
+    let add = |x| IR::Add { x, offset: 0 };
+    assert_eq!(
+        fold_adjacent(vec![add(i32::MAX), add(i32::MAX)]),
+        vec![add(-2)]
+    );
+    assert_eq!(
+        merge_updates(vec![
+            add(i32::MAX),
+            IR::Move { over: 1 },
+            IR::Move { over: -1 },
+            add(i32::MAX)
+        ]),
+        vec![add(-2)]
+    );
+    let ir = merge_updates(vec![IR::Clear { offset: 0 }, add(i32::MIN), add(i32::MIN)]);
+    assert_eq!(ir, vec![IR::Clear { offset: 0 }]);
+}
+
+#[test]
+fn cell_width_wrapping() {
+    // Underflow wraps to the maximum value of the chosen width
+    let failures = test(
+        "-",
+        vec![vec![]],
+        vec![vec![]],
+        Config {
+            optimization_level: OptimizationLevel::O3,
+            max_iterations: 100,
+            cell_width: CellWidth::U16,
+            ..Default::default()
+        },
+    );
+    assert_eq!(failures.len(), 1);
+    assert_eq!(
+        failures[0].typ,
+        TestFailureType::NonZeroMemory {
+            memory: vec![u16::MAX as u32]
         }
+    );
+
+    // Multiplication loops wrap modulo the chosen width as well
+    let bf = format!("{}[->{}<]>", "+".repeat(200), "+".repeat(100));
+    let mut interpreter: Interpreter<Wrapping<u32>> =
+        Interpreter::from(crate::parser::optimize_o3(&bf).unwrap(), 100000);
+    interpreter.run(&[]);
+    assert_eq!(
+        interpreter.return_shrinked_memory(),
+        vec![Wrapping(0), Wrapping(20000)]
+    );
+
+    let mut interpreter: Interpreter<Wrapping<u8>> =
+        Interpreter::from(crate::parser::optimize_o3(&bf).unwrap(), 100000);
+    interpreter.run(&[]);
+    assert_eq!(
+        interpreter.return_shrinked_memory(),
+        vec![Wrapping(0), Wrapping((20000 % 256) as u8)]
+    );
+}
+
+#[test]
+fn tape_modes() {
+    let program = optimize_o0(">>>>+.[-]<<<<").unwrap();
+
+    let mut fixed: Interpreter = Interpreter::with_tape(program.clone(), 1000, TapeMode::Fixed(4));
+    assert_eq!(
+        parts(fixed.run(&[])),
+        (Some(RunTimeError::OutOfBounds { index: 4 }), vec![])
+    );
+
+    let mut capped: Interpreter =
+        Interpreter::with_tape(program.clone(), 1000, TapeMode::Growing { max: Some(4) });
+    assert_eq!(
+        parts(capped.run(&[])),
+        (Some(RunTimeError::OutOfBounds { index: 4 }), vec![])
+    );
+
+    let mut growing: Interpreter =
+        Interpreter::with_tape(program, 1000, TapeMode::Growing { max: Some(5) });
+    assert_eq!(parts(growing.run(&[])), (None, bytes(&[1])));
+    assert_eq!(growing.get_pointer(), 0);
+    assert_eq!(growing.return_shrinked_memory(), vec![Wrapping(0)]);
+
+    // Growing without a limit can go well past the default fixed size
+    let program = optimize_o3("+[>+]").unwrap();
+    let mut unbounded: Interpreter =
+        Interpreter::with_tape(program, 1000000, TapeMode::Growing { max: None });
+    assert_eq!(
+        unbounded.run(&[]).error,
+        Some(RunTimeError::MaxIterationsExceeded)
+    );
+    assert!(unbounded.return_shrinked_memory().len() > 65536);
+
+    unbounded.reset();
+    assert_eq!(unbounded.return_shrinked_memory(), vec![Wrapping(0)]);
+
+    // A cell that was written and then zeroed again is left out, on either side of the tape
+    for tape in [TapeMode::Fixed(100), TapeMode::Bidirectional { max: None }] {
+        let program = optimize_o0("+>>>++<+>[-]<<<").unwrap();
+        let mut i: Interpreter = Interpreter::with_tape(program, 1000, tape);
+        i.run(&[]);
+        assert_eq!(
+            i.return_shrinked_memory(),
+            vec![Wrapping(1), Wrapping(0), Wrapping(1)]
+        );
     }
+    let program = optimize_o0("<<<+>>>-<<<[-]<+>+>>").unwrap();
+    let mut i: Interpreter =
+        Interpreter::with_tape(program, 1000, TapeMode::Bidirectional { max: None });
+    i.run(&[]);
+    assert_eq!(
+        i.return_shrinked_memory(),
+        vec![
+            Wrapping(1),
+            Wrapping(1),
+            Wrapping(0),
+            Wrapping(0),
+            Wrapping(255)
+        ]
+    );
+
+    // The error names the cell that was outside of the tape, after offsets were folded in
+    let mut fixed: Interpreter = Interpreter::from(optimize_o3("+>>[-]<<[<<+>>-]").unwrap(), 1000);
+    assert_eq!(
+        fixed.run(&[]).error,
+        Some(RunTimeError::OutOfBounds { index: -2 })
+    );
 
-    if stack.is_empty() {
-        bf
-    } else {
-        random_bf()
+    // A Mul reports its source before its destination
+    for (x, offset, index) in [(2, -2, -1), (1, 0, 2)] {
+        let program = vec![IR::Move { over: 1 }, IR::Mul { x, y: 1, offset }];
+        let mut fixed: Interpreter = Interpreter::with_tape(program, 1000, TapeMode::Fixed(2));
+        assert_eq!(
+            fixed.run(&[]).error,
+            Some(RunTimeError::OutOfBounds { index })
+        );
     }
 }
 
 #[test]
-fn many() {
-    loop {
-        let bf = random_bf();
-        println!("{}", &bf);
-        specific(&bf);
+fn config_tape() {
+    let config = |tape| Config {
+        tape,
+        max_iterations: 1000,
+        ..Default::default()
+    };
+
+    // The tape reaches run and test
+    let bf = format!("{}+.[-]{}", ">".repeat(29999), "<".repeat(29999));
+    assert_eq!(
+        run(&bf, &[], config(TapeMode::Fixed(30000))),
+        Ok(bytes(&[1]))
+    );
+    assert_eq!(
+        run(&bf, &[], config(TapeMode::Fixed(29999))),
+        Err(Either::Left(RunTimeError::OutOfBounds { index: 29999 }))
+    );
+
+    // Every case gets the same tape after the reset
+    let failures = test(
+        ">>+.",
+        vec![vec![], vec![]],
+        vec![vec![], vec![]],
+        config(TapeMode::Fixed(2)),
+    );
+    assert_eq!(
+        failures
+            .iter()
+            .map(|failure| (failure.case_index, failure.typ.clone()))
+            .filter(|(_, typ)| matches!(typ, TestFailureType::RunTimeError { .. }))
+            .collect::<Vec<_>>(),
+        vec![
+            (
+                0,
+                TestFailureType::RunTimeError {
+                    err: RunTimeError::OutOfBounds { index: 2 }
+                }
+            ),
+            (
+                1,
+                TestFailureType::RunTimeError {
+                    err: RunTimeError::OutOfBounds { index: 2 }
+                }
+            ),
+        ]
+    );
+}
+
+#[test]
+fn bidirectional_tape() {
+    let bf = "<<+++.>+<[->>++<<]>>>-<";
+    let optimizers = [optimize_o0, optimize_o1, optimize_o2, optimize_o3];
+
+    for optimizer in optimizers {
+        let program = optimizer(bf).unwrap();
+
+        let mut fixed: Interpreter = Interpreter::from(program.clone(), 1000);
+        assert_eq!(
+            fixed.run(&[]).error,
+            Some(RunTimeError::OutOfBounds { index: -2 })
+        );
+
+        let mut interpreter: Interpreter =
+            Interpreter::with_tape(program, 1000, TapeMode::Bidirectional { max: None });
+        assert_eq!(parts(interpreter.run(&[])), (None, bytes(&[3])));
+        assert_eq!(interpreter.get_pointer(), 0);
+        assert_eq!(
+            interpreter.return_shrinked_memory(),
+            vec![Wrapping(1), Wrapping(6), Wrapping(255)]
+        );
     }
+
+    // The limit applies to each side of the tape
+    let program = optimize_o0("<<+>>>+").unwrap();
+    let mut capped: Interpreter = Interpreter::with_tape(
+        program.clone(),
+        1000,
+        TapeMode::Bidirectional { max: Some(2) },
+    );
+    assert_eq!(parts(capped.run(&[])), (None, vec![]));
+    let mut capped: Interpreter =
+        Interpreter::with_tape(program, 1000, TapeMode::Bidirectional { max: Some(1) });
+    assert_eq!(
+        capped.run(&[]).error,
+        Some(RunTimeError::OutOfBounds { index: -2 })
+    );
 }
 
 #[test]
-fn one() {
-    let bf = ">++.+[+]+.><[].<";
-    println!("{}", &bf);
-    specific(&bf);
+fn circular_tape() {
+    // Off the left end onto cell 3, off the right end back onto cell 3 and a scan that wraps from cell 0 to cell 3
+    let bf = "<+++>>>>.>+<<<<<.[-]>>+>+<<[<]";
+    for optimizer in [optimize_o0, optimize_o1] {
+        let program = optimizer(bf).unwrap();
+
+        let mut fixed: Interpreter =
+            Interpreter::with_tape(program.clone(), 1000, TapeMode::Fixed(4));
+        assert_eq!(
+            fixed.run(&[]).error,
+            Some(RunTimeError::OutOfBounds { index: -1 })
+        );
+
+        let mut interpreter: Interpreter = Interpreter::builder()
+            .tape(TapeMode::Circular(4))
+            .strict_moves(true)
+            .build(program);
+        assert_eq!(parts(interpreter.run(&[])), (None, bytes(&[3, 3])));
+        assert_eq!(interpreter.get_pointer(), 3);
+        assert_eq!(interpreter.cell(3), Some(Wrapping(0)));
+        assert_eq!(interpreter.cell(-1), Some(Wrapping(0)));
+        assert_eq!(interpreter.cell(5), Some(Wrapping(1)));
+        assert_eq!(
+            interpreter.return_shrinked_memory(),
+            vec![Wrapping(1), Wrapping(1), Wrapping(1)]
+        );
+    }
+
+    // Config doesn't optimize past O1 for a circular tape. The final pointer is reported after wrapping, so a program
+    // that goes all the way around passes.
+    let config = Config {
+        tape: TapeMode::Circular(4),
+        ..Config::default()
+    };
+    assert_eq!(
+        parts(crate::run_outcome(bf, &[], config).unwrap()),
+        (None, bytes(&[3, 3]))
+    );
+    assert_eq!(test("+>>>>-", [vec![]], [vec![]], config), vec![]);
+    let failures = test(">>>>>+-", [vec![]], [vec![]], config);
+    assert_eq!(
+        failures.into_iter().map(|f| f.typ).collect::<Vec<_>>(),
+        vec![TestFailureType::NonZeroPointer { pointer: 1 }]
+    );
 }
 
-fn specific(bf: &str) {
-    let o0 = optimize_o0(&bf);
-    let o1 = optimize_o1(&bf);
-    let o2 = optimize_o2(&bf);
-    let o3 = optimize_o3(&bf);
+#[test]
+fn eof_behavior() {
+    let input = bytes(b"hello");
 
-    // Check that all parses have the same Optimizer error
-    if o0.is_err() {
-        assert_eq!(o0, o1);
-        assert_eq!(o0, o2);
-        assert_eq!(o0, o3);
+    for level in OptimizationLevel::ALL {
+        // cat, stopping at a 0 byte
+        assert_eq!(
+            run(
+                ",[.,]",
+                &input,
+                Config {
+                    optimization_level: level,
+                    max_iterations: 1000,
+                    eof: EofBehavior::Zero,
+                    ..Default::default()
+                }
+            ),
+            Ok(input.clone())
+        );
     }
 
-    // If there was an error we stop here
-    if o0.is_err() {
-        return;
+    for level in OptimizationLevel::ALL {
+        // cat, stopping at a -1 byte
+        assert_eq!(
+            run(
+                ",+[-.,+]",
+                &input,
+                Config {
+                    optimization_level: level,
+                    max_iterations: 1000,
+                    eof: EofBehavior::NegativeOne,
+                    ..Default::default()
+                }
+            ),
+            Ok(input.clone())
+        );
+    }
+
+    for level in OptimizationLevel::ALL {
+        // cat, stopping at a p, which is only read once the input ends
+        let config = |eof| Config {
+            optimization_level: level,
+            max_iterations: 100000,
+            eof,
+            ..Default::default()
+        };
+        let until_p = format!(
+            ",{sub}[{add}.,{sub}]",
+            sub = "-".repeat(112),
+            add = "+".repeat(112)
+        );
+        assert_eq!(
+            run(&until_p, &input, config(EofBehavior::Value(b'p'))),
+            Ok(input.clone())
+        );
+        assert_eq!(
+            run(",.,.,.", &bytes(b"a"), config(EofBehavior::Value(0xff))),
+            Ok(bytes(&[b'a', 0xff, 0xff]))
+        );
+    }
+
+    for level in OptimizationLevel::ALL {
+        // Writes before a Read are kept when the Read leaves the cell unchanged
+        assert_eq!(
+            run(
+                "++>[-]<,.>+++,.",
+                &[],
+                Config {
+                    optimization_level: level,
+                    max_iterations: 1000,
+                    eof: EofBehavior::Unchanged,
+                    ..Default::default()
+                }
+            ),
+            Ok(bytes(&[2, 3]))
+        );
+    }
+
+    for width in [CellWidth::U8, CellWidth::U16, CellWidth::U32] {
+        assert_eq!(
+            run(
+                ",[.,]",
+                &input,
+                Config {
+                    optimization_level: OptimizationLevel::O3,
+                    max_iterations: 1000,
+                    cell_width: width,
+                    ..Default::default()
+                }
+            ),
+            Err(Either::Left(RunTimeError::OutOfInputs))
+        );
+    }
+
+    // -1 is the maximum value of the cell
+    let failures = test(
+        ",",
+        vec![vec![]],
+        vec![vec![]],
+        Config {
+            optimization_level: OptimizationLevel::O3,
+            max_iterations: 1000,
+            cell_width: CellWidth::U16,
+            eof: EofBehavior::NegativeOne,
+            ..Default::default()
+        },
+    );
+    assert_eq!(
+        failures[0].typ,
+        TestFailureType::NonZeroMemory {
+            memory: vec![u16::MAX as u32]
+        }
+    );
+}
+
+#[test]
+fn wide_cell_encoding() {
+    let config = |cell_width, encoding, level| Config {
+        optimization_level: level,
+        max_iterations: 1000,
+        cell_width,
+        encoding,
+        eof: EofBehavior::Zero,
+        ..Default::default()
+    };
+
+    for level in [
+        OptimizationLevel::O0,
+        OptimizationLevel::O1,
+        OptimizationLevel::O3,
+    ] {
+        let u16_le = config(CellWidth::U16, CellEncoding::LittleEndian, level);
+        let u16_be = config(CellWidth::U16, CellEncoding::BigEndian, level);
+
+        // A u16 round-trips through a Read and a Print, the merged Print repeats both bytes
+        assert_eq!(
+            run(",.", &bytes(&[0x34, 0x12]), u16_le),
+            Ok(bytes(&[0x34, 0x12]))
+        );
+        assert_eq!(
+            run(",..", &bytes(&[0x34, 0x12]), u16_be),
+            Ok(bytes(&[0x34, 0x12, 0x34, 0x12]))
+        );
+        // 0x0100 - 1 borrows from the high byte, so both bytes make up a single value
+        assert_eq!(
+            run(",-.", &bytes(&[0x00, 0x01]), u16_le),
+            Ok(bytes(&[0xff, 0x00]))
+        );
+        assert_eq!(
+            run(",-.", &bytes(&[0x01, 0x00]), u16_be),
+            Ok(bytes(&[0x00, 0xff]))
+        );
+        assert_eq!(
+            run(
+                ",+.",
+                &bytes(&[0xff, 0xff, 0x00, 0x00]),
+                config(CellWidth::U32, CellEncoding::LittleEndian, level)
+            ),
+            Ok(bytes(&[0x00, 0x00, 0x01, 0x00]))
+        );
+
+        // The end of the input before a value uses the EofBehavior, in the middle of one it is always an error
+        assert_eq!(run(",.", &[], u16_le), Ok(bytes(&[0, 0])));
+        assert_eq!(
+            run(",.", &bytes(&[0x34]), u16_le),
+            Err(Either::Left(RunTimeError::OutOfInputs))
+        );
+
+        // The default only reads and prints the low byte, 8-bit cells always do
+        assert_eq!(
+            run(
+                ",.",
+                &bytes(&[0x34, 0x12]),
+                config(CellWidth::U16, CellEncoding::LowByte, level)
+            ),
+            Ok(bytes(&[0x34]))
+        );
+        assert_eq!(
+            run(
+                ",.",
+                &bytes(&[0x34, 0x12]),
+                config(CellWidth::U8, CellEncoding::BigEndian, level)
+            ),
+            Ok(bytes(&[0x34]))
+        );
+    }
+}
+
+#[test]
+fn text_io() {
+    let hello = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.";
+    assert_eq!(
+        run_to_string(
+            hello,
+            &[],
+            Config {
+                optimization_level: OptimizationLevel::O2,
+                max_iterations: 100000,
+                ..Default::default()
+            }
+        ),
+        Ok("Hello World!".to_string())
+    );
+
+    let cat = ",[.,]";
+    assert_eq!(
+        run_str(
+            cat,
+            "héllo",
+            Config {
+                optimization_level: OptimizationLevel::O3,
+                max_iterations: 100000,
+                eof: EofBehavior::Zero,
+                ..Default::default()
+            }
+        ),
+        Ok(bytes("héllo".as_bytes()))
+    );
+
+    // Everything after the first `!` is input, even more commands
+    let config = Config {
+        max_iterations: 100000,
+        ..Default::default()
+    };
+    assert_eq!(run_with_inline_input(",.,.!AB", config), Ok(bytes(b"AB")));
+    assert_eq!(
+        run_with_inline_input(",.,.,.!+!.", config),
+        Ok(bytes(b"+!."))
+    );
+    assert_eq!(split_inline_input("+."), ("+.", ""));
+    assert_eq!(
+        run_with_inline_input(",.", config),
+        Err(Either::Left(RunTimeError::OutOfInputs))
+    );
+
+    // Verifying against O0 gives the same results as a plain run
+    let config = Config {
+        max_iterations: 100000,
+        ..Default::default()
+    };
+    assert_eq!(run_verified(hello, &[], config), Ok(bytes(b"Hello World!")));
+    assert_eq!(
+        run_verified(",.", &[], config),
+        Err(VerifyError::Failed(Either::Left(RunTimeError::OutOfInputs)))
+    );
+    assert_eq!(
+        run_verified("[", &[], config),
+        Err(VerifyError::Failed(Either::Right(
+            OptimizerError::UnbalancedBrackets {
+                position: 0,
+                kind: BracketKind::Unclosed
+            }
+        )))
+    );
+    // O0 can't finish, so there is nothing to compare against
+    assert_eq!(
+        run_verified(
+            &format!("{}[-]", "+".repeat(200)),
+            &[],
+            Config {
+                max_iterations: 300,
+                ..Default::default()
+            }
+        ),
+        Err(VerifyError::Failed(Either::Left(
+            RunTimeError::MaxIterationsExceeded
+        )))
+    );
+
+    assert_eq!(bytes_to_string(&bytes(b"abc")), "abc");
+    assert_eq!(bytes_to_string(&bytes("é".as_bytes())), "é");
+    assert_eq!(bytes_to_string(&bytes(&[b'a', 0xff])), "a\u{FFFD}");
+}
+
+#[test]
+fn profiling() {
+    let bf = "++[->+<]>.";
+
+    let (output, profile) = run_profiled(
+        bf,
+        &[],
+        Config {
+            optimization_level: OptimizationLevel::O0,
+            max_iterations: 1000,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    assert_eq!(output, bytes(&[2]));
+    // 5 top level instructions, 3 loop condition checks and 2 iterations of a 4 instruction body
+    assert_eq!(profile.iterations, 16);
+    assert_eq!(profile.instructions.get("Add"), Some(&6));
+    assert_eq!(profile.instructions.get("Move"), Some(&5));
+    assert_eq!(profile.instructions.get("Loop"), Some(&2));
+    assert_eq!(profile.instructions.get("Print"), Some(&1));
+
+    let (output, profile) = run_profiled(
+        bf,
+        &[],
+        Config {
+            optimization_level: OptimizationLevel::O3,
+            max_iterations: 1000,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    assert_eq!(output, bytes(&[2]));
+    // The counter is a known constant so the whole loop folds into constant writes
+    assert_eq!(profile.instructions.get("Loop"), None);
+    assert_eq!(profile.instructions.get("Mul"), None);
+    assert!(profile.iterations < 16);
+
+    // Profiling is opt-in and restarts on reset
+    let mut interpreter: Interpreter = Interpreter::from(optimize_o0(bf).unwrap(), 1000);
+    interpreter.run(&[]);
+    assert_eq!(interpreter.get_iterations(), 16);
+    assert_eq!(interpreter.get_profile(), None);
+
+    interpreter.reset();
+    interpreter.set_profiling(true);
+    interpreter.run(&[]);
+    assert_eq!(
+        interpreter.get_profile().unwrap().instructions.get("Add"),
+        Some(&6)
+    );
+    interpreter.reset();
+    assert_eq!(interpreter.get_profile().unwrap(), Default::default());
+}
+
+#[test]
+fn loop_profiling() {
+    // The first loop never runs, the second runs twice and the one inside it three times per iteration
+    let bf = "[-]++[>+++[->+<]<-]>>.";
+
+    let (output, profile) = run_profiled(
+        bf,
+        &[],
+        Config {
+            optimization_level: OptimizationLevel::O0,
+            max_iterations: 1000,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    assert_eq!(output, bytes(&[6]));
+    assert_eq!(profile.loops, vec![(LoopId(1), 2), (LoopId(2), 6)]);
+    assert_eq!(profile.instructions.get("Loop"), Some(&8));
+
+    // Ids map back to the IR and, at O0, to the source
+    let ir = optimize_o0(bf).unwrap();
+    assert_eq!(
+        loops(&ir),
+        vec![
+            LoopInfo { path: vec![0] },
+            LoopInfo { path: vec![3] },
+            LoopInfo { path: vec![3, 4] },
+        ]
+    );
+    assert_eq!(loop_positions(bf), vec![0, 5, 10]);
+
+    // Running, running flattened and stepping count the same loops
+    let bf = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.,[.,]";
+    let input = bytes(b"ab");
+    for level in OptimizationLevel::ALL {
+        let ir = optimize(bf, level).unwrap();
+        let interpreter = || -> Interpreter {
+            Interpreter::builder()
+                .max_iterations(100000)
+                .eof(EofBehavior::Zero)
+                .profiling(true)
+                .build(ir.clone())
+        };
+
+        let mut run = interpreter();
+        assert_eq!(run.run(&input).error, None);
+        let expected = run.get_profile().unwrap();
+        assert!(!expected.loops.is_empty());
+
+        let mut flat = interpreter();
+        flat.run_flat(&flatten(&ir), &mut input.iter().copied());
+        assert_eq!(flat.get_profile().unwrap(), expected);
+
+        let mut stepper = interpreter();
+        stepper.push_input(&input);
+        while let StepResult::Continue | StepResult::Output(_) = stepper.step() {}
+        assert_eq!(stepper.get_profile().unwrap(), expected);
     }
+}
+
+#[test]
+fn cell_stats() {
+    let stats = |reads, writes| CellStats { reads, writes };
+    let instrumented = |bf, level, input: &[u8]| {
+        let mut interpreter: Interpreter = Interpreter::from(optimize(bf, level).unwrap(), 1000);
+        let (outcome, stats) = interpreter.run_instrumented(&bytes(input));
+        assert_eq!(outcome.error, None, "{bf}");
+        stats
+    };
+
+    // The loop's cell is checked three times and changed twice in it, on top of the two adds before it
+    assert_eq!(
+        instrumented("++[>+<-]>.", OptimizationLevel::O0, &[]),
+        HashMap::from([(0, stats(3, 4)), (1, stats(1, 2))])
+    );
+    // A multiply loop reads its counter once, then clears it
+    assert_eq!(
+        instrumented(",[>++<-]>.", OptimizationLevel::O3, &[3]),
+        HashMap::from([(0, stats(1, 2)), (1, stats(1, 1))])
+    );
+    // A scan reads every cell it passes over, a merged Print only counts once
+    assert_eq!(
+        instrumented("+>+>>+<<<[>]<..", OptimizationLevel::O3, &[]),
+        HashMap::from([
+            (0, stats(1, 1)),
+            (1, stats(2, 1)),
+            (2, stats(1, 0)),
+            (3, stats(0, 1))
+        ])
+    );
 
-    let o0 = o0.unwrap();
-    let o1 = o1.unwrap();
-    let o2 = o2.unwrap();
-    let o3 = o3.unwrap();
+    // Off unless it is asked for, and a reset starts the counts over
+    let mut interpreter: Interpreter =
+        Interpreter::builder().build(optimize("+.", OptimizationLevel::O0).unwrap());
+    interpreter.run(&[]);
+    assert_eq!(interpreter.get_cell_stats(), None);
 
-    // Run all programs
-    let max_iterations = 1000000;
-    let mut i0 = Interpreter::from(o0.clone(), max_iterations);
-    let mut i1 = Interpreter::from(o1.clone(), max_iterations);
-    let mut i2 = Interpreter::from(o2.clone(), max_iterations);
-    let mut i3 = Interpreter::from(o3.clone(), max_iterations);
+    let mut interpreter: Interpreter = Interpreter::builder()
+        .cell_stats(true)
+        .build(optimize("+.", OptimizationLevel::O0).unwrap());
+    interpreter.run(&[]);
+    interpreter.run(&[]);
+    assert_eq!(
+        interpreter.get_cell_stats(),
+        Some(&HashMap::from([(0, stats(2, 2))]))
+    );
+    interpreter.reset();
+    assert_eq!(interpreter.get_cell_stats(), Some(&HashMap::new()));
+}
 
-    println!("O0 {:?}", o0);
-    println!("O1 {:?}", o1);
-    println!("O2 {:?}", o2);
-    println!("O3 {:?}", o3);
+#[test]
+fn test_optimizer_error() {
+    let failures = test(
+        "[",
+        vec![bytes(b"in")],
+        vec![bytes(b"out")],
+        Config {
+            optimization_level: OptimizationLevel::O3,
+            max_iterations: 1000,
+            ..Default::default()
+        },
+    );
+    assert_eq!(
+        failures,
+        vec![TestFailure {
+            typ: TestFailureType::OptimizerError(OptimizerError::UnbalancedBrackets {
+                position: 0,
+                kind: BracketKind::Unclosed
+            }),
+            case_index: 0,
+            input: bytes(b"in"),
+            expected_output: bytes(b"out"),
+            iterations: 0,
+        }]
+    );
 
-    // Generate an infinite stream of random inputs
-    let seed = thread_rng().gen::<u64>();
+    let failures = test(
+        "]",
+        vec![],
+        vec![],
+        Config {
+            optimization_level: OptimizationLevel::O0,
+            max_iterations: 1000,
+            ..Default::default()
+        },
+    );
+    assert_eq!(failures.len(), 1);
+    assert!(failures[0].input.is_empty() && failures[0].expected_output.is_empty());
+}
 
-    let inputs0 = {
-        let mut rng = ChaCha8Rng::seed_from_u64(seed);
-        (0..).map(move |_| rng.gen::<Wrapping<u8>>())
+#[test]
+fn test_suite() {
+    let config = Config {
+        max_iterations: 1000,
+        eof: EofBehavior::Zero,
+        ..Default::default()
     };
 
-    let inputs1 = {
-        let mut rng = ChaCha8Rng::seed_from_u64(seed);
-        (0..).map(move |_| rng.gen::<Wrapping<u8>>())
+    let mut suite = TestSuite::new();
+    suite
+        .add_case("cat", ",[.,]", vec![bytes(b"ab")], vec![bytes(b"ab")])
+        .add_case(
+            "double",
+            ",[->++<]>.[-]<",
+            vec![bytes(&[2]), bytes(&[3])],
+            vec![bytes(&[4]), bytes(&[7])],
+        )
+        .add_case("unbalanced", "[", vec![bytes(b"")], vec![bytes(b"")]);
+
+    let report = suite.run(config);
+    assert_eq!(report.passed(), 1);
+    assert_eq!(report.failed(), 2);
+    assert!(!report.all_passed());
+
+    // Each program is tested exactly like `test` would
+    for (name, bf, inputs, outputs) in [
+        ("cat", ",[.,]", vec![bytes(b"ab")], vec![bytes(b"ab")]),
+        (
+            "double",
+            ",[->++<]>.[-]<",
+            vec![bytes(&[2]), bytes(&[3])],
+            vec![bytes(&[4]), bytes(&[7])],
+        ),
+        ("unbalanced", "[", vec![bytes(b"")], vec![bytes(b"")]),
+    ] {
+        assert_eq!(
+            report.failures(name).unwrap(),
+            &test(bf, inputs, outputs, config)[..]
+        );
+    }
+    assert_eq!(report.failures("missing"), None);
+    assert_eq!(
+        report.iter().map(|(name, _)| name).collect::<Vec<_>>(),
+        vec!["cat", "double", "unbalanced"]
+    );
+
+    assert_eq!(
+        report.to_string(),
+        "1 of 3 programs passed
+double:
+    case 1: incorrect output: expected [7] got [6]
+unbalanced:
+    case 0: optimizer error: unclosed `[` at character 0"
+    );
+
+    assert!(TestSuite::new().run(config).all_passed());
+}
+
+#[test]
+fn unbalanced_bracket_positions() {
+    let error = |position, kind| Err(OptimizerError::UnbalancedBrackets { position, kind });
+
+    assert_eq!(optimize_o0("+[-]]"), error(4, BracketKind::UnexpectedClose));
+    assert_eq!(optimize_o0("]["), error(0, BracketKind::UnexpectedClose));
+    assert_eq!(optimize_o0("+[[-]"), error(1, BracketKind::Unclosed));
+    assert_eq!(optimize_o0("[>[<[]"), error(0, BracketKind::Unclosed));
+    assert_eq!(optimize_o3("++[>[<]"), error(2, BracketKind::Unclosed));
+}
+
+#[test]
+fn nesting_depth() {
+    let nested = |depth| format!("+{}-{}.", "[".repeat(depth), "]".repeat(depth));
+    let too_deep = |position, limit| OptimizerError::NestingTooDeep { position, limit };
+
+    // Far too deep programs fail instead of overflowing the stack
+    let bf = nested(100000);
+    assert_eq!(
+        parse(&bf),
+        Err(too_deep(1 + MAX_NESTING_DEPTH, MAX_NESTING_DEPTH))
+    );
+    for level in [OptimizationLevel::O0, OptimizationLevel::O3] {
+        let config = Config {
+            optimization_level: level,
+            ..Default::default()
+        };
+        assert_eq!(
+            run(&bf, &[], config),
+            Err(Either::Right(too_deep(
+                1 + MAX_NESTING_DEPTH,
+                MAX_NESTING_DEPTH
+            )))
+        );
+        assert_eq!(
+            test(&bf, vec![vec![]], vec![vec![]], config)[0].typ,
+            TestFailureType::OptimizerError(too_deep(1 + MAX_NESTING_DEPTH, MAX_NESTING_DEPTH))
+        );
+    }
+
+    // Every optimizer stops at the parser, before any pass recurses into the loops
+    let bf = nested(50000);
+    for level in OptimizationLevel::ALL {
+        assert_eq!(
+            optimize(&bf, level),
+            Err(too_deep(1 + MAX_NESTING_DEPTH, MAX_NESTING_DEPTH)),
+            "{level:?}"
+        );
+    }
+
+    // The deepest allowed program still optimizes and runs
+    let bf = nested(MAX_NESTING_DEPTH);
+    for level in OptimizationLevel::ALL {
+        let config = Config {
+            optimization_level: level,
+            ..Default::default()
+        };
+        assert_eq!(run(&bf, &[], config), Ok(bytes(&[0])), "{level:?}");
+    }
+
+    // The limit is configurable
+    assert_eq!(parse_with_max_depth("[[[]]]", 2), Err(too_deep(2, 2)));
+    assert!(parse_with_max_depth("[[]][[]]", 2).is_ok());
+    let config = Config {
+        max_nesting_depth: 2,
+        ..Default::default()
     };
+    assert_eq!(
+        run("[[[]]]", &[], config),
+        Err(Either::Right(too_deep(2, 2)))
+    );
+    assert_eq!(
+        run_verified("[[[]]]", &[], config),
+        Err(VerifyError::Failed(Either::Right(too_deep(2, 2))))
+    );
 
-    let inputs2 = {
-        let mut rng = ChaCha8Rng::seed_from_u64(seed);
-        (0..).map(move |_| rng.gen::<Wrapping<u8>>())
+    // run_verified parses its reference with the same limit
+    let config = Config {
+        max_nesting_depth: MAX_NESTING_DEPTH + 1,
+        ..Default::default()
     };
+    assert_eq!(
+        run_verified(&nested(MAX_NESTING_DEPTH + 1), &[], config),
+        Ok(bytes(&[0]))
+    );
+
+    assert_eq!(
+        too_deep(2, 2).to_string(),
+        "loops are nested deeper than 2 at character 2"
+    );
+}
+
+#[test]
+fn scan_zero() {
+    assert_eq!(
+        optimize_o3(">>+[<]+").unwrap(),
+        vec![
+            IR::Add { x: 1, offset: 2 },
+            IR::Move { over: 2 },
+            IR::ScanZero { step: -1 },
+            IR::Add { x: 1, offset: 0 },
+        ]
+    );
+
+    // Scans nested in loops keep the pointer consistent for the instructions after them
+    let hello = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.";
+    assert_eq!(
+        run_to_string(
+            hello,
+            &[],
+            Config {
+                optimization_level: OptimizationLevel::O3,
+                max_iterations: 100000,
+                ..Default::default()
+            }
+        ),
+        Ok("Hello World!".to_string())
+    );
+}
+
+// Builds a random balanced program out of small fragments, with a bias towards scan loops.
+fn random_scan_bf(rng: &mut impl Rng, depth: usize) -> String {
+    const FRAGMENTS: [&str; 10] = [
+        "+", "-", ">", "<", ".", "[>]", "[<]", "[-]", ">>+<", "[->+<]",
+    ];
+
+    let mut bf = String::new();
+    for _ in 0..rng.gen_range(0..12) {
+        if depth < 3 && rng.gen_bool(0.15) {
+            bf.push('[');
+            bf.push_str(&random_scan_bf(rng, depth + 1));
+            bf.push(']');
+        } else {
+            bf.push_str(FRAGMENTS[rng.gen_range(0..FRAGMENTS.len())]);
+        }
+    }
+    bf
+}
 
-    let inputs3 = {
-        let mut rng = ChaCha8Rng::seed_from_u64(seed);
-        (0..).map(move |_| rng.gen::<Wrapping<u8>>())
+#[test]
+fn passes_compose() {
+    // Every pass takes the output of every level, and the program still behaves like it did
+    let passes: [(&str, Pass); 9] = [
+        ("remove_zero_moves_and_adds", remove_zero_moves_and_adds),
+        ("fold_adjacent", fold_adjacent),
+        ("fold_adjacent_strict", fold_adjacent_strict),
+        ("merge_updates", merge_updates),
+        ("lower_loops", lower_loops),
+        ("merge_moves_into_offset", merge_moves_into_offset),
+        ("fold_constant_muls", fold_constant_muls),
+        ("remove_redundant_writes", remove_redundant_writes),
+        ("unroll_loops", unroll_loops),
+    ];
+    let tape = TapeMode::Bidirectional { max: None };
+    let run_ir = |ir: Vec<IR>, input: &[Wrapping<u8>]| {
+        let mut i: Interpreter = Interpreter::with_tape(ir, 100000, tape);
+        i.set_eof(EofBehavior::Zero);
+        let outcome = i.run(input);
+        (
+            outcome.error,
+            outcome.output,
+            i.get_pointer(),
+            i.return_shrinked_memory(),
+        )
     };
 
-    let (e0, r0) = i0.run_iter(inputs0);
-    let (e1, r1) = i1.run_iter(inputs1);
-    let (e2, r2) = i2.run_iter(inputs2);
-    let (e3, r3) = i3.run_iter(inputs3);
+    let o3 = optimize(",[->+<]>.", OptimizationLevel::O3).unwrap();
+    assert_eq!(
+        run_ir(merge_updates(o3.clone()), &bytes(&[7])),
+        run_ir(o3, &bytes(&[7]))
+    );
+
+    let mut rng = ChaCha8Rng::seed_from_u64(26);
+    for _ in 0..300 {
+        let bf = format!(",>,<{}", random_scan_bf(&mut rng, 0));
+        let input = bytes(&[rng.gen(), rng.gen(), rng.gen()]);
+        let expected = run_ir(optimize_o0(&bf).unwrap(), &input);
+        if expected.0.is_some() {
+            continue;
+        }
+
+        for level in OptimizationLevel::ALL {
+            let ir = optimize(&bf, level).unwrap();
+            for (name, pass) in passes {
+                assert_eq!(
+                    run_ir(pass(ir.clone()), &input),
+                    expected,
+                    "{name} on {bf} at {level:?}"
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn scan_zero_differential() {
+    let mut rng = ChaCha8Rng::seed_from_u64(9);
+
+    for _ in 0..2000 {
+        let bf = random_scan_bf(&mut rng, 0);
+        let tape = TapeMode::Bidirectional { max: None };
+
+        let mut o2: Interpreter = Interpreter::with_tape(optimize_o2(&bf).unwrap(), 10000, tape);
+        let mut o3: Interpreter = Interpreter::with_tape(optimize_o3(&bf).unwrap(), 10000, tape);
+        let RunOutcome {
+            error: e2,
+            output: r2,
+            ..
+        } = o2.run(&[]);
+        let RunOutcome {
+            error: e3,
+            output: r3,
+            ..
+        } = o3.run(&[]);
+
+        // O3 executes fewer instructions, so only programs that finish at O2 are comparable
+        if e2.is_none() {
+            assert_eq!(e3, None, "{bf}");
+            assert_eq!(r2, r3, "{bf}");
+            assert_eq!(o2.get_pointer(), o3.get_pointer(), "{bf}");
+            assert_eq!(
+                o2.return_shrinked_memory(),
+                o3.return_shrinked_memory(),
+                "{bf}"
+            );
+        }
+    }
+}
+
+#[test]
+fn out_of_inputs_differential() {
+    // Read heavy programs with inputs that are often too short, so the levels have to agree on where the input ran out
+    const FRAGMENTS: [&str; 10] = [
+        ",", ",", ".", ">", "<", "+", ",[.,]", ",[->+<]", ">,<", ",[-]",
+    ];
+    let mut rng = ChaCha8Rng::seed_from_u64(17);
+    let mut ran_out = 0;
+
+    for _ in 0..2000 {
+        let bf: String = (0..rng.gen_range(1..10))
+            .map(|_| FRAGMENTS[rng.gen_range(0..FRAGMENTS.len())])
+            .collect();
+        let input: Vec<u8> = (0..rng.gen_range(0..6)).map(|_| rng.gen()).collect();
+        let tape = TapeMode::Bidirectional { max: None };
+
+        let mut o0: Interpreter = Interpreter::with_tape(optimize_o0(&bf).unwrap(), 10000, tape);
+        let expected = parts(o0.run(&bytes(&input)));
+        match expected.0 {
+            Some(RunTimeError::MaxIterationsExceeded) => continue,
+            Some(RunTimeError::OutOfInputs) => ran_out += 1,
+            _ => {}
+        }
+
+        for level in [
+            OptimizationLevel::O1,
+            OptimizationLevel::O2,
+            OptimizationLevel::O3,
+            OptimizationLevel::O4,
+        ] {
+            let mut i: Interpreter =
+                Interpreter::with_tape(optimize(&bf, level).unwrap(), 10000, tape);
+            assert_eq!(
+                parts(i.run(&bytes(&input))),
+                expected,
+                "{bf} with {input:?} at {level:?}"
+            );
+        }
+    }
+
+    // Most of the programs need more input than they get
+    assert!(ran_out > 1000, "{ran_out}");
+}
+
+#[test]
+fn negative_offsets_differential() {
+    // Programs that go left of the starting cell first, so O2 and O3 fold the moves into negative offsets while O0
+    // keeps them
+    const FRAGMENTS: [&str; 12] = [
+        "<", "<", ">", "+", "-", ".", ",", "<+>", "<<.>>", "[-]", "[-<+>]", "[->>+<<]",
+    ];
+    let mut rng = ChaCha8Rng::seed_from_u64(21);
+    let mut folded = 0;
+
+    for _ in 0..2000 {
+        let bf: String = "<".repeat(rng.gen_range(1..4))
+            + &(0..rng.gen_range(1..12))
+                .map(|_| FRAGMENTS[rng.gen_range(0..FRAGMENTS.len())])
+                .collect::<String>();
+        let input: Vec<u8> = (0..4).map(|_| rng.gen()).collect();
+        let tape = TapeMode::Bidirectional { max: None };
+
+        let mut o0: Interpreter = Interpreter::with_tape(optimize_o0(&bf).unwrap(), 10000, tape);
+        let expected = parts(o0.run(&bytes(&input)));
+        if expected.0 == Some(RunTimeError::MaxIterationsExceeded) {
+            continue;
+        }
+
+        for level in [OptimizationLevel::O2, OptimizationLevel::O3] {
+            let ir = optimize(&bf, level).unwrap();
+            folded += usize::from(format_ir(&ir).contains("@-"));
 
-    if let Some(_) = e0 {
-        // Ensure all programs finished with the same error state
-        assert_eq!(e0, e1);
-        assert_eq!(e0, e2);
-        assert_eq!(e0, e3);
-    } else {
-        // Ensure all programs finished with the same output
-        assert_eq!(r0, r1);
-        assert_eq!(r0, r2);
-        assert_eq!(r0, r3);
+            let mut i: Interpreter = Interpreter::with_tape(ir, 10000, tape);
+            assert_eq!(parts(i.run(&bytes(&input))), expected, "{bf} at {level:?}");
+            assert_eq!(i.get_pointer(), o0.get_pointer(), "{bf} at {level:?}");
+            assert_eq!(
+                i.memory_window(0, 40),
+                o0.memory_window(0, 40),
+                "{bf} at {level:?}"
+            );
+        }
     }
+
+    // Most of the optimized programs address cells left of the pointer through an offset
+    assert!(folded > 2000, "{folded}");
+}
+
+#[test]
+fn final_pointer_differential() {
+    let mut rng = ChaCha8Rng::seed_from_u64(13);
+    let levels = [
+        OptimizationLevel::O1,
+        OptimizationLevel::O2,
+        OptimizationLevel::O3,
+        OptimizationLevel::O4,
+    ];
+
+    for _ in 0..2000 {
+        // Trailing moves leave most of the programs somewhere other than where they started
+        let moves = ['>', '<'];
+        let tail: String = (0..rng.gen_range(0..6))
+            .map(|_| moves[rng.gen_range(0..2)])
+            .collect();
+        let bf = format!(",{}{tail}", random_scan_bf(&mut rng, 0));
+        let input = bytes(&[rng.gen()]);
+        let tape = TapeMode::Bidirectional { max: None };
+
+        let mut o0: Interpreter = Interpreter::with_tape(optimize_o0(&bf).unwrap(), 10000, tape);
+        if o0.run(&input).error.is_some() {
+            continue;
+        }
+
+        let config = |optimization_level| Config {
+            optimization_level,
+            max_iterations: 10000,
+            tape,
+            ..Config::default()
+        };
+        let pointer_failures = |level| {
+            test(&bf, [input.clone()], [vec![]], config(level))
+                .into_iter()
+                .filter(|f| matches!(f.typ, TestFailureType::NonZeroPointer { .. }))
+                .map(|f| f.typ)
+                .collect::<Vec<_>>()
+        };
+        let expected = pointer_failures(OptimizationLevel::O0);
+
+        for level in levels {
+            let mut i: Interpreter =
+                Interpreter::with_tape(optimize(&bf, level).unwrap(), 10000, tape);
+            assert_eq!(
+                i.run(&input).final_pointer,
+                o0.get_pointer(),
+                "{bf} at {level:?}"
+            );
+            assert_eq!(pointer_failures(level), expected, "{bf} at {level:?}");
+        }
+    }
+}
+
+#[test]
+fn same_final_pointer() {
+    // Programs that end away from the starting cell in the ways the optimizer rewrites: pending offsets at the end of
+    // the program, moves folded into loops, scans and lowered multiply loops.
+    let cases: [(&str, &[u8]); 10] = [
+        (">", &[]),
+        ("<<+>", &[]),
+        ("+>++>+++<.", &[]),
+        (">+[->+<]>", &[]),
+        ("+[>+<-]>>", &[]),
+        (",[>]<", &[3]),
+        ("+>+>+<<[>]<<", &[]),
+        (">>+[<]>", &[]),
+        (",[->>+<<]>>[-<+>]<", &[5]),
+        ("+++[>+>[-]<<-]>>>.<", &[]),
+    ];
+    let levels = OptimizationLevel::ALL;
+    let tape = TapeMode::Bidirectional { max: None };
+
+    for (bf, input) in cases {
+        let pointers: Vec<i32> = levels
+            .iter()
+            .map(|&level| {
+                let mut i: Interpreter =
+                    Interpreter::with_tape(optimize(bf, level).unwrap(), 10000, tape);
+                assert_eq!(i.run(&bytes(input)).error, None, "{bf} at {level:?}");
+                i.get_pointer()
+            })
+            .collect();
+        assert!(
+            pointers.iter().all(|&p| p == pointers[0]),
+            "{bf}: {pointers:?}"
+        );
+    }
+}
+
+#[test]
+fn mul_loop_counters() {
+    // Decrementing and incrementing counters both lower to a Mul and a Clear
+    for (bf, y) in [(",[->++<]>.[-]<", 2), (",[+>++<]>.[-]<", -2)] {
+        assert_eq!(
+            optimize_o3(bf).unwrap(),
+            vec![
+                IR::Read { offset: 0 },
+                IR::Mul { x: 1, y, offset: 0 },
+                IR::Clear { offset: 0 },
+                IR::Print {
+                    times: 1,
+                    offset: 1
+                },
+                IR::Clear { offset: 1 },
+            ]
+        );
+    }
+
+    // An incrementing counter runs until it wraps around, so the result depends on the cell width
+    let cases = [
+        ("+++[->++<]>.[-]<", CellWidth::U8, 6),
+        ("+++[->++<]>.[-]<", CellWidth::U16, 6),
+        ("+++[+>++<]>.[-]<", CellWidth::U8, (253 * 2) % 256),
+        (
+            "+++[+>++<]>.[-]<",
+            CellWidth::U16,
+            ((65533 * 2) % 65536) % 256,
+        ),
+        ("+++[+>-<]>.[+]<", CellWidth::U8, 3),
+    ];
+
+    for (bf, width, expected) in cases {
+        for level in [OptimizationLevel::O0, OptimizationLevel::O3] {
+            assert_eq!(
+                run(
+                    bf,
+                    &[],
+                    Config {
+                        optimization_level: level,
+                        max_iterations: 1000000,
+                        cell_width: width,
+                        ..Default::default()
+                    }
+                ),
+                Ok(bytes(&[expected as u8])),
+                "{bf}"
+            );
+        }
+    }
+
+    // Counters that step by more than one are left as loops
+    assert!(matches!(
+        optimize_o3("++++[-->+<]").unwrap()[1],
+        IR::Loop { .. }
+    ));
+}
+
+#[test]
+fn multi_target_mul_loops() {
+    let add = |x, offset| IR::Add { x, offset };
+    let mul = |x, y, offset| IR::Mul { x, y, offset };
+
+    // Every target of the loop gets its own Mul, in the order of the body
+    assert_eq!(
+        lower_loops(vec![IR::Loop {
+            over: 2,
+            instructions: vec![add(-1, 0), add(1, 1), add(3, -2), add(-1, 4)],
+        }]),
+        vec![
+            mul(1, 1, 2),
+            mul(-2, 3, 2),
+            mul(4, -1, 2),
+            IR::Clear { offset: 2 },
+            IR::Move { over: 2 },
+        ]
+    );
+
+    // Adds to the same target add up, whether or not O2 merged them first
+    assert_eq!(
+        lower_loops(vec![IR::Loop {
+            over: 0,
+            instructions: vec![add(2, 1), add(-1, 0), add(3, 1)],
+        }]),
+        vec![
+            mul(1, 2, 0),
+            mul(1, 3, 0),
+            IR::Clear { offset: 0 },
+            IR::Move { over: 0 },
+        ]
+    );
+
+    // 2 and 3 target move loops, including targets on both sides and the counter away from the pointer
+    for (bf, targets) in [
+        (",[->+>+<<]>.>.<<", 2),
+        (">,[-<++>>+++>-<<]<.>>.>.<<<", 3),
+        (",[->+<>+<>>+<<]>.>.<<", 2),
+    ] {
+        let ir = optimize_o3(bf).unwrap();
+        let muls = ir.iter().filter(|i| matches!(i, IR::Mul { .. })).count();
+        assert_eq!(muls, targets, "{bf}");
+        assert!(!ir.iter().any(|i| matches!(i, IR::Loop { .. })), "{bf}");
+
+        for input in 0..=255 {
+            let config = |optimization_level| Config {
+                optimization_level,
+                max_iterations: 100000,
+                ..Default::default()
+            };
+            assert_eq!(
+                run(bf, &bytes(&[input]), config(OptimizationLevel::O3)),
+                run(bf, &bytes(&[input]), config(OptimizationLevel::O0)),
+                "{bf} on {input}"
+            );
+        }
+    }
+}
+
+#[test]
+fn decompile() {
+    assert_eq!(
+        to_brainfuck(&[
+            IR::Add { x: 3, offset: 1 },
+            IR::Exact { x: -1, offset: 0 },
+            IR::Print {
+                times: 2,
+                offset: -1
+            },
+            IR::Mul {
+                x: 1,
+                y: 2,
+                offset: 0
+            },
+            IR::Exact { x: 5, offset: 2 },
+            IR::Mul {
+                x: -1,
+                y: -1,
+                offset: 0
+            },
+            IR::Clear { offset: 0 },
+            IR::Loop {
+                over: 2,
+                instructions: vec![IR::Read { offset: 0 }, IR::ScanZero { step: -1 }],
+            },
+        ])
+        .unwrap(),
+        ">+++<[-]-<..>>>[-]+++++<<[->++<<->]>>[,[<]]"
+    );
+
+    // A Mul without the Clear of its source runs fine but can't be lowered, wherever it is
+    let mul = IR::Mul {
+        x: 1,
+        y: 3,
+        offset: 0,
+    };
+    let err = to_brainfuck(&[IR::Read { offset: 0 }, mul.clone()]).unwrap_err();
+    assert_eq!((err.path, err.instruction), (vec![1], mul.clone()));
+    // Nor can a write to a cell a Mul of the same group adds to, the order of the two matters
+    let ir = [
+        IR::Add { x: 3, offset: 0 },
+        mul.clone(),
+        IR::Clear { offset: 1 },
+        IR::Clear { offset: 0 },
+        IR::Print {
+            times: 1,
+            offset: 1,
+        },
+    ];
+    assert_eq!(validate_ir(&ir, OptimizationLevel::O3), Ok(()));
+    let mut i: Interpreter = Interpreter::from(ir.to_vec(), 100);
+    assert_eq!(parts(i.run(&[])), (None, bytes(&[0])));
+    let err = to_brainfuck(&ir).unwrap_err();
+    assert_eq!(
+        (err.path, err.instruction),
+        (vec![2], IR::Clear { offset: 1 })
+    );
+
+    // Writes to other cells do move in front of the loop
+    let ir = [
+        IR::Add { x: 3, offset: 0 },
+        mul.clone(),
+        IR::Clear { offset: 2 },
+        IR::Clear { offset: 0 },
+        IR::Print {
+            times: 1,
+            offset: 1,
+        },
+    ];
+    let lowered = to_brainfuck(&ir).unwrap();
+    let mut i: Interpreter = Interpreter::from(ir.to_vec(), 100);
+    assert_eq!(parts(i.run(&[])), (None, bytes(&[9])));
+    let mut i: Interpreter = Interpreter::from(optimize_o0(&lowered).unwrap(), 100);
+    assert_eq!(parts(i.run(&[])), (None, bytes(&[9])), "{lowered}");
+
+    let err = to_brainfuck(&[IR::Loop {
+        over: 0,
+        instructions: vec![mul.clone(), IR::Clear { offset: 1 }],
+    }])
+    .unwrap_err();
+    assert_eq!(err.path, vec![0, 0]);
+
+    // Lowering any optimized program and parsing it again gives a program with the same behavior
+    let mut rng = ChaCha8Rng::seed_from_u64(11);
+    let optimizers = [
+        optimize_o0,
+        optimize_o1,
+        optimize_o2,
+        optimize_o3,
+        optimize_o4,
+    ];
+
+    for _ in 0..1000 {
+        let bf = format!(",>,<{}", random_scan_bf(&mut rng, 0));
+        let input = bytes(&[rng.gen(), rng.gen(), rng.gen()]);
+        let tape = TapeMode::Bidirectional { max: None };
+
+        let mut original: Interpreter =
+            Interpreter::with_tape(optimize_o0(&bf).unwrap(), 10000, tape);
+        original.set_eof(EofBehavior::Zero);
+        let RunOutcome {
+            error: err,
+            output: expected,
+            ..
+        } = original.run(&input);
+        if err.is_some() {
+            continue;
+        }
+
+        for optimizer in optimizers {
+            let lowered = to_brainfuck(&optimizer(&bf).unwrap()).unwrap();
+            let mut interpreter: Interpreter =
+                Interpreter::with_tape(optimize_o0(&lowered).unwrap(), 100000, tape);
+            interpreter.set_eof(EofBehavior::Zero);
+
+            assert_eq!(
+                parts(interpreter.run(&input)),
+                (None, expected.clone()),
+                "{bf} {lowered}"
+            );
+            assert_eq!(
+                interpreter.get_pointer(),
+                original.get_pointer(),
+                "{bf} {lowered}"
+            );
+            assert_eq!(
+                interpreter.return_shrinked_memory(),
+                original.return_shrinked_memory(),
+                "{bf} {lowered}"
+            );
+        }
+    }
+
+    // The same holds for whole programs with nested loops and Muls
+    let hello = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.";
+    for optimizer in [optimize_o1, optimize_o2, optimize_o3, optimize_o4] {
+        let lowered = to_brainfuck(&optimizer(hello).unwrap()).unwrap();
+        let mut i: Interpreter = Interpreter::from(optimize_o0(&lowered).unwrap(), 100000);
+        assert_eq!(
+            parts(i.run(&[])),
+            (None, bytes(b"Hello World!")),
+            "{lowered}"
+        );
+    }
+}
+
+#[test]
+fn transpile_to_c() {
+    let c = to_c(&optimize_o1("+[>,.[-]<-]").unwrap(), "uint8_t");
+    assert!(c.contains("static uint8_t mem[65536];"));
+    assert!(c.contains("int main(void) {"));
+    assert_eq!(c.matches("while (mem[p])").count(), 1);
+    assert_eq!(c.matches("mem[p] = getchar();").count(), 1);
+    assert_eq!(c.matches("putchar(mem[p]);").count(), 1);
+    assert_eq!(c.matches("mem[p] = 0;").count(), 1);
+    assert_eq!(c.matches('{').count(), c.matches('}').count());
+
+    let c = to_c(
+        &[
+            IR::Add { x: -3, offset: -2 },
+            IR::Print {
+                times: 4,
+                offset: 1,
+            },
+            IR::Loop {
+                over: 2,
+                instructions: vec![
+                    IR::Mul {
+                        x: 1,
+                        y: 5,
+                        offset: 0,
+                    },
+                    IR::Clear { offset: 0 },
+                    IR::ScanZero { step: -1 },
+                ],
+            },
+        ],
+        "uint16_t",
+    );
+    assert!(c.contains("static uint16_t mem[65536];"));
+    assert!(c.contains("    mem[p-2] += -3;\n"));
+    assert!(c.contains("    for (int i = 0; i < 4; i++) putchar(mem[p+1]);\n"));
+    assert!(c.contains("    p += 2;\n    while (mem[p]) {\n"));
+    assert!(c.contains("        mem[p+1] += mem[p] * 5;\n"));
+    assert!(c.contains("        while (mem[p]) p += -1;\n    }\n"));
+    assert_eq!(c.matches("while (mem[p])").count(), 2);
+}
+
+#[test]
+fn listing() {
+    assert_eq!(format_ir(&[]), "");
+    assert_eq!(
+        format_ir(&optimize_o3(">+++[-<++>]<.,[>]").unwrap()),
+        "Exact x=6 @+0\n\
+         Clear @+1\n\
+         Print times=1 @+0\n\
+         Read @+0\n\
+         ScanZero step=+1\n"
+    );
+    assert_eq!(
+        format_ir(&[
+            IR::Move { over: -1 },
+            IR::Loop {
+                over: 2,
+                instructions: vec![
+                    IR::Mul {
+                        x: 1,
+                        y: 5,
+                        offset: -1,
+                    },
+                    IR::Loop {
+                        over: 0,
+                        instructions: vec![IR::Add { x: -1, offset: 0 }],
+                    },
+                    IR::Exact { x: 7, offset: 3 },
+                ],
+            },
+            IR::Print {
+                times: 2,
+                offset: -3,
+            },
+        ]),
+        "Move over=-1\n\
+         Loop @+2\n    \
+             Mul y=5 dst=+0 @-1\n    \
+             Loop @+0\n        \
+                 Add x=-1 @+0\n    \
+             Exact x=7 @+3\n\
+         Print times=2 @-3\n"
+    );
+}
+
+#[test]
+fn display_ir() {
+    let cases = [
+        (IR::Add { x: 3, offset: 2 }, "add +3 @2"),
+        (IR::Add { x: -1, offset: -1 }, "add -1 @-1"),
+        (IR::Move { over: 5 }, "mov >5"),
+        (IR::Move { over: -2 }, "mov <2"),
+        (
+            IR::Mul {
+                x: 1,
+                y: 2,
+                offset: 0,
+            },
+            "mul dst=+1 y=2 @0",
+        ),
+        (
+            IR::Mul {
+                x: -3,
+                y: -1,
+                offset: 1,
+            },
+            "mul dst=-2 y=-1 @1",
+        ),
+        (IR::Clear { offset: 0 }, "clr @0"),
+        (IR::Exact { x: 7, offset: 3 }, "set 7 @3"),
+        (
+            IR::Print {
+                times: 4,
+                offset: 1,
+            },
+            "prn x4 @1",
+        ),
+        (IR::Read { offset: 0 }, "rd @0"),
+        (IR::ScanZero { step: 1 }, "scan >1"),
+        (IR::ScanZero { step: -4 }, "scan <4"),
+        (IR::Debug { offset: -2 }, "dbg @-2"),
+        (
+            IR::Loop {
+                over: 2,
+                instructions: vec![],
+            },
+            "loop @2 {}",
+        ),
+        (
+            IR::Loop {
+                over: 0,
+                instructions: vec![
+                    IR::Add { x: -1, offset: 0 },
+                    IR::Loop {
+                        over: 1,
+                        instructions: vec![IR::Move { over: 1 }],
+                    },
+                ],
+            },
+            "loop @0 { add -1 @0; loop @1 { mov >1 } }",
+        ),
+    ];
+    for (ir, expected) in cases {
+        assert_eq!(ir.to_string(), expected);
+    }
+}
+
+#[test]
+fn step() {
+    let bf = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.,[.,]";
+    let input = bytes(b"ab");
+
+    for level in OptimizationLevel::ALL {
+        let ir = crate::optimize(bf, level).unwrap();
+        let mut expected: Interpreter = Interpreter::from(ir.clone(), 100000);
+        expected.set_eof(EofBehavior::Zero);
+        let RunOutcome {
+            error: err,
+            output: expected_output,
+            ..
+        } = expected.run(&input);
+        assert_eq!(err, None);
+
+        let mut stepper: Interpreter = Interpreter::from(ir, 100000);
+        stepper.set_eof(EofBehavior::Zero);
+        stepper.push_input(&input);
+        let mut output = Vec::new();
+        loop {
+            match stepper.step() {
+                StepResult::Done => break,
+                StepResult::Output(bytes) => output.extend(bytes),
+                StepResult::Continue => {}
+                StepResult::Error(err) => panic!("{level:?}: {err:?}"),
+            }
+        }
+
+        assert_eq!(output, expected_output);
+        assert_eq!(
+            stepper.return_shrinked_memory(),
+            expected.return_shrinked_memory()
+        );
+        assert_eq!(stepper.get_pointer(), expected.get_pointer());
+        assert_eq!(stepper.get_iterations(), expected.get_iterations());
+        assert_eq!(stepper.step(), StepResult::Done);
+    }
+
+    // The loop gets entered, the body runs once, then the condition is checked again
+    let mut i: Interpreter = Interpreter::from(optimize_o0("+[-]").unwrap(), 100);
+    assert_eq!(i.current_instruction(), Some(&IR::Add { x: 1, offset: 0 }));
+    assert_eq!(i.step(), StepResult::Continue);
+    assert_eq!(i.step(), StepResult::Continue);
+    assert_eq!(i.current_instruction(), Some(&IR::Add { x: -1, offset: 0 }));
+    assert_eq!(i.step(), StepResult::Continue);
+    assert_eq!(i.current_instruction(), None);
+    assert_eq!(i.step(), StepResult::Continue);
+    assert_eq!(i.step(), StepResult::Done);
+
+    let mut i: Interpreter = Interpreter::from(optimize_o0(",").unwrap(), 100);
+    assert_eq!(i.step(), StepResult::Error(RunTimeError::OutOfInputs));
+    i.push_input(&bytes(b"x"));
+    assert_eq!(i.step(), StepResult::Continue);
+    assert_eq!(i.return_shrinked_memory(), bytes(b"x"));
+
+    let mut i: Interpreter = Interpreter::from(optimize_o0("+[]").unwrap(), 10);
+    let result = std::iter::repeat_with(|| i.step()).find(|result| *result != StepResult::Continue);
+    assert_eq!(
+        result,
+        Some(StepResult::Error(RunTimeError::MaxIterationsExceeded))
+    );
+}
+
+#[test]
+fn breakpoints() {
+    // Indices: 0 +, 1 [, 2 -, 3 >, 4 +, 5 <, 6 >, 7 [, 8 -, 9 .
+    let ir = optimize_o0("+[->+<]>[-].").unwrap();
+    let mut i: Interpreter = Interpreter::from(ir, 1000);
+    assert_eq!(i.instruction_index(), Some(0));
+
+    let breakpoints = Breakpoints {
+        on_instruction: [4, 9].into(),
+        ..Default::default()
+    };
+    assert_eq!(
+        i.run_until_breakpoint(&breakpoints),
+        (Ok(Some(Breakpoint::Instruction(4))), vec![])
+    );
+    assert_eq!(i.get_pointer(), 1);
+    assert_eq!(
+        i.run_until_breakpoint(&breakpoints),
+        (Ok(Some(Breakpoint::Instruction(9))), vec![])
+    );
+    assert_eq!(
+        i.run_until_breakpoint(&breakpoints),
+        (Ok(None), bytes(&[0]))
+    );
+
+    let mut i: Interpreter = Interpreter::from(optimize_o0("+[->+<]>[-].").unwrap(), 1000);
+    let breakpoints = Breakpoints {
+        on_cell_change: [1].into(),
+        ..Default::default()
+    };
+    assert_eq!(
+        i.run_until_breakpoint(&breakpoints),
+        (Ok(Some(Breakpoint::CellChange(1))), vec![])
+    );
+    assert_eq!(i.instruction_index(), Some(5));
+    assert_eq!(i.return_shrinked_memory(), bytes(&[0, 1]));
+    assert_eq!(
+        i.run_until_breakpoint(&breakpoints),
+        (Ok(Some(Breakpoint::CellChange(1))), vec![])
+    );
+    assert_eq!(i.instruction_index(), None);
+    assert_eq!(
+        i.run_until_breakpoint(&breakpoints),
+        (Ok(None), bytes(&[0]))
+    );
+
+    let mut i: Interpreter = Interpreter::from(optimize_o0(">>><<<>>>").unwrap(), 1000);
+    let breakpoints = Breakpoints {
+        on_pointer: [2].into(),
+        ..Default::default()
+    };
+    assert_eq!(
+        i.run_until_breakpoint(&breakpoints),
+        (Ok(Some(Breakpoint::Pointer(2))), vec![])
+    );
+    assert_eq!(i.instruction_index(), Some(2));
+    assert_eq!(
+        i.run_until_breakpoint(&breakpoints),
+        (Ok(Some(Breakpoint::Pointer(2))), vec![])
+    );
+    assert_eq!(i.instruction_index(), Some(4));
+    assert_eq!(
+        i.run_until_breakpoint(&breakpoints),
+        (Ok(Some(Breakpoint::Pointer(2))), vec![])
+    );
+    assert_eq!(i.run_until_breakpoint(&breakpoints), (Ok(None), vec![]));
+
+    let mut i: Interpreter = Interpreter::from(optimize_o0("<+").unwrap(), 1000);
+    assert_eq!(
+        i.run_until_breakpoint(&Breakpoints::default()),
+        (Err(RunTimeError::OutOfBounds { index: -1 }), vec![])
+    );
+}
+
+#[test]
+fn memory_window() {
+    let mut i: Interpreter =
+        Interpreter::with_tape(optimize_o0("+>++>+++").unwrap(), 1000, TapeMode::Fixed(4));
+    assert_eq!(parts(i.run(&[])), (None, vec![]));
+
+    assert_eq!(i.cell(1), Some(Wrapping(2)));
+    assert_eq!(i.cell(3), Some(Wrapping(0)));
+    assert_eq!(i.cell(4), None);
+    assert_eq!(i.cell(-1), None);
+
+    assert_eq!(
+        i.memory_window(i.get_pointer(), 1),
+        vec![(1, Wrapping(2)), (2, Wrapping(3)), (3, Wrapping(0))]
+    );
+    assert_eq!(
+        i.memory_window(0, 2),
+        vec![(0, Wrapping(1)), (1, Wrapping(2)), (2, Wrapping(3))]
+    );
+    assert_eq!(i.memory_window(10, 2), vec![]);
+
+    let i: Interpreter<Wrapping<u16>> =
+        Interpreter::with_tape(vec![], 1000, TapeMode::Bidirectional { max: Some(1) });
+    assert_eq!(
+        i.memory_window(0, usize::MAX),
+        vec![(-1, Wrapping(0)), (0, Wrapping(0))]
+    );
+}
+
+#[test]
+fn run_outcome() {
+    // The output printed before the error is kept, along with where the run stopped
+    let mut i: Interpreter = Interpreter::from(optimize_o0("+.>+.>,").unwrap(), 1000);
+    let outcome = i.run(&[]);
+    assert_eq!(
+        outcome,
+        RunOutcome {
+            output: bytes(&[1, 1]),
+            error: Some(RunTimeError::OutOfInputs),
+            iterations: 7,
+            final_pointer: 2,
+            min_pointer: 0,
+            max_pointer: 2,
+        }
+    );
+    assert_eq!(outcome.iterations, i.get_iterations());
+    assert_eq!(outcome.final_pointer, i.get_pointer());
+
+    // A program that hangs still shows what it printed before running out of iterations, run only reports the error
+    let config = Config {
+        max_iterations: 1000,
+        ..Default::default()
+    };
+    for level in [OptimizationLevel::O0, OptimizationLevel::O3] {
+        let config = Config {
+            optimization_level: level,
+            ..config
+        };
+        let outcome = crate::run_outcome("+.>+..+[]", &[], config).unwrap();
+        assert_eq!(outcome.output, bytes(&[1, 1, 1]));
+        assert_eq!(outcome.error, Some(RunTimeError::MaxIterationsExceeded));
+        assert_eq!(
+            run("+.>+..+[]", &[], config),
+            Err(Either::Left(RunTimeError::MaxIterationsExceeded))
+        );
+    }
+
+    assert_eq!(
+        crate::run_outcome("[", &[], config),
+        Err(OptimizerError::UnbalancedBrackets {
+            position: 0,
+            kind: BracketKind::Unclosed
+        })
+    );
+}
+
+#[test]
+fn max_output() {
+    // A Print that repeats far more often than the limit only prints up to it, without building the rest
+    let program = vec![
+        IR::Add { x: 65, offset: 0 },
+        IR::Print {
+            times: usize::MAX,
+            offset: 0,
+        },
+    ];
+    let mut i: Interpreter = Interpreter::builder().max_output(5).build(program);
+    assert_eq!(
+        parts(i.run(&[])),
+        (Some(RunTimeError::OutputLimitExceeded), bytes(b"AAAAA"))
+    );
+
+    // The limit counts everything since the last reset, like the tape the second run carries over
+    let mut i: Interpreter = Interpreter::builder()
+        .max_output(3)
+        .build(optimize_o0("+.+.").unwrap());
+    assert_eq!(parts(i.run(&[])), (None, bytes(&[1, 2])));
+    assert_eq!(
+        parts(i.run(&[])),
+        (Some(RunTimeError::OutputLimitExceeded), bytes(&[3]))
+    );
+    i.reset();
+    assert_eq!(parts(i.run(&[])), (None, bytes(&[1, 2])));
+
+    // Every level stops at the same byte, even with the Prints merged
+    for level in [OptimizationLevel::O0, OptimizationLevel::O3] {
+        let config = Config {
+            optimization_level: level,
+            max_output: Some(4),
+            ..Default::default()
+        };
+        assert_eq!(
+            run("+..>++...", &[], config),
+            Err(Either::Left(RunTimeError::OutputLimitExceeded))
+        );
+        let outcome = crate::run_outcome("+..>++...", &[], config).unwrap();
+        assert_eq!(outcome.output, bytes(&[1, 1, 2, 2]));
+        assert_eq!(run("+..>++..", &[], config), Ok(bytes(&[1, 1, 2, 2])));
+    }
+
+    // A wide cell can be cut off halfway
+    let mut i = Interpreter::builder()
+        .cell_width::<Wrapping<u16>>()
+        .encoding(CellEncoding::LittleEndian)
+        .max_output(5)
+        .build(vec![
+            IR::Exact {
+                x: 0x0141,
+                offset: 0,
+            },
+            IR::Print {
+                times: 3,
+                offset: 0,
+            },
+        ]);
+    assert_eq!(
+        parts(i.run(&[])),
+        (
+            Some(RunTimeError::OutputLimitExceeded),
+            bytes(&[0x41, 0x01, 0x41, 0x01, 0x41])
+        )
+    );
+}
+
+#[test]
+fn print_iterations() {
+    // A merged Print costs as many iterations as the `.`s it replaces
+    for level in [
+        OptimizationLevel::O0,
+        OptimizationLevel::O1,
+        OptimizationLevel::O3,
+    ] {
+        let config = Config {
+            optimization_level: level,
+            ..Default::default()
+        };
+        let outcome = crate::run_outcome("+.....", &[], config).unwrap();
+        assert_eq!(outcome.iterations, 6);
+
+        // Running out of iterations stops in the middle of the Print, after the same bytes as O0
+        let config = Config {
+            max_iterations: 3,
+            ..config
+        };
+        let outcome = crate::run_outcome("+.....", &[], config).unwrap();
+        assert_eq!(outcome.output, bytes(&[1, 1]));
+        assert_eq!(outcome.error, Some(RunTimeError::MaxIterationsExceeded));
+        assert_eq!(outcome.iterations, 4);
+    }
+
+    // A Print that could never finish is stopped without printing more than the iterations allow
+    let mut i: Interpreter = Interpreter::from(
+        vec![IR::Print {
+            times: usize::MAX,
+            offset: 0,
+        }],
+        1000,
+    );
+    let outcome = i.run(&[]);
+    assert_eq!(outcome.output.len(), 1000);
+    assert_eq!(outcome.error, Some(RunTimeError::MaxIterationsExceeded));
+
+    // The output limit is hit first when it is the smaller of the two
+    let mut i: Interpreter = Interpreter::builder()
+        .max_iterations(1000)
+        .max_output(10)
+        .build(vec![IR::Print {
+            times: usize::MAX,
+            offset: 0,
+        }]);
+    assert_eq!(
+        parts(i.run(&[])),
+        (
+            Some(RunTimeError::OutputLimitExceeded),
+            vec![Wrapping(0); 10]
+        )
+    );
+}
+
+#[test]
+fn iteration_costs() {
+    assert_eq!(IR::Add { x: -300, offset: 2 }.cost(), 300);
+    assert_eq!(IR::Move { over: 7 }.cost(), 7);
+    assert_eq!(
+        IR::Print {
+            times: 12,
+            offset: 0
+        }
+        .cost(),
+        12
+    );
+    assert_eq!(
+        IR::Mul {
+            x: 1,
+            y: 5,
+            offset: 0
+        }
+        .cost(),
+        1
+    );
+    assert_eq!(IR::Clear { offset: 0 }.cost(), 1);
+
+    // Without loops every level that only merges instructions does the same amount of work as O0
+    let bf = "+++++>>>---<<.>,+++++++.";
+    let iterations = |level| {
+        let config = Config {
+            optimization_level: level,
+            ..Default::default()
+        };
+        crate::run_outcome(bf, &bytes(b"a"), config)
+            .unwrap()
+            .iterations
+    };
+    assert_eq!(iterations(OptimizationLevel::O0), 24);
+    assert_eq!(iterations(OptimizationLevel::O1), 24);
+
+    // A merged Add runs out of iterations like the `+`s it replaces
+    let mut i: Interpreter = Interpreter::from(vec![IR::Add { x: 1000, offset: 0 }], 999);
+    assert_eq!(i.run(&[]).error, Some(RunTimeError::MaxIterationsExceeded));
+    let mut i: Interpreter = Interpreter::from(vec![IR::Add { x: 1000, offset: 0 }], 1000);
+    assert_eq!(i.run(&[]).error, None);
+}
+
+#[test]
+fn builder() {
+    let program = optimize_o0("-,.").unwrap();
+
+    let mut i = Interpreter::builder()
+        .max_iterations(10)
+        .eof(EofBehavior::Unchanged)
+        .build(program.clone());
+    assert_eq!(parts(i.run(&[])), (None, bytes(&[255])));
+
+    let mut i = InterpreterBuilder::new()
+        .cell_width::<Wrapping<u16>>()
+        .tape(TapeMode::Growing { max: Some(4) })
+        .profiling(true)
+        .build(program.clone());
+    assert_eq!(parts(i.run(&bytes(b"a"))), (None, bytes(b"a")));
+    assert_eq!(i.return_shrinked_memory(), vec![Wrapping(97u16)]);
+    assert_eq!(i.get_profile().unwrap().instructions["Read"], 1);
+
+    let mut i = Interpreter::builder().max_iterations(2).build(program);
+    assert_eq!(
+        parts(i.run(&bytes(b"a"))),
+        (Some(RunTimeError::MaxIterationsExceeded), vec![])
+    );
+}
+
+#[test]
+fn classic() {
+    // Reading past the end of the input leaves the cell alone and cells wrap at 256
+    let mut i = Interpreter::classic(optimize_o0("+,-.").unwrap());
+    assert_eq!(parts(i.run(&[])), (None, bytes(&[0])));
+
+    // The last cell is 29999
+    let mut i = Interpreter::classic(optimize_o0(&(">".repeat(29999) + "+")).unwrap());
+    assert_eq!(parts(i.run(&[])), (None, vec![]));
+    let mut i = Interpreter::classic(optimize_o0(&(">".repeat(30000) + "+")).unwrap());
+    assert_eq!(
+        parts(i.run(&[])),
+        (Some(RunTimeError::OutOfBounds { index: 30000 }), vec![])
+    );
+
+    // There is no limit on the number of iterations
+    let mut i = Interpreter::classic(optimize_o0("-[>-[-]<-]").unwrap());
+    assert_eq!(parts(i.run(&[])), (None, vec![]));
+}
+
+#[test]
+fn comments() {
+    assert_eq!(
+        optimize_o0(">+ this is a comment <.").unwrap(),
+        optimize_o0(">+<.").unwrap()
+    );
+
+    let bf = "Prints the letter A using a multiplication loop
+        ++++++++ [ > ++++++++ < - ]   65 is 8 times 8 plus 1
+        > + .    then clear the cell again [-] <
+
+        \té ünïcödé is fine too";
+    for level in OptimizationLevel::ALL {
+        assert_eq!(
+            run(
+                bf,
+                &[],
+                Config {
+                    optimization_level: level,
+                    max_iterations: 10000,
+                    ..Default::default()
+                }
+            ),
+            Ok(bytes(b"A"))
+        );
+    }
+
+    // Brackets inside of comments still count
+    assert_eq!(
+        optimize_o0("a comment [ with a bracket"),
+        Err(OptimizerError::UnbalancedBrackets {
+            position: 10,
+            kind: BracketKind::Unclosed
+        })
+    );
+}
+
+#[test]
+fn o1_print_offsets() {
+    let print = |times, offset| IR::Print { times, offset };
+
+    assert_eq!(
+        o1_optimize_vec(&[print(1, 0), print(1, 1), print(2, 1), print(1, 0)], false),
+        vec![print(1, 0), print(3, 1), print(1, 0)]
+    );
+    assert_eq!(optimize_o1("..").unwrap(), vec![print(2, 0)]);
+}
+
+#[test]
+fn o2_read_offsets() {
+    let add = |x, offset| IR::Add { x, offset };
+    let read = |offset| IR::Read { offset };
+
+    // The Read is relative to the moves before it, only the behavior of the cell it reads is flushed
+    assert_eq!(
+        merge_updates(vec![
+            add(2, 0),
+            IR::Move { over: 1 },
+            add(3, 0),
+            read(-1),
+            add(1, 0),
+            IR::Move { over: -1 },
+            IR::Print {
+                times: 1,
+                offset: 0
+            },
+        ]),
+        vec![
+            add(2, 0),
+            read(0),
+            IR::Print {
+                times: 1,
+                offset: 0
+            },
+            add(4, 1),
+        ]
+    );
+    assert_eq!(
+        merge_updates(vec![add(1, 0), read(2), add(1, 0)]),
+        vec![read(2), add(2, 0)]
+    );
+}
+
+#[test]
+fn o2_zero_loops() {
+    let has_loop = |bf, level| {
+        optimize(bf, level)
+            .unwrap()
+            .iter()
+            .any(|i| matches!(i, IR::Loop { .. }))
+    };
+
+    for level in [
+        OptimizationLevel::O2,
+        OptimizationLevel::O3,
+        OptimizationLevel::O4,
+    ] {
+        // Adds that cancel out on a cell that starts at 0
+        assert!(!has_loop("+-[.]", level), "{level:?}");
+        assert!(!has_loop(">+<+->[-]<[.]", level), "{level:?}");
+        assert!(!has_loop(">+<[.]", level), "{level:?}");
+        assert!(!has_loop(",[-]+-[.]", level), "{level:?}");
+        // Cells that were read, flushed or aren't back at 0 may hold anything
+        assert!(has_loop(">+<-[.]", level), "{level:?}");
+        assert!(has_loop(",+-[.]", level), "{level:?}");
+        assert!(has_loop("+.-[.]", level), "{level:?}");
+        // After a loop nothing is known about the cells it didn't exit on
+        assert!(has_loop(",[>+<-]>-+<+-[.]>[.-]", level), "{level:?}");
+
+        // Print loops over a cell that was cleared or never written never run
+        assert!(!has_loop(",[-][.]", level), "{level:?}");
+        assert!(!has_loop(",[-]>+<[.]", level), "{level:?}");
+        assert!(!has_loop(",>[.]", level), "{level:?}");
+        assert!(!has_loop(",>[-]>[-]<[.]", level), "{level:?}");
+        assert!(!has_loop(",[-]>,<[..]", level), "{level:?}");
+        assert!(!has_loop(",[-]>[.]", level), "{level:?}");
+        // The loop over the cell that was read stays
+        assert_eq!(
+            optimize(",>[.]<[.]", level).unwrap(),
+            vec![
+                IR::Read { offset: 0 },
+                IR::Loop {
+                    over: 0,
+                    instructions: vec![IR::Print {
+                        times: 1,
+                        offset: 0
+                    }]
+                }
+            ],
+            "{level:?}"
+        );
+    }
+    // O1 only removes loops right after a Clear or a loop
+    assert!(has_loop("+-[.]", OptimizationLevel::O1));
+
+    for bf in [
+        "+-[.]+.",
+        ">+<+->[-]<[.].",
+        "+.-[.]",
+        ",[>+<-]>-+<+-[.]>[.-]",
+    ] {
+        for level in [OptimizationLevel::O2, OptimizationLevel::O4] {
+            let o0 = Config {
+                optimization_level: OptimizationLevel::O0,
+                ..Config::default()
+            };
+            assert_eq!(
+                run(bf, &bytes(b"a"), o0),
+                run(
+                    bf,
+                    &bytes(b"a"),
+                    Config {
+                        optimization_level: level,
+                        ..Config::default()
+                    }
+                ),
+                "{bf}"
+            );
+        }
+    }
+}
+
+#[test]
+fn flat() {
+    assert_eq!(
+        flatten(&optimize_o0("+[>[-]<-].").unwrap()),
+        vec![
+            FlatOp::Op(IR::Add { x: 1, offset: 0 }),
+            FlatOp::JumpIfZero { over: 0, target: 9 },
+            FlatOp::Op(IR::Move { over: 1 }),
+            FlatOp::JumpIfZero { over: 0, target: 6 },
+            FlatOp::Op(IR::Add { x: -1, offset: 0 }),
+            FlatOp::JumpIfNonZero { target: 4 },
+            FlatOp::Op(IR::Move { over: -1 }),
+            FlatOp::Op(IR::Add { x: -1, offset: 0 }),
+            FlatOp::JumpIfNonZero { target: 2 },
+            FlatOp::Op(IR::Print {
+                times: 1,
+                offset: 0
+            }),
+        ]
+    );
+
+    // Deeply nested loops run without recursion
+    let depth = 10000;
+    let mut ir = vec![IR::Add { x: -1, offset: 0 }];
+    for _ in 0..depth {
+        ir = vec![IR::Loop {
+            over: 0,
+            instructions: ir,
+        }];
+    }
+    ir.insert(0, IR::Add { x: 1, offset: 0 });
+    ir.push(IR::Print {
+        times: 1,
+        offset: 0,
+    });
+
+    let ops = flatten(&ir);
+    assert_eq!(ops.len(), 2 * depth + 3);
+    let mut i: Interpreter = Interpreter::from(vec![], usize::MAX);
+    assert_eq!(
+        parts(i.run_flat(&ops, &mut std::iter::empty())),
+        (None, bytes(&[0]))
+    );
+}
+
+#[test]
+fn timeout() {
+    let config = Config {
+        optimization_level: OptimizationLevel::O0,
+        timeout: Some(Duration::from_millis(50)),
+        ..Default::default()
+    };
+
+    let start = Instant::now();
+    assert_eq!(
+        run("+[]", &[], config),
+        Err(Either::Left(RunTimeError::Timeout))
+    );
+    assert!(start.elapsed() < Duration::from_secs(5));
+
+    let failures = test("+[]", vec![vec![]], vec![vec![]], config);
+    assert_eq!(
+        failures[0].typ,
+        TestFailureType::RunTimeError {
+            err: RunTimeError::Timeout
+        }
+    );
+
+    // Programs that finish in time are unaffected
+    assert_eq!(run("+.-", &[], config), Ok(bytes(&[1])));
+
+    // The budget applies to every run separately, and the flat runner honors it too
+    let mut i = Interpreter::builder()
+        .timeout(Duration::from_millis(20))
+        .build(optimize_o0("+[]").unwrap());
+    assert_eq!(parts(i.run(&[])), (Some(RunTimeError::Timeout), vec![]));
+    i.reset();
+    assert_eq!(parts(i.run(&[])), (Some(RunTimeError::Timeout), vec![]));
+    i.reset();
+    let ops = flatten(&optimize_o0("+[]").unwrap());
+    assert_eq!(
+        parts(i.run_flat(&ops, &mut std::iter::empty())),
+        (Some(RunTimeError::Timeout), vec![])
+    );
+}
+
+#[test]
+fn plain_bytes() {
+    let config = Config {
+        max_iterations: 1000,
+        eof: EofBehavior::Zero,
+        ..Default::default()
+    };
+    assert_eq!(
+        run_bytes(",[.,]", b"ab".as_slice(), config),
+        Ok(b"ab".to_vec())
+    );
+    assert_eq!(run_bytes(",[.,]", vec![1, 2, 3], config), Ok(vec![1, 2, 3]));
+    assert_eq!(run_bytes("+.", "", config), Ok(vec![1]));
+
+    let failures = test_bytes(
+        ",.[-]",
+        vec![b"a".to_vec(), b"b".to_vec()],
+        vec![b"a".to_vec(), b"x".to_vec()],
+        config,
+    );
+    assert_eq!(failures.len(), 1);
+    let failure = &failures[0];
+    assert_eq!(failure.case_index(), 1);
+    assert_eq!(failure.input(), b"b");
+    assert_eq!(failure.expected_output(), b"x");
+    assert_eq!(
+        failure.typ(),
+        &TestFailureType::IncorrectOutput {
+            output: bytes(b"b")
+        }
+    );
+    assert_eq!(
+        failure.to_string(),
+        "case 1: incorrect output: expected [120] got [98]"
+    );
+
+    let failures = test_bytes("<[-]>+", vec![vec![]], vec![vec![]], config);
+    assert_eq!(
+        failures
+            .iter()
+            .map(TestFailure::to_string)
+            .collect::<Vec<_>>(),
+        vec!["case 0: run time error: cell -1 is outside of the tape"]
+    );
+    assert_eq!(
+        TestFailureType::NonZeroMemory { memory: vec![0, 1] }.to_string(),
+        "the memory ended as [0, 1] instead of as it started"
+    );
+    assert_eq!(
+        TestFailureType::OptimizerError(OptimizerError::UnbalancedBrackets {
+            position: 3,
+            kind: BracketKind::UnexpectedClose
+        })
+        .to_string(),
+        "optimizer error: unmatched `]` at character 3"
+    );
+}
+
+#[test]
+fn strict_moves() {
+    let bf = "<>>+.<";
+    for optimization_level in OptimizationLevel::ALL {
+        let config = |strict_moves| Config {
+            optimization_level,
+            strict_moves,
+            ..Default::default()
+        };
+        assert_eq!(run(bf, &[], config(false)), Ok(bytes(&[1])));
+        assert_eq!(
+            run(bf, &[], config(true)),
+            Err(Either::Left(RunTimeError::OutOfBounds { index: -1 }))
+        );
+        assert_eq!(run(">+.<>.", &[], config(true)), Ok(bytes(&[1, 1])));
+    }
+
+    // Opposite moves stay apart, moves the same way are still joined
+    assert_eq!(
+        fold_adjacent_strict(parse("<<<>>>>+[<<>]").unwrap()),
+        vec![
+            IR::Move { over: -3 },
+            IR::Move { over: 4 },
+            IR::Add { x: 1, offset: 0 },
+            IR::Loop {
+                over: 0,
+                instructions: vec![IR::Move { over: -2 }, IR::Move { over: 1 }],
+            },
+        ]
+    );
+
+    // Moving past the right edge fails too
+    let mut i: Interpreter = Interpreter::builder()
+        .tape(TapeMode::Fixed(2))
+        .strict_moves(true)
+        .build(optimize_o0(">><<").unwrap());
+    assert_eq!(
+        i.run(&[]).error,
+        Some(RunTimeError::OutOfBounds { index: 2 })
+    );
+}
+
+#[test]
+fn test_case_index() {
+    // Echoes a single byte, the second case expects the wrong output
+    let failures = test(
+        ",.[-]",
+        vec![bytes(b"a"), bytes(b"b"), bytes(b"c")],
+        vec![bytes(b"a"), bytes(b"x"), bytes(b"c")],
+        Config {
+            optimization_level: OptimizationLevel::O0,
+            max_iterations: 1000,
+            ..Default::default()
+        },
+    );
+    assert_eq!(
+        failures,
+        vec![TestFailure {
+            typ: TestFailureType::IncorrectOutput {
+                output: bytes(b"b")
+            },
+            case_index: 1,
+            input: bytes(b"b"),
+            expected_output: bytes(b"x"),
+            // The Read, the Print, entering the loop, two iterations per decrement and the final check
+            iterations: 3 + 2 * 98 + 1,
+        }]
+    );
+
+    let failures = test(
+        "+[]",
+        vec![vec![], vec![]],
+        vec![vec![], vec![]],
+        Config {
+            optimization_level: OptimizationLevel::O0,
+            max_iterations: 100,
+            ..Default::default()
+        },
+    );
+    assert!(failures.iter().all(|failure| failure.iterations == 101));
+    assert_eq!(
+        failures
+            .iter()
+            .map(|failure| failure.case_index)
+            .collect::<Vec<_>>(),
+        vec![0, 0, 1, 1]
+    );
+}
+
+#[test]
+fn reset_between_cases() {
+    let counter = |reset_between_cases| {
+        test(
+            "+.",
+            vec![vec![], vec![], vec![]],
+            vec![bytes(&[1]), bytes(&[2]), bytes(&[3])],
+            Config {
+                optimization_level: OptimizationLevel::O0,
+                reset_between_cases,
+                ..Default::default()
+            },
+        )
+        .into_iter()
+        .filter(|failure| matches!(failure.typ, TestFailureType::IncorrectOutput { .. }))
+        .map(|failure| (failure.case_index, failure.iterations))
+        .collect::<Vec<_>>()
+    };
+
+    // Each case starts from a blank tape
+    assert_eq!(counter(true), vec![(1, 2), (2, 2)]);
+    // The count carries over from one case into the next
+    assert_eq!(counter(false), vec![]);
+
+    // A grown tape is cleared in place and still reads as blank
+    let mut i: Interpreter = Interpreter::with_tape(
+        optimize_o0(">>>+<<<<-").unwrap(),
+        1000,
+        TapeMode::Bidirectional { max: None },
+    );
+    i.run(&[]);
+    i.reset();
+    assert_eq!(i.return_shrinked_memory(), vec![Wrapping(0)]);
+    assert_eq!(parts(i.run(&[])), (None, vec![]));
+}
+
+#[test]
+fn initial_memory() {
+    // Every run, including the ones after a reset, starts from the pattern
+    let mut i: Interpreter =
+        Interpreter::with_initial_memory(optimize_o0("+.>.>.").unwrap(), 1000, &bytes(&[5, 6]));
+    assert!(i.memory_is_initial());
+    assert_eq!(parts(i.run(&[])), (None, bytes(&[6, 6, 0])));
+    assert!(!i.memory_is_initial());
+    i.reset();
+    assert!(i.memory_is_initial());
+    assert_eq!(parts(i.run(&[])), (None, bytes(&[6, 6, 0])));
+
+    // The pattern is cut to the size of a fixed tape
+    let mut i: Interpreter = Interpreter::builder()
+        .tape(TapeMode::Fixed(2))
+        .build(vec![]);
+    i.set_initial_memory(&bytes(&[1, 2, 3]));
+    assert_eq!(i.return_shrinked_memory(), bytes(&[1, 2]));
+    assert_eq!(i.cell(2), None);
+
+    // Cells left of 0 have to be blank too
+    let mut i: Interpreter = Interpreter::builder()
+        .tape(TapeMode::Bidirectional { max: None })
+        .build(optimize_o0("<+>").unwrap());
+    i.set_initial_memory(&bytes(&[1]));
+    i.run(&[]);
+    assert!(!i.memory_is_initial());
+
+    // test checks that each case restores the pattern instead of clearing the tape
+    let config = Config {
+        optimization_level: OptimizationLevel::O0,
+        max_iterations: 1000,
+        ..Default::default()
+    };
+    let initial = bytes(&[1, 2]);
+    let cases = || (vec![vec![], vec![]], vec![bytes(&[1, 2]), bytes(&[1, 2])]);
+
+    let (inputs, outputs) = cases();
+    assert_eq!(
+        test_with_initial_memory(".>.<", &initial, inputs, outputs, config),
+        vec![]
+    );
+
+    let (inputs, outputs) = cases();
+    let failures = test_with_initial_memory(".>.[-]<", &initial, inputs, outputs, config);
+    assert_eq!(failures.len(), 2);
+    assert!(failures
+        .iter()
+        .all(|failure| failure.typ == TestFailureType::NonZeroMemory { memory: vec![1] }));
+
+    let (inputs, outputs) = cases();
+    assert_eq!(
+        test(".>.<", inputs, outputs, config).len(),
+        2,
+        "test starts from a blank tape"
+    );
+}
+
+#[test]
+fn initial_memory_differential() {
+    let levels = OptimizationLevel::ALL;
+    let failures = |bf: &str, initial: &[Wrapping<u8>], expected: Vec<Wrapping<u8>>, level| {
+        let config = Config {
+            optimization_level: level,
+            max_iterations: 10000,
+            tape: TapeMode::Bidirectional { max: None },
+            ..Default::default()
+        };
+        test_with_initial_memory(bf, initial, [vec![]], [expected], config)
+            .into_iter()
+            .map(|failure| failure.typ().clone())
+            .collect::<Vec<_>>()
+    };
+
+    // A leading `[-]` clears a cell that isn't zero to begin with
+    for level in levels {
+        assert_eq!(
+            failures("[-].", &bytes(&[5]), bytes(&[0]), level),
+            vec![TestFailureType::NonZeroMemory { memory: vec![0] }],
+            "{level:?}"
+        );
+    }
+
+    // Random programs on random tapes fail in the same ways at every level
+    let mut rng = ChaCha8Rng::seed_from_u64(52);
+    for _ in 0..1000 {
+        let bf = random_scan_bf(&mut rng, 0);
+        let initial: Vec<Wrapping<u8>> = (0..rng.gen_range(1..6))
+            .map(|_| Wrapping(rng.gen_range(0..4)))
+            .collect();
+
+        let mut reference: Interpreter = Interpreter::with_tape(
+            optimize_o0(&bf).unwrap(),
+            10000,
+            TapeMode::Bidirectional { max: None },
+        );
+        reference.set_initial_memory(&initial);
+        let outcome = reference.run(&[]);
+        if outcome.error.is_some() {
+            continue;
+        }
+
+        let expected = failures(&bf, &initial, outcome.output.clone(), OptimizationLevel::O0);
+        for level in levels {
+            assert_eq!(
+                failures(&bf, &initial, outcome.output.clone(), level),
+                expected,
+                "{bf} on {initial:?} at {level:?}"
+            );
+        }
+    }
+}
+
+#[test]
+fn constant_mul_folding() {
+    // The source of the Mul is known to be 3, so 6 is added to the destination directly. Nothing reads the source
+    // before it is cleared, so setting it to 3 goes away as well.
+    assert_eq!(
+        optimize_o3(">+++[-<++>]").unwrap(),
+        vec![
+            IR::Exact { x: 6, offset: 0 },
+            IR::Clear { offset: 1 },
+            IR::Move { over: 1 },
+        ]
+    );
+
+    // An unknown destination gets an Add, an unknown source stays a Mul
+    assert_eq!(
+        optimize_o3(",>+++[-<++>]").unwrap(),
+        vec![
+            IR::Read { offset: 0 },
+            IR::Add { x: 6, offset: 0 },
+            IR::Clear { offset: 1 },
+            IR::Move { over: 1 },
+        ]
+    );
+    assert_eq!(
+        optimize_o3(">,[-<++>]").unwrap(),
+        vec![
+            IR::Read { offset: 1 },
+            IR::Mul {
+                x: -1,
+                y: 2,
+                offset: 1
+            },
+            IR::Clear { offset: 1 },
+            IR::Move { over: 1 },
+        ]
+    );
+
+    // Nothing is known about the cells after a loop
+    assert!(optimize_o3("+[>]++[->+<]")
+        .unwrap()
+        .iter()
+        .any(|i| matches!(i, IR::Mul { .. })));
+
+    let mut rng = ChaCha8Rng::seed_from_u64(25);
+    for _ in 0..100 {
+        let bf = format!(
+            "{}[->{}<]>{}[-<{}>]<.>.",
+            "+".repeat(rng.gen_range(0..300)),
+            "+".repeat(rng.gen_range(0..300)),
+            "-".repeat(rng.gen_range(0..300)),
+            "-".repeat(rng.gen_range(0..300)),
+        );
+
+        for cell_width in [CellWidth::U8, CellWidth::U16] {
+            let config = |optimization_level| Config {
+                optimization_level,
+                max_iterations: 1000000,
+                cell_width,
+                ..Default::default()
+            };
+
+            // Wide cells can take too long to count down at O0
+            if let Ok(expected) = run(&bf, &[], config(OptimizationLevel::O0)) {
+                assert_eq!(
+                    run(&bf, &[], config(OptimizationLevel::O3)),
+                    Ok(expected),
+                    "{bf}"
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn infinite_loops() {
+    let paths = |bf: &str, level| {
+        find_infinite_loops(&optimize(bf, level).unwrap())
+            .iter()
+            .map(|l| l.path.clone())
+            .collect::<Vec<_>>()
+    };
+    for level in OptimizationLevel::ALL {
+        assert_eq!(paths("+[]", level), vec![vec![1]], "{level:?}");
+        assert_eq!(paths("+[>+<]", level).len(), 1, "{level:?}");
+        assert_eq!(paths(",[-]+[>]+[]", level).len(), 1, "{level:?}");
+        // Printing the cell doesn't change it
+        assert_eq!(paths("+[.]", level).len(), 1, "{level:?}");
+        assert_eq!(paths("+>+<[..>.<]", level).len(), 1, "{level:?}");
+
+        // The loop never runs, or its cell depends on the input or on a write inside the loop
+        for bf in [
+            "[]",
+            "++++++++[-]",
+            ",[]",
+            "+[-]",
+            "+[>]",
+            "+[,]",
+            "+[.-]",
+            "+[[-]]",
+            "+[>+]",
+        ] {
+            assert_eq!(paths(bf, level), Vec::<Vec<usize>>::new(), "{bf} {level:?}");
+        }
+    }
+
+    // Loops nested in a loop are found when their cell is set inside the same body
+    assert_eq!(paths(",[[-]+[]]", OptimizationLevel::O0), vec![vec![1, 2]]);
+
+    // 256 wraps to 0 in a byte cell, so the loop may not run at all
+    let bf = format!("{}[]", "+".repeat(256));
+    assert!(paths(&bf, OptimizationLevel::O1).is_empty());
+}
+
+#[test]
+fn offset_bounds() {
+    let bounds = |bf, level| static_offset_bounds(&optimize(bf, level).unwrap());
+
+    for level in [OptimizationLevel::O0, OptimizationLevel::O3] {
+        assert_eq!(bounds("", level), (Some(0), Some(0)));
+        assert_eq!(bounds(",>>.<<<,", level), (Some(-1), Some(2)));
+        assert_eq!(bounds(",[->+<]", level), (Some(0), Some(1)));
+        assert_eq!(bounds(",[>[-]<-<+>]", level), (Some(-1), Some(1)));
+        assert_eq!(bounds("++[>+++[>++<-]<-]>>.", level), (Some(0), Some(2)));
+
+        // Loops that drift are unbounded in the direction they drift
+        assert_eq!(bounds(",[>,]", level), (Some(0), None));
+        assert_eq!(bounds(",[<]>>.", level), (None, Some(2)));
+        assert_eq!(bounds(",[>>,[<<<,]]", level), (None, None));
+        assert_eq!(bounds(">,[<,>-]", level), (Some(0), Some(1)));
+    }
+}
+
+#[test]
+fn unrolling() {
+    let add = |x, offset| IR::Add { x, offset };
+    let print = |offset| IR::Print { times: 1, offset };
+
+    // A loop with a known count is unrolled and its copies collapse into direct writes
+    assert_eq!(
+        optimize_o4("+++[>++.<-]").unwrap(),
+        vec![
+            add(2, 1),
+            print(1),
+            add(2, 1),
+            print(1),
+            add(2, 1),
+            print(1)
+        ]
+    );
+    assert_eq!(
+        optimize_o4("+++++[>++<-]>.").unwrap(),
+        vec![add(10, 1), print(1), IR::Move { over: 1 }]
+    );
+
+    // Loops that depend on the input, count up, or never return the pointer are left alone
+    for bf in [",[>++.<-]", "+++[>++.<+]", "+++[>++.-]", "+++[>++.<,-]"] {
+        assert_eq!(optimize_o4(bf), optimize_o3(bf), "{bf}");
+    }
+
+    // So is a loop that would grow past the limit
+    let bf = "++++++++[>+.<-]";
+    let ir = optimize_o2(bf).unwrap();
+    assert_eq!(unroll_loops_up_to(ir.clone(), 23), ir);
+    assert!(!unroll_loops_up_to(ir.clone(), 24)
+        .iter()
+        .any(|i| matches!(i, IR::Loop { .. })));
+    assert!(
+        unroll_loops(optimize_o2(&format!("{}[>+.<-]", "+".repeat(UNROLL_LIMIT))).unwrap())
+            .iter()
+            .any(|i| matches!(i, IR::Loop { .. }))
+    );
+
+    // Unrolled programs behave like O0, including loops whose count comes from an earlier unrolled loop
+    let programs = [
+        "+++[>++.<-]>.",
+        "++[>+++<-]>[>+.<-]>.",
+        "+++++[>+>++<<-]>>[-<+>]<.",
+        ">++++[<+.>-]<.[-]",
+        "+++[>.>+++<<-]>>[<<+.>>-]",
+        "+++[>[-]++.<-]",
+    ];
+    for bf in programs {
+        for width in [CellWidth::U8, CellWidth::U16] {
+            let config = |optimization_level| Config {
+                optimization_level,
+                max_iterations: 100000,
+                cell_width: width,
+                ..Default::default()
+            };
+            assert_eq!(
+                run(bf, &[], config(OptimizationLevel::O4)),
+                run(bf, &[], config(OptimizationLevel::O0)),
+                "{bf}"
+            );
+        }
+        assert!(!optimize_o4(bf)
+            .unwrap()
+            .iter()
+            .any(|i| matches!(i, IR::Loop { .. })));
+
+        // The default limit is what O4 uses, and without unrolling O4 is O3
+        assert_eq!(
+            optimize_o4_with_limit(bf, UNROLL_LIMIT),
+            optimize_o4(bf),
+            "{bf}"
+        );
+        assert_eq!(optimize_o4_with_limit(bf, 0), optimize_o3(bf), "{bf}");
+    }
+
+    // The limit can be picked along with O4 itself
+    let has_loop = |unroll_limit| {
+        let config = Config {
+            optimization_level: OptimizationLevel::O4,
+            unroll_limit,
+            ..Default::default()
+        };
+        assert_eq!(run(bf, &[], config), Ok(bytes(&[1, 2, 3, 4, 5, 6, 7, 8])));
+        config
+            .optimize(bf)
+            .unwrap()
+            .iter()
+            .any(|i| matches!(i, IR::Loop { .. }))
+    };
+    assert!(has_loop(23));
+    assert!(!has_loop(24));
+    assert!(!has_loop(Config::default().unroll_limit));
+}
+
+#[test]
+fn min_reads() {
+    for level in [OptimizationLevel::O0, OptimizationLevel::O3] {
+        let reads = |bf| count_min_reads(&optimize(bf, level).unwrap());
+
+        assert_eq!(reads(""), Some(0));
+        assert_eq!(reads(",>,.<,"), Some(3));
+        // Loops that don't read don't matter
+        assert_eq!(reads(",[->+<],>[-]"), Some(2));
+        // A loop that reads could consume any amount of input
+        assert_eq!(reads(",[.,]"), None);
+        assert_eq!(reads(",[>[,]<-]"), None);
+    }
+
+    // The count is a lower bound on what a run consumes
+    let bf = ",>,<.";
+    let ir = optimize_o3(bf).unwrap();
+    assert_eq!(count_min_reads(&ir), Some(2));
+    let mut i: Interpreter = Interpreter::from(ir, 1000);
+    assert_eq!(i.run(&bytes(b"a")).error, Some(RunTimeError::OutOfInputs));
+}
+
+#[test]
+fn pure_programs() {
+    for level in [OptimizationLevel::O0, OptimizationLevel::O3] {
+        let pure = |bf| is_pure(&optimize(bf, level).unwrap());
+
+        assert!(pure(""));
+        assert!(pure("+++[->++<]>[>]"));
+        assert!(pure("+[>+[>+[-]<-]<-]"));
+        assert!(!pure("."));
+        assert!(!pure("+[>+[>,<-]<-]"));
+        assert!(!pure("+[>+[>+[.-]<-]<-]"));
+    }
+
+    // A loop that never runs is still I/O until a pass removes it
+    assert!(!is_pure(&optimize_o0("[.]").unwrap()));
+    assert!(is_pure(&optimize_o1("[.]").unwrap()));
+    assert!(is_pure(&parse_with_debug_markers("+#").unwrap()));
+
+    // A pure program only ever prints nothing
+    let bf = "++[>+++[>++<-]<-]>>";
+    assert!(is_pure(&optimize_o0(bf).unwrap()));
+    assert_eq!(run(bf, &[], Config::default()), Ok(vec![]));
+}
+
+#[test]
+fn custom_passes() {
+    let bf = ">+++[-<++>]<.";
+
+    // The presets are the same as the optimize_oN functions
+    for (level, expected) in [
+        (OptimizationLevel::O0, optimize_o0(bf)),
+        (OptimizationLevel::O1, optimize_o1(bf)),
+        (OptimizationLevel::O2, optimize_o2(bf)),
+        (OptimizationLevel::O3, optimize_o3(bf)),
+        (OptimizationLevel::O4, optimize_o4(bf)),
+    ] {
+        assert_eq!(optimize_with(bf, level.passes().iter().copied()), expected);
+    }
+
+    // Folding without lowering the multiply loop keeps the Loop
+    let folded = optimize_with(bf, [fold_adjacent as Pass, merge_updates]).unwrap();
+    assert!(folded.iter().any(|i| matches!(i, IR::Loop { .. })));
+    assert!(!folded.iter().any(|i| matches!(i, IR::Mul { .. })));
+
+    // Lowering without the constant fold (and the cleanups after it) keeps the Mul
+    let o3 = OptimizationLevel::O3.passes();
+    let lowered = optimize_with(bf, o3[..o3.len() - 3].iter().copied()).unwrap();
+    assert!(lowered.iter().any(|i| matches!(i, IR::Mul { .. })));
+
+    for ir in [folded, lowered] {
+        let mut i: Interpreter = Interpreter::from(ir, 1000);
+        assert_eq!(parts(i.run(&[])), (None, bytes(&[6])));
+    }
+
+    assert_eq!(parse("+-").unwrap().len(), 2);
+    assert_eq!(optimize_with("+-", []).unwrap(), parse("+-").unwrap());
+    assert!(optimize_with("[", OptimizationLevel::O3.passes().iter().copied()).is_err());
+}
+
+#[test]
+fn all_levels() {
+    let levels = OptimizationLevel::ALL;
+    for bf in [
+        "",
+        ">+++[-<++>]<.[-]>,[>]",
+        "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.",
+        "+[",
+        "]",
+    ] {
+        let all = optimize_all(bf);
+        for (ir, level) in all.into_iter().zip(levels) {
+            assert_eq!(ir, optimize(bf, level), "{bf} {level:?}");
+        }
+    }
+}
+
+#[test]
+fn staged_optimization() {
+    let bf = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.";
+    let stages = optimize_staged(bf).unwrap();
+    assert_eq!(stages.parsed, parse(bf).unwrap());
+
+    for level in OptimizationLevel::ALL {
+        assert_eq!(Ok(stages.level(level).to_vec()), optimize(bf, level));
+
+        // Every stage is what the passes up to it produce
+        let passes = level.passes();
+        let stages = &stages.passes[level as usize];
+        assert_eq!(stages.len(), passes.len());
+        for (ran, stage) in stages.iter().enumerate() {
+            let expected = run_passes(parse(bf).unwrap(), passes[..=ran].iter().copied());
+            assert_eq!(stage, &expected, "{level:?} after {} passes", ran + 1);
+        }
+    }
+
+    assert_eq!(
+        optimize_staged("+]"),
+        Err(OptimizerError::UnbalancedBrackets {
+            position: 1,
+            kind: BracketKind::UnexpectedClose
+        })
+    );
+}
+
+#[test]
+fn ir_invariants() {
+    let bf = ">+++[-<++>]<.[-]>,[>]";
+    let levels = OptimizationLevel::ALL;
+
+    // Every level holds what it and the levels above it allow, but not less optimized levels
+    for (index, &level) in levels.iter().enumerate() {
+        let ir = optimize(bf, level).unwrap();
+        for &other in &levels[index..] {
+            assert_eq!(validate_ir(&ir, other), Ok(()), "{level:?} as {other:?}");
+        }
+    }
+    assert!(validate_ir(&optimize_o1(bf).unwrap(), OptimizationLevel::O0).is_err());
+    assert!(validate_ir(&optimize_o3(bf).unwrap(), OptimizationLevel::O2).is_err());
+
+    // The error points at the first instruction that breaks an invariant
+    let ir = vec![
+        IR::Move { over: 1 },
+        IR::Loop {
+            over: 0,
+            instructions: vec![IR::Add { x: 1, offset: 2 }],
+        },
+    ];
+    let err = validate_ir(&ir, OptimizationLevel::O1).unwrap_err();
+    assert_eq!(
+        err,
+        InvalidIr {
+            path: vec![1, 0],
+            instruction: IR::Add { x: 1, offset: 2 },
+            reason: "O1 leaves every offset at 0",
+        }
+    );
+    assert_eq!(
+        err.to_string(),
+        "Add { x: 1, offset: 2 } at [1, 0]: O1 leaves every offset at 0"
+    );
+    assert_eq!(
+        validate_ir(&[IR::Add { x: 0, offset: 0 }], OptimizationLevel::O3)
+            .unwrap_err()
+            .reason,
+        "zero adds and moves are removed"
+    );
+    assert!(validate_ir(&ir, OptimizationLevel::O2).is_ok());
+}
+
+#[test]
+fn overflow() {
+    let config = |bf: &str, optimization_level, overflow| {
+        run(
+            bf,
+            &[],
+            Config {
+                optimization_level,
+                max_iterations: 100000,
+                overflow,
+                ..Default::default()
+            },
+        )
+    };
+
+    // Wrapping stays the default
+    assert_eq!(
+        config("-.+", OptimizationLevel::O0, Overflow::default()),
+        Ok(bytes(&[255]))
+    );
+
+    for level in [OptimizationLevel::O0, OptimizationLevel::O3] {
+        assert_eq!(
+            config(">-.+", level, Overflow::Error),
+            Err(Either::Left(RunTimeError::IntegerOverflow {
+                cell: 1,
+                value: -1
+            }))
+        );
+        assert_eq!(config("-.", level, Overflow::Saturate), Ok(bytes(&[0])));
+        assert_eq!(
+            config(
+                &format!("{}.[-]", "+".repeat(300)),
+                level,
+                Overflow::Saturate
+            ),
+            Ok(bytes(&[255]))
+        );
+    }
+
+    // The Adds would cancel out once merged, so every level runs them one by one to see the underflow
+    for level in OptimizationLevel::ALL {
+        assert_eq!(
+            config("-+", level, Overflow::Error),
+            Err(Either::Left(RunTimeError::IntegerOverflow {
+                cell: 0,
+                value: -1
+            })),
+            "{level:?}"
+        );
+    }
+    let verified = run_verified(
+        "-+",
+        &[],
+        Config {
+            overflow: Overflow::Error,
+            ..Default::default()
+        },
+    );
+    assert_eq!(
+        verified,
+        Err(VerifyError::Failed(Either::Left(
+            RunTimeError::IntegerOverflow { cell: 0, value: -1 }
+        )))
+    );
+
+    // A Mul overflows when the product doesn't fit, and the source is untouched when it fails
+    let ir = vec![
+        IR::Read { offset: 0 },
+        IR::Mul {
+            x: 1,
+            y: 3,
+            offset: 0,
+        },
+    ];
+    let mut i = Interpreter::builder()
+        .overflow(Overflow::Error)
+        .build(ir.clone());
+    assert_eq!(
+        parts(i.run(&bytes(&[100]))),
+        (
+            Some(RunTimeError::IntegerOverflow {
+                cell: 1,
+                value: 300
+            }),
+            vec![]
+        )
+    );
+    assert_eq!(i.return_shrinked_memory(), bytes(&[100]));
+
+    let mut i = Interpreter::builder()
+        .overflow(Overflow::Error)
+        .cell_width::<Wrapping<u16>>()
+        .build(ir.clone());
+    assert_eq!(parts(i.run(&bytes(&[100]))), (None, vec![]));
+    assert_eq!(
+        i.return_shrinked_memory(),
+        vec![Wrapping(100), Wrapping(300)]
+    );
+
+    let mut i = Interpreter::builder()
+        .overflow(Overflow::Saturate)
+        .build(ir);
+    assert_eq!(parts(i.run(&bytes(&[100]))), (None, vec![]));
+    assert_eq!(i.return_shrinked_memory(), bytes(&[100, 255]));
+
+    let mut i = Interpreter::builder()
+        .overflow(Overflow::Error)
+        .build(vec![IR::Exact { x: 256, offset: 0 }]);
+    assert_eq!(
+        i.run(&[]).error,
+        Some(RunTimeError::IntegerOverflow {
+            cell: 0,
+            value: 256
+        })
+    );
+}
+
+#[test]
+fn saturating_cells() {
+    let config = |overflow, optimization_level| Config {
+        optimization_level,
+        max_iterations: 100000,
+        overflow,
+        ..Default::default()
+    };
+    let at_max = format!("{}.+.", "+".repeat(255));
+    let past_max = format!("{}-.", "+".repeat(256));
+
+    for level in OptimizationLevel::ALL {
+        // A + at 255 stays 255 and a - at 0 stays 0
+        let saturate = config(Overflow::Saturate, level);
+        assert_eq!(run(&at_max, &[], saturate), Ok(bytes(&[255, 255])));
+        assert_eq!(run("-.+.", &[], saturate), Ok(bytes(&[0, 1])));
+
+        // Runs of Adds clamp on every single one, even without a Print in between to keep them apart
+        assert_eq!(run("-+.", &[], saturate), Ok(bytes(&[1])), "{level:?}");
+        assert_eq!(
+            run(&past_max, &[], saturate),
+            Ok(bytes(&[254])),
+            "{level:?}"
+        );
+
+        // Wrapping is unchanged
+        let wrap = config(Overflow::Wrap, level);
+        assert_eq!(run(&at_max, &[], wrap), Ok(bytes(&[255, 0])));
+        assert_eq!(run("-.+.", &[], wrap), Ok(bytes(&[255, 0])));
+        assert_eq!(run("-+.", &[], wrap), Ok(bytes(&[0])));
+        assert_eq!(run(&past_max, &[], wrap), Ok(bytes(&[255])));
+    }
+
+    // The add of a Mul saturates in both directions
+    let mul = |y| {
+        let mut i = Interpreter::builder()
+            .overflow(Overflow::Saturate)
+            .build(vec![
+                IR::Read { offset: 0 },
+                IR::Read { offset: 1 },
+                IR::Mul { x: 1, y, offset: 0 },
+            ]);
+        assert_eq!(i.run(&bytes(&[3, 250])).error, None);
+        i.cell(1).unwrap()
+    };
+    assert_eq!(mul(1), Wrapping(253));
+    assert_eq!(mul(2), Wrapping(255));
+    assert_eq!(mul(-100), Wrapping(0));
+}
+
+#[test]
+fn run_with_writer() {
+    let bf = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.,[.,]";
+    let mut i: Interpreter = Interpreter::from(optimize_o3(bf).unwrap(), 100000);
+    i.set_eof(EofBehavior::Zero);
+
+    let mut output = Vec::new();
+    assert_eq!(i.run_with_writer(&bytes(b"!"), &mut output), None);
+    assert_eq!(output, b"Hello World!!");
+
+    // Repeated prints are written out in full, every repeat costs an iteration
+    let mut i: Interpreter = Interpreter::from(
+        vec![IR::Print {
+            times: 2000,
+            offset: 0,
+        }],
+        2000,
+    );
+    let mut output = Vec::new();
+    assert_eq!(i.run_with_writer(&[], &mut output), None);
+    assert_eq!(output, vec![0; 2000]);
+
+    // A full writer stops the program
+    let mut i: Interpreter = Interpreter::from(optimize_o0("+[.]").unwrap(), 100000);
+    let mut buffer = [0u8; 4];
+    let mut output = &mut buffer[..];
+    assert_eq!(
+        i.run_with_writer(&[], &mut output),
+        Some(RunTimeError::WriteFailed(std::io::ErrorKind::WriteZero))
+    );
+    assert_eq!(buffer, [1; 4]);
+
+    // Input from an iterator is only pulled as the program reads it
+    let pulled = std::cell::Cell::new(0);
+    let inputs = bytes(b"abcde")
+        .into_iter()
+        .inspect(|_| pulled.set(pulled.get() + 1));
+    let mut i: Interpreter = Interpreter::from(optimize_o3(",.,.").unwrap(), 100000);
+    let mut output = Vec::new();
+    assert_eq!(i.run_iter_with_writer(inputs, &mut output), None);
+    assert_eq!(output, b"ab");
+    assert_eq!(pulled.get(), 2);
+}
+
+#[test]
+fn run_with_callback() {
+    let prints = |ir: Vec<IR>, input: &[u8]| {
+        let mut prints = vec![];
+        let mut i: Interpreter = Interpreter::from(ir, 100000);
+        let err = i.run_with_callback(&bytes(input), |bytes| prints.push(bytes.to_vec()));
+        (err, prints)
+    };
+
+    // One call per Print, a repeated Print comes in a single call
+    let bf = "+..>++.";
+    assert_eq!(
+        prints(optimize_o0(bf).unwrap(), b""),
+        (None, vec![bytes(&[1]), bytes(&[1]), bytes(&[2])])
+    );
+    assert_eq!(
+        prints(optimize_o1(bf).unwrap(), b""),
+        (None, vec![bytes(&[1, 1]), bytes(&[2])])
+    );
+    let (err, long) = prints(
+        vec![IR::Print {
+            times: 2000,
+            offset: 0,
+        }],
+        b"",
+    );
+    assert_eq!((err, long.len(), long[0].len()), (None, 1, 2000));
+
+    // Everything printed before an error has been passed on
+    assert_eq!(
+        prints(optimize_o3(",.,.,.").unwrap(), b"ab"),
+        (
+            Some(RunTimeError::OutOfInputs),
+            vec![bytes(b"a"), bytes(b"b")]
+        )
+    );
+}
+
+#[test]
+fn tape_hash() {
+    let end_state = |bf: &str, tape| {
+        let mut i: Interpreter = Interpreter::with_tape(optimize_o0(bf).unwrap(), 100000, tape);
+        assert_eq!(i.run(&[]).error, None, "{bf}");
+        i
+    };
+    let fixed = TapeMode::Fixed(100);
+    let growing = TapeMode::Growing { max: None };
+    let bidirectional = TapeMode::Bidirectional { max: None };
+
+    // Programs that end in the same state hash the same, however they got there and whatever the tape
+    let hashes: Vec<u64> = [
+        ("++>+++", fixed),
+        ("+++[->+<]>[-<++>]<---->+++", fixed),
+        (">>>>>+[-]<<<<<++>+++", growing),
+        ("<+-<+[-]>>++>+++", bidirectional),
+    ]
+    .into_iter()
+    .map(|(bf, tape)| end_state(bf, tape).tape_hash())
+    .collect();
+    assert!(hashes.iter().all(|hash| *hash == hashes[0]), "{hashes:?}");
+
+    // A different pointer or a different cell doesn't
+    let states = ["", ">", "+", "+>+", "++>++", "<+>", "<<+>>", "+<+>"];
+    let hashes: HashSet<u64> = states
+        .iter()
+        .map(|bf| end_state(bf, bidirectional).tape_hash())
+        .collect();
+    assert_eq!(hashes.len(), states.len());
+
+    // A reset interpreter is back to the blank state
+    let mut i = end_state("+>++", fixed);
+    i.reset();
+    assert_eq!(i.tape_hash(), end_state("", fixed).tape_hash());
+}
+
+#[test]
+fn trace_events() {
+    let bf = ",>++[-<+>]<.>+++[->++<]";
+    let input = bytes(&[5]);
+
+    for level in [OptimizationLevel::O0, OptimizationLevel::O3] {
+        let program = optimize(bf, level).unwrap();
+        let ops = flatten(&program);
+
+        let mut plain: Interpreter = Interpreter::from(program.clone(), 1000);
+        let expected = plain.run(&input);
+
+        let mut traced: Interpreter = Interpreter::builder()
+            .max_iterations(1000)
+            .trace(TraceMode::All)
+            .build(program.clone());
+        let (outcome, trace) = traced.run_traced(&input);
+        assert_eq!(outcome, expected, "{level:?}");
+        assert!(!trace.is_empty());
+
+        // Every event names the op it ran, and picks up where the one before it left off
+        let mut pointer = 0;
+        let mut tape: HashMap<i32, Wrapping<u8>> = HashMap::new();
+        for event in &trace {
+            assert_eq!(ops[event.index], event.op, "{level:?}");
+            assert_eq!(event.pointer_before, pointer, "{level:?}");
+            pointer = event.pointer_after;
+            for &(index, old, new) in &event.changes {
+                assert_eq!(tape.get(&index).copied().unwrap_or_default(), old);
+                tape.insert(index, new);
+            }
+        }
+        assert_eq!(pointer, outcome.final_pointer);
+
+        // Replaying the changes rebuilds the tape, undoing them in reverse clears it again
+        let mut replayed: Vec<(i32, Wrapping<u8>)> = tape
+            .iter()
+            .map(|(i, c)| (*i, *c))
+            .filter(|(_, c)| c.0 != 0)
+            .collect();
+        replayed.sort();
+        assert_eq!(replayed, traced.changed_cells(), "{level:?}");
+        for event in trace.iter().rev() {
+            for &(index, old, _) in &event.changes {
+                tape.insert(index, old);
+            }
+        }
+        assert!(tape.values().all(|c| c.0 == 0));
+
+        // Last keeps the tail of the full trace
+        let mut last: Interpreter = Interpreter::from(program.clone(), 1000);
+        last.set_trace_mode(TraceMode::Last(3));
+        let (_, tail) = last.run_traced(&input);
+        assert_eq!(tail, trace[trace.len() - 3..], "{level:?}");
+
+        // Without a trace mode nothing is recorded
+        assert_eq!(plain.take_trace(), vec![]);
+    }
+
+    // A Mul records the cell it adds to
+    let mut i: Interpreter = Interpreter::from(optimize_o3(",[->++<]").unwrap(), 1000);
+    i.set_trace_mode(TraceMode::All);
+    let (_, trace) = i.run_traced(&bytes(&[3]));
+    let mul = trace
+        .iter()
+        .find(|event| matches!(event.op, FlatOp::Op(IR::Mul { .. })))
+        .unwrap();
+    assert_eq!(mul.changes, vec![(1, Wrapping(0), Wrapping(6))]);
+
+    // A failing instruction isn't recorded
+    let mut i: Interpreter = Interpreter::from(optimize_o0("+,").unwrap(), 1000);
+    i.set_trace_mode(TraceMode::All);
+    let (outcome, trace) = i.run_traced(&[]);
+    assert_eq!(outcome.error, Some(RunTimeError::OutOfInputs));
+    assert_eq!(trace.len(), 1);
+}
+
+#[test]
+fn run_with_trace() {
+    // Cells that end back at zero are left out, cells left of 0 come first
+    let program = optimize_o0("<<++>+>>+++>+-<<").unwrap();
+    let mut i: Interpreter =
+        Interpreter::with_tape(program, 1000, TapeMode::Bidirectional { max: None });
+    let (outcome, changed) = i.run_with_trace(&[]);
+    assert_eq!(parts(outcome), (None, vec![]));
+    assert_eq!(
+        changed,
+        vec![(-2, Wrapping(2)), (-1, Wrapping(1)), (1, Wrapping(3))]
+    );
+
+    // Changes are against the initial memory and start over after a reset
+    let program = optimize_o3(",[-]>+").unwrap();
+    let mut i: Interpreter = Interpreter::with_initial_memory(program, 1000, &bytes(&[7, 1]));
+    let (outcome, changed) = i.run_with_trace(&bytes(b"a"));
+    assert_eq!(
+        (outcome.error, changed),
+        (None, vec![(0, Wrapping(0)), (1, Wrapping(2))])
+    );
+    i.reset();
+    assert_eq!(i.changed_cells(), vec![]);
+}
+
+#[test]
+fn debug_markers() {
+    use std::{cell::RefCell, rc::Rc};
+
+    // `#` is only an instruction when asked for
+    assert_eq!(parse("+#").unwrap(), parse("+").unwrap());
+    let bf = "+>++#<[->+<]>#>+++#";
+    let ir = parse_with_debug_markers(bf).unwrap();
+    assert_eq!(ir[4], IR::Debug { offset: 0 });
+
+    // Every level shows the hook the same tape at the same cells
+    let dumps = |level: OptimizationLevel| {
+        let program = run_passes(ir.clone(), level.passes().iter().copied());
+        assert_eq!(validate_ir(&program, level), Ok(()));
+
+        let dumps = Rc::new(RefCell::new(vec![]));
+        let mut i: Interpreter = Interpreter::from(program, 1000);
+        let seen = Rc::clone(&dumps);
+        i.set_debug_hook(1, move |pointer, window| {
+            seen.borrow_mut().push((pointer, window.to_vec()))
+        });
+        assert_eq!(parts(i.run(&[])), (None, vec![]));
+        dumps.take()
+    };
+    let expected = vec![
+        (
+            1,
+            vec![(0, Wrapping(1)), (1, Wrapping(2)), (2, Wrapping(0))],
+        ),
+        (
+            1,
+            vec![(0, Wrapping(0)), (1, Wrapping(3)), (2, Wrapping(0))],
+        ),
+        (
+            2,
+            vec![(1, Wrapping(3)), (2, Wrapping(3)), (3, Wrapping(0))],
+        ),
+    ];
+    for level in OptimizationLevel::ALL {
+        assert_eq!(dumps(level), expected, "{level:?}");
+    }
+
+    // Without a hook it does nothing
+    let mut i: Interpreter = Interpreter::from(ir.clone(), 1000);
+    assert_eq!(parts(i.run(&[])), (None, vec![]));
+    assert_eq!(i.return_shrinked_memory(), bytes(&[0, 3, 3]));
+
+    let o2 = run_passes(ir, OptimizationLevel::O2.passes().iter().copied());
+    assert!(format_ir(&o2).contains("Debug @+1"));
+    assert!(to_brainfuck(&o2).unwrap().contains('#'));
+}
+
+#[test]
+fn assertions() {
+    // `{=n}` is only an instruction when asked for, anything close to it stays a comment
+    assert_eq!(parse("+{=1}").unwrap(), parse("+").unwrap());
+    assert_eq!(
+        parse_with_assertions("+{=1}").unwrap(),
+        vec![
+            IR::Add { x: 1, offset: 0 },
+            IR::Assert {
+                offset: 0,
+                expected: 1
+            }
+        ]
+    );
+    for bf in ["{=}", "{65}", "{= 65}", "{=65", "{=99999999999}"] {
+        assert_eq!(parse_with_assertions(bf).unwrap(), vec![], "{bf}");
+    }
+    assert_eq!(parse_with_assertions("{=6+}").unwrap(), parse("+").unwrap());
+
+    // Every level checks the same cells against the same values
+    let run_at = |bf: &str, level: OptimizationLevel| {
+        let ir = parse_with_assertions(bf).unwrap();
+        let program = run_passes(ir, level.passes().iter().copied());
+        assert_eq!(validate_ir(&program, level), Ok(()));
+        let mut i: Interpreter = Interpreter::from(program, 10000);
+        parts(i.run(&[]))
+    };
+    let passing = "{=0}++++++++[>++++++++<-]>+{=65}.<{=0}>>{=0}";
+    let failing = "++++++++[>++++++++<-]>+.>+<{=66}";
+    for level in OptimizationLevel::ALL {
+        assert_eq!(run_at(passing, level), (None, bytes(b"A")), "{level:?}");
+        assert_eq!(
+            run_at(failing, level),
+            (
+                Some(RunTimeError::AssertionFailed {
+                    offset: 1,
+                    expected: 66,
+                    actual: 65
+                }),
+                bytes(b"A")
+            ),
+            "{level:?}"
+        );
+    }
+    assert_eq!(
+        RunTimeError::AssertionFailed {
+            offset: 1,
+            expected: 66,
+            actual: 65
+        }
+        .to_string(),
+        "cell 1 holds 65 instead of 66"
+    );
+
+    // Written back out, the assertions parse again
+    let o3 = run_passes(
+        parse_with_assertions(passing).unwrap(),
+        OptimizationLevel::O3.passes().iter().copied(),
+    );
+    assert!(format_ir(&o3).contains("Assert expected=65 @+1"));
+    let bf = to_brainfuck(&o3).unwrap();
+    assert!(bf.contains("{=65}"));
+    assert_eq!(run_at(&bf, OptimizationLevel::O0), (None, bytes(b"A")));
+}
+
+#[test]
+fn shebang() {
+    // The shebang holds commands of its own, which aren't part of the program
+    let script = "#!/usr/bin/env -S bf -O3 --max-iterations 1000\n++++++++[>++++++++<-]>+.";
+    assert_eq!(parse(script).unwrap(), parse(&script[47..]).unwrap());
+    assert_eq!(
+        parse_with_debug_markers(script).unwrap(),
+        parse(&script[47..]).unwrap()
+    );
+    assert_eq!(run(script, &[], Config::default()), Ok(bytes(b"A")));
+    assert_eq!(parse_bytes(script.bytes()), parse(&script[47..]));
+    assert_eq!(
+        optimize_bytes(script.bytes(), OptimizationLevel::O3),
+        optimize(&script[47..], OptimizationLevel::O3)
+    );
+
+    // Positions still count the characters of the shebang
+    assert_eq!(
+        parse("#!bf\n+]"),
+        Err(OptimizerError::UnbalancedBrackets {
+            position: 6,
+            kind: BracketKind::UnexpectedClose
+        })
+    );
+    assert_eq!(parse_bytes(*b"#!bf\n+]"), parse("#!bf\n+]"));
+
+    // Only the first line can be a shebang, anywhere else `#!` is a debug marker and a comment
+    assert_eq!(parse("#!").unwrap(), vec![]);
+    assert_eq!(
+        parse_with_debug_markers("+\n#!-").unwrap(),
+        vec![
+            IR::Add { x: 1, offset: 0 },
+            IR::Debug { offset: 0 },
+            IR::Add { x: -1, offset: 0 }
+        ]
+    );
+}
+
+#[test]
+fn benchmark_levels() {
+    let bf = "++++++++[>++++++++<-]>+.+.+.[-]<";
+    let config = Config {
+        // Ignored, every level is timed
+        optimization_level: OptimizationLevel::O1,
+        max_iterations: 100000,
+        ..Default::default()
+    };
+    let timings = benchmark(bf, &[], config).unwrap();
+
+    let levels: Vec<_> = timings.levels.iter().map(|timing| timing.level).collect();
+    assert_eq!(levels, OptimizationLevel::ALL);
+    assert!(timings.levels.iter().all(|timing| timing.error.is_none()));
+    let iterations = |level| timings.get(level).unwrap().iterations;
+    assert!(iterations(OptimizationLevel::O3) < iterations(OptimizationLevel::O0));
+    let total = timings.get(OptimizationLevel::O0).unwrap().total();
+    assert!(timings.fastest().unwrap().total() <= total);
+
+    // Errors of the run are reported per level, errors of the optimizer fail the whole benchmark
+    let config = Config {
+        max_iterations: 10,
+        ..config
+    };
+    let timings = benchmark(bf, &[], config).unwrap();
+    assert_eq!(
+        timings.get(OptimizationLevel::O0).unwrap().error,
+        Some(RunTimeError::MaxIterationsExceeded)
+    );
+    assert!(benchmark("[", &[], config).is_err());
+}
+
+#[test]
+fn static_sizes() {
+    // 3 adds, the loop with its 5 commands, a move and a print
+    let bf = "+++[->++<]>.";
+    let report = optimization_report(bf).unwrap();
+    let nodes = |level| report.get(level).unwrap().nodes;
+    assert_eq!(nodes(OptimizationLevel::O0), 11);
+    // The adds and moves in the loop fold into one each
+    assert_eq!(nodes(OptimizationLevel::O1), 8);
+    // O3 lowers the loop and folds the constant, leaving Exact 6 @1, Clear @0, Move and Print
+    assert_eq!(nodes(OptimizationLevel::O3), 4);
+
+    assert_eq!(report.levels.len(), 5);
+    for window in report.levels.windows(2) {
+        assert!(window[1].nodes <= window[0].nodes, "{report:?}");
+    }
+    assert_eq!(
+        report.to_string(),
+        format!(
+            "O0: 11 nodes\nO1: 8 nodes (-27%)\nO2: {} nodes ({:+.0}%)\nO3: 4 nodes (-64%)\nO4: {} nodes ({:+.0}%)\n",
+            nodes(OptimizationLevel::O2),
+            (nodes(OptimizationLevel::O2) as f64 / 11.0 - 1.0) * 100.0,
+            nodes(OptimizationLevel::O4),
+            (nodes(OptimizationLevel::O4) as f64 / 11.0 - 1.0) * 100.0,
+        )
+    );
+
+    assert!(optimization_report("+]").is_err());
+    assert_eq!(
+        optimization_report("").unwrap().to_string(),
+        "O0: 0 nodes\nO1: 0 nodes\nO2: 0 nodes\nO3: 0 nodes\nO4: 0 nodes\n"
+    );
+}
+
+#[test]
+fn pointer_excursion() {
+    let tape = TapeMode::Bidirectional { max: None };
+    let bf = "<<+>>>>>.<<[-<+>]";
+
+    // Moves the optimizer folds into offsets still count
+    for optimizer in [optimize_o0, optimize_o1, optimize_o2, optimize_o3] {
+        let mut i: Interpreter = Interpreter::with_tape(optimizer(bf).unwrap(), 1000, tape);
+        let outcome = i.run(&[]);
+        assert_eq!(
+            (outcome.min_pointer, outcome.max_pointer),
+            (-2, 3),
+            "{:?}",
+            optimizer(bf)
+        );
+        assert_eq!((i.get_min_pointer(), i.get_max_pointer()), (-2, 3));
+
+        // The range carries over between runs until a reset
+        i.run(&[]);
+        assert_eq!(i.get_min_pointer(), -2);
+        i.reset();
+        assert_eq!((i.get_min_pointer(), i.get_max_pointer()), (0, 0));
+    }
+
+    // A scan reaches every cell it passes over
+    let mut i: Interpreter = Interpreter::from(optimize_o3("+>+>+>>+<<<<[>]").unwrap(), 1000);
+    assert_eq!(i.run(&[]).max_pointer, 4);
+}
+
+#[test]
+fn compiled_program() {
+    let bf = ",[.,]";
+    let config = Config {
+        eof: EofBehavior::Zero,
+        max_iterations: 1000,
+        ..Default::default()
+    };
+    let program = Program::compile(bf, config).unwrap();
+    assert_eq!(program.instructions(), &optimize_o3(bf).unwrap()[..]);
+
+    // Runs don't affect each other
+    for input in [&b"abc"[..], b"", b"hello"] {
+        assert_eq!(program.run(&bytes(input)), Ok(bytes(input)));
+    }
+    assert_eq!(
+        crate::run_outcome("+[]", &[], config).unwrap(),
+        Program::compile("+[]", config).unwrap().run_outcome(&[])
+    );
+    assert_eq!(
+        Program::compile("+[]", config).unwrap().run(&[]),
+        Err(RunTimeError::MaxIterationsExceeded)
+    );
+
+    // Testing gives the same failures as testing the source
+    let inputs = || vec![bytes(b"ab"), bytes(b"cd")];
+    let outputs = || vec![bytes(b"ab"), bytes(b"ce")];
+    assert_eq!(
+        program.test(inputs(), outputs()),
+        test(bf, inputs(), outputs(), config)
+    );
+    assert_eq!(program.test(inputs(), outputs()).len(), 1);
+
+    // The cell width comes from the config
+    let wide = Config {
+        cell_width: CellWidth::U16,
+        ..config
+    };
+    let program = Program::compile(&"+".repeat(300), wide).unwrap();
+    assert_eq!(
+        program.test([vec![]], [vec![]])[0].typ,
+        TestFailureType::NonZeroMemory { memory: vec![300] }
+    );
+
+    assert_eq!(
+        Program::compile("]", config),
+        Err(OptimizerError::UnbalancedBrackets {
+            position: 0,
+            kind: BracketKind::UnexpectedClose
+        })
+    );
+}
+
+#[test]
+fn run_reader() {
+    // Counts the bytes that have been pulled out of it, failing once it runs out
+    struct Source<'a> {
+        bytes: &'a [u8],
+        read: &'a std::cell::Cell<usize>,
+    }
+
+    impl std::io::Read for Source<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            match self.bytes.split_first() {
+                Some((byte, rest)) if !buf.is_empty() => {
+                    buf[0] = *byte;
+                    self.bytes = rest;
+                    self.read.set(self.read.get() + 1);
+                    Ok(1)
+                }
+                Some(_) => Ok(0),
+                None => Err(std::io::ErrorKind::BrokenPipe.into()),
+            }
+        }
+    }
+
+    let config = Config {
+        optimization_level: OptimizationLevel::O3,
+        max_iterations: 1000,
+        ..Default::default()
+    };
+
+    // Only the bytes the program reads are pulled from the reader
+    let read = std::cell::Cell::new(0);
+    let source = Source {
+        bytes: b"abcdef",
+        read: &read,
+    };
+    assert_eq!(
+        crate::run_reader(",.,.[-]", source, config),
+        Ok(bytes(b"ab"))
+    );
+    assert_eq!(read.get(), 2);
+
+    assert_eq!(
+        crate::run_reader(
+            ",[.,]",
+            &b"hello"[..],
+            Config {
+                eof: EofBehavior::Zero,
+                ..config
+            }
+        ),
+        Ok(bytes(b"hello"))
+    );
+    assert_eq!(
+        crate::run_reader(",", std::io::empty(), config),
+        Err(Either::Left(RunTimeError::OutOfInputs))
+    );
+
+    let source = Source {
+        bytes: b"a",
+        read: &read,
+    };
+    assert_eq!(
+        crate::run_reader(",.,.", source, config),
+        Err(Either::Left(RunTimeError::ReadFailed(
+            std::io::ErrorKind::BrokenPipe
+        )))
+    );
+}
+
+#[test]
+fn unordered_instructions() {
+    let add = |x, offset| IR::Add { x, offset };
+    let exact = |x, offset| IR::Exact { x, offset };
+
+    assert!(same_instructions(
+        &[add(1, 0), exact(2, 1), add(1, 0)],
+        &[add(1, 0), add(1, 0), exact(2, 1)]
+    ));
+    assert!(!same_instructions(&[add(1, 0), add(1, 0)], &[add(1, 0)]));
+    assert!(!same_instructions(&[add(1, 0)], &[add(1, 1)]));
+
+    assert!(same_instructions(
+        &optimize_o2("+>++>[-]+++<<").unwrap(),
+        &[exact(3, 2), add(1, 0), add(2, 1)]
+    ));
+}
+
+#[test]
+fn deterministic_o2() {
+    // Updates are flushed in order of their offset, before loops and at the end
+    assert_eq!(
+        optimize_o2(">>+<-<++[>>>+<<<-]>+>>[-]+<<<").unwrap(),
+        vec![
+            IR::Add { x: 2, offset: 0 },
+            IR::Add { x: -1, offset: 1 },
+            IR::Add { x: 1, offset: 2 },
+            IR::Loop {
+                over: 0,
+                instructions: vec![IR::Add { x: -1, offset: 0 }, IR::Add { x: 1, offset: 3 }]
+            },
+            IR::Add { x: 1, offset: 1 },
+            IR::Exact { x: 1, offset: 3 },
+        ]
+    );
+
+    let bf = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.,[.,]";
+    for optimizer in [optimize_o2, optimize_o3] {
+        let first = optimizer(bf);
+        for _ in 0..20 {
+            assert_eq!(optimizer(bf), first);
+        }
+    }
+}
+
+#[test]
+fn clears() {
+    for bf in ["+>[-]", "+>[+]"] {
+        let expected = vec![
+            IR::Add { x: 1, offset: 0 },
+            IR::Move { over: 1 },
+            IR::Clear { offset: 0 },
+        ];
+        assert_eq!(optimize_o1(bf).unwrap(), expected);
+
+        for optimizer in [optimize_o2, optimize_o3] {
+            assert_eq!(
+                optimizer(bf).unwrap(),
+                vec![
+                    IR::Add { x: 1, offset: 0 },
+                    IR::Clear { offset: 1 },
+                    IR::Move { over: 1 }
+                ]
+            );
+        }
+    }
+
+    // Mul loops end with a Clear of their counter
+    let ir = optimize_o3(",[->++<]").unwrap();
+    assert_eq!(ir.last(), Some(&IR::Clear { offset: 0 }));
+    assert_eq!(to_brainfuck(&ir).unwrap(), ",[->++<]");
+
+    // Clearing never overflows
+    let mut interpreter = Interpreter::<Wrapping<u8>>::builder()
+        .overflow(Overflow::Error)
+        .build(vec![IR::Add { x: 5, offset: 1 }, IR::Clear { offset: 1 }]);
+    assert_eq!(parts(interpreter.run(&[])), (None, vec![]));
+    assert_eq!(interpreter.cell(1), Some(Wrapping(0)));
+
+    let c = to_c(&[IR::Clear { offset: -2 }], "uint8_t");
+    assert!(c.contains("    mem[p-2] = 0;\n"));
+}
+
+#[test]
+fn redundant_writes() {
+    let clear = |offset| IR::Clear { offset };
+    let exact = |x, offset| IR::Exact { x, offset };
+    let print = |offset| IR::Print { times: 1, offset };
+
+    // Overwritten before anything reads it
+    assert_eq!(
+        remove_redundant_writes(vec![clear(1), exact(4, 2), exact(3, 1), print(1)]),
+        vec![exact(4, 2), exact(3, 1), print(1)]
+    );
+    // An Add only updates the cell, so it dies along with the writes before it
+    assert_eq!(
+        remove_redundant_writes(vec![
+            exact(3, 0),
+            IR::Add { x: 1, offset: 0 },
+            print(1),
+            IR::Add { x: 2, offset: 0 },
+            clear(0),
+        ]),
+        vec![print(1), clear(0)]
+    );
+    assert_eq!(
+        remove_redundant_writes(vec![IR::Add { x: 1, offset: 0 }, print(0), clear(0)]),
+        vec![IR::Add { x: 1, offset: 0 }, print(0), clear(0)]
+    );
+
+    // Already holds the constant
+    assert_eq!(
+        remove_redundant_writes(vec![exact(3, 0), IR::Add { x: -3, offset: 0 }, clear(0)]),
+        vec![exact(3, 0), IR::Add { x: -3, offset: 0 }]
+    );
+    // Offsets follow the pointer
+    assert_eq!(
+        remove_redundant_writes(vec![clear(1), IR::Move { over: 1 }, exact(2, 0)]),
+        vec![IR::Move { over: 1 }, exact(2, 0)]
+    );
+
+    // Anything that reads the cell keeps the write, including a Read that may leave it unchanged
+    for read in [
+        print(0),
+        IR::Read { offset: 0 },
+        IR::Mul {
+            x: 1,
+            y: 2,
+            offset: 0,
+        },
+        IR::Mul {
+            x: -1,
+            y: 2,
+            offset: 1,
+        },
+        IR::ScanZero { step: 1 },
+        IR::Loop {
+            over: 0,
+            instructions: vec![],
+        },
+        IR::Debug { offset: 4 },
+    ] {
+        let ir = vec![exact(3, 0), read.clone(), exact(5, 0)];
+        assert_eq!(remove_redundant_writes(ir.clone()), ir, "{read:?}");
+    }
+
+    // Loop bodies are handled on their own
+    assert_eq!(
+        remove_redundant_writes(vec![
+            clear(0),
+            IR::Loop {
+                over: 1,
+                instructions: vec![clear(0), clear(0)],
+            },
+            clear(0),
+        ]),
+        vec![
+            clear(0),
+            IR::Loop {
+                over: 1,
+                instructions: vec![clear(0)],
+            },
+            clear(0),
+        ]
+    );
+
+    // The Clear ending a Mul group stays, so the group still lowers
+    let ir = vec![
+        IR::Read { offset: 0 },
+        IR::Mul {
+            x: 1,
+            y: 2,
+            offset: 0,
+        },
+        clear(0),
+        clear(0),
+    ];
+    let removed = remove_redundant_writes(ir.clone());
+    assert_eq!(removed, ir[..3]);
+    assert_eq!(to_brainfuck(&removed).unwrap(), ",[->++<]");
+
+    // The counter is cleared by the lowered loop and then by the `+[-]` after it
+    assert_eq!(
+        optimize_o3(",[->+<]+[-]>.").unwrap(),
+        vec![
+            IR::Read { offset: 0 },
+            IR::Mul {
+                x: 1,
+                y: 1,
+                offset: 0
+            },
+            clear(0),
+            print(1),
+            IR::Move { over: 1 },
+        ]
+    );
+
+    // A folded Mul becomes an Exact that only meets the Exact after it once the loop has been lowered
+    let bf = "+++[->++<]>[-]+<>.";
+    let o3 = optimize_o3(bf).unwrap();
+    let writes = o3
+        .iter()
+        .filter(|i| matches!(i, IR::Exact { offset: 1, .. } | IR::Mul { .. }))
+        .count();
+    assert_eq!(writes, 1, "{o3:?}");
+    let mut i: Interpreter = Interpreter::from(o3, 1000);
+    assert_eq!(parts(i.run(&[])), (None, bytes(&[1])));
+
+    // Differential check against O0, with a Read that keeps the old value at the end of input
+    const FRAGMENTS: [&str; 13] = [
+        "+", "-", ">", "<", ".", ",", "[-]", "[-]+", "[-]++", "[->+<]", "[>]", "+[-]-", "+>.<[-]",
+    ];
+    let mut rng = ChaCha8Rng::seed_from_u64(41);
+    for _ in 0..2000 {
+        let mut bf = String::new();
+        for _ in 0..rng.gen_range(0..16) {
+            match rng.gen_range(0..10) {
+                0 => bf.push('['),
+                1 => bf.push(']'),
+                _ => bf.push_str(FRAGMENTS[rng.gen_range(0..FRAGMENTS.len())]),
+            }
+        }
+        let Ok(o0) = optimize_o0(&bf) else {
+            continue;
+        };
+        let input = bytes(&[rng.gen(), rng.gen()]);
+        let tape = TapeMode::Bidirectional { max: None };
+
+        let mut expected: Interpreter = Interpreter::with_tape(o0, 10000, tape);
+        expected.set_eof(EofBehavior::Unchanged);
+        let expected_outcome = expected.run(&input);
+        if expected_outcome.error.is_some() {
+            continue;
+        }
+
+        let o3 = optimize_o3(&bf).unwrap();
+        let mut actual: Interpreter = Interpreter::with_tape(o3.clone(), 10000, tape);
+        actual.set_eof(EofBehavior::Unchanged);
+        let actual_outcome = actual.run(&input);
+        assert_eq!(actual_outcome.error, None, "{bf} {o3:?}");
+        assert_eq!(actual_outcome.output, expected_outcome.output, "{bf}");
+        assert_eq!(
+            actual.return_shrinked_memory(),
+            expected.return_shrinked_memory(),
+            "{bf}"
+        );
+        assert_eq!(actual.get_pointer(), expected.get_pointer(), "{bf}");
+        assert!(to_brainfuck(&o3).is_ok(), "{bf}");
+    }
+}
+
+#[test]
+fn optimizer_log() {
+    let messages = |log: &OptimizerLog, pass: &str| -> Vec<String> {
+        log.pass(pass).map(|event| event.message.clone()).collect()
+    };
+
+    let (ir, log) = optimize_with_log("+[-][.].", OptimizationLevel::O1);
+    assert_eq!(ir, optimize("+[-][.].", OptimizationLevel::O1));
+    assert_eq!(
+        messages(&log, "fold_adjacent"),
+        [
+            "converted - into a Clear",
+            "removed a loop right after a Clear, which never runs",
+            "folded 6 instructions into 3",
+        ]
+    );
+
+    let (_, log) = optimize_with_log("[.]+.", OptimizationLevel::O1);
+    assert_eq!(
+        messages(&log, "fold_adjacent")[0],
+        "removed a loop at the program start, which never runs"
+    );
+
+    let (ir, log) = optimize_with_log("+++[->++<]>.,[>]", OptimizationLevel::O3);
+    assert_eq!(ir, optimize("+++[->++<]>.,[>]", OptimizationLevel::O3));
+    assert_eq!(
+        messages(&log, "lower_loops"),
+        [
+            "lowered a multiply loop at offset 0 into 1 Muls",
+            "lowered a scan loop with step 1 into a ScanZero",
+        ]
+    );
+    assert_eq!(
+        messages(&log, "fold_constant_muls"),
+        ["folded a Mul into offset 1 into a constant 6"]
+    );
+    assert!(log.pass("merge_moves_into_offset").next().is_some());
+
+    let (_, log) = optimize_with_log("+++[>+<-]>.", OptimizationLevel::O4);
+    assert_eq!(
+        messages(&log, "unroll_loops"),
+        ["unrolled a loop that runs 3 times"]
+    );
+    assert!(log.pass("lower_loops").next().is_none());
+
+    // O0 doesn't decide anything, and a program that doesn't parse has nothing to log
+    assert_eq!(
+        optimize_with_log("+-<>.", OptimizationLevel::O0).1,
+        OptimizerLog::default()
+    );
+    let (err, log) = optimize_with_log("[[]", OptimizationLevel::O3);
+    assert!(err.is_err());
+    assert!(log.events.is_empty());
+
+    // One line per event
+    let (_, log) = optimize_with_log("+[-][.].", OptimizationLevel::O1);
+    assert_eq!(log.to_string().lines().count(), log.events.len());
+    assert!(log
+        .to_string()
+        .starts_with("fold_adjacent: converted - into a Clear\n"));
+
+    // Logging stops with the call
+    optimize("+[-]", OptimizationLevel::O1).unwrap();
+    let (_, log) = optimize_with_log(".", OptimizationLevel::O1);
+    assert!(log.events.is_empty());
+}
+
+#[test]
+fn parse_from_bytes() {
+    let programs = [
+        "",
+        "+++[->++<]>.,[>]<<-",
+        "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.",
+        "comments # and ünicode [-] are ignored",
+    ];
+    for bf in programs {
+        assert_eq!(parse_bytes(bf.bytes()), parse(bf), "{bf}");
+        for level in OptimizationLevel::ALL {
+            assert_eq!(optimize_bytes(bf.bytes(), level), optimize(bf, level));
+        }
+    }
+
+    // Straight from a reader, bytes that aren't UTF-8 are comments too
+    let source: &[u8] = b"+\xff\xfe[->+<]>.";
+    let reader = std::io::Cursor::new(source);
+    let ir = parse_bytes(std::io::Read::bytes(reader).map(Result::unwrap)).unwrap();
+    assert_eq!(ir, parse("+[->+<]>.").unwrap());
+
+    // Positions count bytes, where the str functions count characters
+    assert_eq!(
+        parse_bytes("ü]".bytes()),
+        Err(OptimizerError::UnbalancedBrackets {
+            position: 2,
+            kind: BracketKind::UnexpectedClose
+        })
+    );
+    assert_eq!(
+        parse("ü]"),
+        Err(OptimizerError::UnbalancedBrackets {
+            position: 1,
+            kind: BracketKind::UnexpectedClose
+        })
+    );
+
+    // A long program can be generated without ever holding its source
+    let commands = "+>".bytes().cycle().take(2_000_000);
+    let ir = optimize_bytes(commands, OptimizationLevel::O1).unwrap();
+    assert_eq!(ir.len(), 2_000_000);
+}
+
+#[test]
+fn mul_onto_source() {
+    // A Mul with x == 0 adds the source to itself y times, m[p] *= 1 + y. The source is read before the write, so
+    // it behaves like copying the cell to a scratch cell and adding that back 1 + y times.
+    for (start, y) in [(5, 2), (3, -1), (100, 2), (7, 0)] {
+        let ir = vec![
+            IR::Add {
+                x: start,
+                offset: 1,
+            },
+            IR::Mul { x: 0, y, offset: 1 },
+            IR::Print {
+                times: 1,
+                offset: 1,
+            },
+        ];
+        let bf = format!(
+            ">{}[->>+<<]>>[-<<{}>>]<<.",
+            "+".repeat(start as usize),
+            "+".repeat((1 + y) as usize)
+        );
+
+        let mut mul: Interpreter = Interpreter::from(ir, 1000);
+        let mut looped: Interpreter = Interpreter::from(optimize_o0(&bf).unwrap(), 100000);
+        let expected = looped.run(&[]);
+        assert_eq!(expected.error, None, "{bf}");
+        assert_eq!(mul.run(&[]).output, expected.output, "{start} {y}");
+        // The scratch cell ends up cleared
+        assert_eq!(
+            mul.return_shrinked_memory(),
+            looped.return_shrinked_memory()
+        );
+    }
+
+    // The optimizer never produces one, a counter isn't a destination
+    for bf in ["[-]", "+++[-++]", "+++[--->+<]", "++[+>+<]"] {
+        let ir = optimize_o3(bf).unwrap();
+        assert_eq!(validate_ir(&ir, OptimizationLevel::O3), Ok(()), "{bf}");
+    }
+    let err = validate_ir(
+        &[IR::Mul {
+            x: 0,
+            y: 2,
+            offset: 0,
+        }],
+        OptimizationLevel::O3,
+    )
+    .unwrap_err();
+    assert_eq!(err.reason, "a Mul never adds to its source");
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn serde_round_trip() {
+    let bf = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.,[.,]";
+    let ir = crate::optimize(bf, OptimizationLevel::O3).unwrap();
+
+    let json = serde_json::to_string(&ir).unwrap();
+    let decoded: Vec<IR> = serde_json::from_str(&json).unwrap();
+    assert_eq!(ir, decoded);
+
+    let input = bytes(b"!");
+    let mut original: Interpreter = Interpreter::from(ir, 100000);
+    let mut reloaded: Interpreter = Interpreter::from(decoded, 100000);
+    original.set_eof(EofBehavior::Zero);
+    reloaded.set_eof(EofBehavior::Zero);
+    let expected = original.run(&input);
+    assert_eq!(parts(expected.clone()), (None, bytes(b"Hello World!!")));
+    assert_eq!(reloaded.run(&input), expected);
+
+    let error = optimize_o0("[[]").unwrap_err();
+    let json = serde_json::to_string(&error).unwrap();
+    assert_eq!(
+        serde_json::from_str::<OptimizerError>(&json).unwrap(),
+        error
+    );
+}
+
+#[cfg(feature = "wasm")]
+#[test]
+fn wasm_api() {
+    use crate::wasm;
+
+    let bf = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.,.";
+    let levels = OptimizationLevel::ALL;
+    for (number, level) in (0..).zip(levels) {
+        assert_eq!(
+            wasm::run_bytes(bf, b"!", number, 100000),
+            Ok(b"Hello World!!".to_vec())
+        );
+
+        let json = wasm::optimize_json(bf, number).unwrap();
+        let ir: Vec<IR> = serde_json::from_str(&json).unwrap();
+        assert_eq!(ir, optimize(bf, level).unwrap());
+    }
+
+    // Every error is its message
+    assert_eq!(
+        wasm::optimize_json("[[]", 3),
+        Err(optimize_o0("[[]").unwrap_err().to_string())
+    );
+    assert_eq!(
+        wasm::run_bytes("+[]", &[], 3, 1000),
+        Err(RunTimeError::MaxIterationsExceeded.to_string())
+    );
+    assert_eq!(
+        wasm::run_bytes("+", &[], 5, 1000),
+        Err("unknown optimization level 5, expected 0 to 4".to_string())
+    );
 }
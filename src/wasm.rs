@@ -0,0 +1,39 @@
+// Plain typed entry points for running in the browser, where binding generators like wasm-bindgen can't handle
+// Wrapping, Either or std::io in a signature. Levels are given as 0 to 4 and every error becomes its message.
+
+use crate::{optimize, Config, OptimizationLevel};
+
+fn level(level: u8) -> Result<OptimizationLevel, String> {
+    match level {
+        0 => Ok(OptimizationLevel::O0),
+        1 => Ok(OptimizationLevel::O1),
+        2 => Ok(OptimizationLevel::O2),
+        3 => Ok(OptimizationLevel::O3),
+        4 => Ok(OptimizationLevel::O4),
+        _ => Err(format!(
+            "unknown optimization level {level}, expected 0 to 4"
+        )),
+    }
+}
+
+// Optimizes the program and serializes its IR as JSON.
+pub fn optimize_json(bf: &str, level: u8) -> Result<String, String> {
+    let ir = optimize(bf, self::level(level)?).map_err(|err| err.to_string())?;
+    serde_json::to_string(&ir).map_err(|err| err.to_string())
+}
+
+// Same as the crate's `run_bytes` with an otherwise default config.
+pub fn run_bytes(
+    bf: &str,
+    input: &[u8],
+    level: u8,
+    max_iterations: usize,
+) -> Result<Vec<u8>, String> {
+    let config = Config {
+        optimization_level: self::level(level)?,
+        max_iterations,
+        ..Config::default()
+    };
+    crate::run_bytes(bf, input, config)
+        .map_err(|err| err.either(|e| e.to_string(), |e| e.to_string()))
+}